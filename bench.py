@@ -6,11 +6,14 @@ import os
 import subprocess
 import sys
 
-from build import print_header, print_error, STEP_IMPLEMENTATIONS
+from build import print_header, print_error, STEP_IMPLEMENTATIONS, RUST_ONLY_STEP_IMPLEMENTATIONS
 
 INPUT_SIZES = [100, 160, 250, 400, 630, 1000, 1600, 2500, 4000, 6300]
 STEP_ITERATIONS = [2, 3, 5, 10, 15, 20, 20, 20]
 assert len(STEP_ITERATIONS) == len(STEP_IMPLEMENTATIONS)
+# As many as for v5_more_register_reuse
+RUST_ONLY_STEP_ITERATIONS = [20]
+assert len(RUST_ONLY_STEP_ITERATIONS) == len(RUST_ONLY_STEP_IMPLEMENTATIONS)
 
 # Prefix the benchmark command with taskset, binding the process to 4 physical cores
 CPU_BIND_CMD = "taskset --cpu-list 0-3"
@@ -98,15 +101,18 @@ def do_benchmark(build_dir, iterations_per_step_impl, benchmark_langs, threads,
         print_header("Benchmarking", end="\n\n")
     else:
         print_header("Benchmarking with perf-stat", end="\n\n")
-    for step_impl in STEP_IMPLEMENTATIONS:
+    for step_impl in STEP_IMPLEMENTATIONS + RUST_ONLY_STEP_IMPLEMENTATIONS:
         if impl_filter and not any(step_impl.startswith(prefix) for prefix in impl_filter):
             continue
         iterations = iterations_per_step_impl[step_impl]
         for lang in benchmark_langs:
+            if lang == "cpp" and step_impl in RUST_ONLY_STEP_IMPLEMENTATIONS:
+                continue
             print_header(lang + ' ' + step_impl)
             report_path = None
             if report_dir:
-                report_name = step_impl[:2] + '.' + reporter_out
+                # Rust-only implementations share the short name of their C++ counterpart, so they keep the full name
+                report_name = (step_impl if step_impl in RUST_ONLY_STEP_IMPLEMENTATIONS else step_impl[:2]) + '.' + reporter_out
                 report_path = os.path.join(report_dir, lang, report_name)
             reporter = Reporter(reporter_out, report_path, use_perf=not no_perf)
             reporter.print_header(clear_file=True)
@@ -207,9 +213,9 @@ if __name__ == "__main__":
             sys.exit(1)
 
     if args.iterations:
-        iterations = {k: args.iterations for k in STEP_IMPLEMENTATIONS}
+        iterations = {k: args.iterations for k in STEP_IMPLEMENTATIONS + RUST_ONLY_STEP_IMPLEMENTATIONS}
     else:
-        iterations = {k: iterations for k, iterations in zip(STEP_IMPLEMENTATIONS, STEP_ITERATIONS)}
+        iterations = {k: iterations for k, iterations in zip(STEP_IMPLEMENTATIONS + RUST_ONLY_STEP_IMPLEMENTATIONS, STEP_ITERATIONS + RUST_ONLY_STEP_ITERATIONS)}
     try:
         do_benchmark(build_dir, iterations, benchmark_langs, args.threads, args.report_dir, args.reporter_out, args.no_perf)
     except PerfToolException:
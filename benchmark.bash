@@ -4,6 +4,7 @@ set -e
 BUILD_DIR=./build
 REPORT_DIR=./reports
 STEP_IMPLEMENTATIONS_LIST=./src/step_implementations.txt
+RUST_ONLY_STEP_IMPLEMENTATIONS_LIST=./src/rust_only_step_implementations.txt
 THREADS=4
 TEST_ITERATIONS=1
 
@@ -60,6 +61,10 @@ step_implementations=()
 while read -r step; do
     step_implementations+=("$step")
 done < $STEP_IMPLEMENTATIONS_LIST
+rust_only_step_implementations=()
+while read -r step; do
+    rust_only_step_implementations+=("$step")
+done < $RUST_ONLY_STEP_IMPLEMENTATIONS_LIST
 
 mkdir --verbose --parents ${REPORT_DIR}/{single-thread,multi-thread}/{gcc,clang,rustc}
 
@@ -84,6 +89,13 @@ for threads in 1 $THREADS; do
     fi
     echo "testing"
     ./test.py --iterations $TEST_ITERATIONS --build_dir $BUILD_DIR --threads $threads
+    if [ $threads -ne 1 ]; then
+        clean
+        echo "building Rust with the std-threads backend in debug mode"
+        ./build.py --debug --rust-parallel-backend std-threads --build_dir $BUILD_DIR
+        echo "testing"
+        ./test.py --no-cpp --iterations $TEST_ITERATIONS --build_dir $BUILD_DIR --threads $threads
+    fi
     clean
     echo "building"
     if [ $threads -eq 1 ]; then
@@ -98,6 +110,10 @@ for threads in 1 $THREADS; do
         echo "$step rustc"
         perf stat --field-separator=, taskset --cpu-list $cpu_list $BUILD_DIR/bin/${step}_rust benchmark $BENCHMARK_SIZE $MAX_ITERATIONS $MAX_SECONDS &> ${REPORT_DIR}/$mode/rustc/${step}.txt
     done
+    for step in ${rust_only_step_implementations[*]}; do
+        echo "$step rustc"
+        perf stat --field-separator=, taskset --cpu-list $cpu_list $BUILD_DIR/bin/${step}_rust benchmark $BENCHMARK_SIZE $MAX_ITERATIONS $MAX_SECONDS &> ${REPORT_DIR}/$mode/rustc/${step}.txt
+    done
     echo_header "$mode, clang"
     clean
     echo "building in debug mode"
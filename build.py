@@ -10,6 +10,10 @@ import sys
 with open(os.path.join("src", "step_implementations.txt")) as f:
     STEP_IMPLEMENTATIONS = f.read().splitlines()
 
+# Rust implementations without a C++ counterpart, built with a nightly compiler
+with open(os.path.join("src", "rust_only_step_implementations.txt")) as f:
+    RUST_ONLY_STEP_IMPLEMENTATIONS = f.read().splitlines()
+
 COMMANDS = {
     "cmake-generate": {
         "cmd": ["cmake", "-D", "SC_NO_MULTI_THREAD=0", "G", "Unix Makefiles"],
@@ -31,6 +35,10 @@ def append_rust_flags(string):
     cargo_env = COMMANDS["cargo-build"]["env"]
     cargo_env["RUSTFLAGS"] = cargo_env["RUSTFLAGS"] + ' ' + string
 
+def select_rust_parallel_backend(feature):
+    # Without default features, c-abi must be enabled again so that the step functions are exported
+    COMMANDS["cargo-build"]["cmd"] += ["--no-default-features", "--features", feature + ",c-abi"]
+
 def run(cmd, cwd=None, verbose=False):
     if verbose:
         print("Command: {}".format(cmd["cmd"]))
@@ -76,6 +84,10 @@ if __name__ == "__main__":
     parser.add_argument("--no-multi-thread",
             action='store_true',
             help="Explicitly exclude multithreading support from all builds, i.e. OpenMP and Rayon syntax.")
+    parser.add_argument("--rust-parallel-backend",
+            choices=("rayon", "std-threads"),
+            default="rayon",
+            help="Cargo feature selecting how the Rust implementations run in parallel, ignored if --no-multi-thread is given.")
     parser.add_argument("--makefiles-only",
             action='store_true',
             help="Generate makefiles and exit")
@@ -105,7 +117,9 @@ if __name__ == "__main__":
 
     if args.no_multi_thread:
         enable_cmake_var(2)
-        append_rust_flags("--cfg feature=\"no-multi-thread\"")
+        select_rust_parallel_backend("no-multi-thread")
+    else:
+        select_rust_parallel_backend(args.rust_parallel_backend)
     if args.emit_asm:
         append_rust_flags("--emit asm")
         append_rust_flags("-Z asm_comments")
@@ -134,6 +148,13 @@ if __name__ == "__main__":
         returncode = run(cargo_build, crate_dir, args.verbose)
         if returncode > 0:
             sys.exit(returncode)
+    # core::simd is only available on nightly
+    cargo_build_nightly = dict(cargo_build, cmd=cargo_build["cmd"][:1] + ["+nightly"] + cargo_build["cmd"][1:])
+    for step_impl in RUST_ONLY_STEP_IMPLEMENTATIONS:
+        crate_dir = os.path.join(root_dir, "rust", step_impl)
+        returncode = run(cargo_build_nightly, crate_dir, args.verbose)
+        if returncode > 0:
+            sys.exit(returncode)
 
     print_header("Building C++ libraries and benchmarks")
     returncode = run(COMMANDS["make"], build_dir, args.verbose)
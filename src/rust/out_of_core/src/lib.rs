@@ -0,0 +1,192 @@
+//! Compute r for inputs whose partial results do not fit into memory.
+//!
+//! v7_cache_reuse reads d one vertical stripe at a time, but keeps partial results for all n² elements of r.
+//! Here, d and r are memory-mapped from files and r is computed one horizontal panel of rows at a time,
+//! such that the partial results of one panel fit into a given amount of memory.
+//! Each panel reads all of d once, which the operating system pages in from the file as needed,
+//! and the finished rows of r are written back to their file and unmapped before the next panel starts.
+//!
+//! The files contain the n×n elements in row-major order as f32 values in native byte order, without any header.
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::mem;
+use std::path::Path;
+use std::time::Instant;
+
+use memmap2::{Mmap, MmapOptions};
+use tools::params::Params;
+use tools::simd::{self, f32x8};
+
+/// Rows of r computed at a time, such that the memory used by v7_cache_reuse for one panel,
+/// i.e. the partial results, packed stripes and dirty pages of r, stays below `memory` bytes.
+/// The result is a multiple of 8, but at least 8 rows even if they do not fit.
+pub fn panel_rows(n: usize, memory: usize, params: &Params) -> usize {
+    let vecs_per_col = n.div_ceil(simd::f32x8_LENGTH);
+    // For each row: partial results of one vector for each 8x8 block, the row of r until it is written back,
+    // and 1/8 of the Z-order curve index, which has an entry for each 8x8 block
+    let bytes_per_row = vecs_per_col * mem::size_of::<f32x8>()
+        + n * mem::size_of::<f32>()
+        + vecs_per_col * mem::size_of::<(usize, usize, usize)>() / simd::f32x8_LENGTH;
+    // vd and vt of one stripe, with at most all row blocks
+    let stripe_bytes = 2 * params.cols_per_stripe * vecs_per_col * mem::size_of::<f32x8>();
+    let rows = memory.saturating_sub(stripe_bytes) / bytes_per_row.max(1);
+    (rows - rows % simd::f32x8_LENGTH).clamp(simd::f32x8_LENGTH, n.next_multiple_of(simd::f32x8_LENGTH))
+}
+
+/// Reinterpret the bytes of a mapped file as f32 values
+fn as_f32s(bytes: &[u8]) -> &[f32] {
+    // Mappings start at page boundaries, and the lengths are checked when opening the files
+    assert!(bytes.as_ptr().align_offset(mem::align_of::<f32>()) == 0 && bytes.len().is_multiple_of(mem::size_of::<f32>()));
+    // Safety: the pointer is aligned and valid for all bytes, and every bit pattern is a valid f32
+    unsafe { std::slice::from_raw_parts(bytes.as_ptr() as *const f32, bytes.len() / mem::size_of::<f32>()) }
+}
+
+fn as_f32s_mut(bytes: &mut [u8]) -> &mut [f32] {
+    assert!(bytes.as_ptr().align_offset(mem::align_of::<f32>()) == 0 && bytes.len().is_multiple_of(mem::size_of::<f32>()));
+    // Safety: as above, and the bytes are borrowed mutably for the lifetime of the result
+    unsafe { std::slice::from_raw_parts_mut(bytes.as_mut_ptr() as *mut f32, bytes.len() / mem::size_of::<f32>()) }
+}
+
+/// n of an n×n matrix of f32 stored in a file of this size
+fn matrix_size(bytes: u64) -> io::Result<usize> {
+    let elements = bytes / mem::size_of::<f32>() as u64;
+    let n = (elements as f64).sqrt().round() as u64;
+    if n * n * mem::size_of::<f32>() as u64 != bytes {
+        return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                  format!("file size {} is not the size of a square matrix of f32", bytes)));
+    }
+    Ok(n as usize)
+}
+
+/// Compute r of the matrix d in the file at d_path into a new file at r_path, replacing it if it exists,
+/// using at most about `memory` bytes besides the pages of the mapped files, which the operating system
+/// can evict at any time. Progress is printed to stderr if verbose is true. Returns n.
+pub fn step_files<P: AsRef<Path>, Q: AsRef<Path>>(d_path: P, r_path: Q, memory: usize, params: &Params, verbose: bool) -> io::Result<usize> {
+    let d_file = File::open(d_path)?;
+    let n = matrix_size(d_file.metadata()?.len())?;
+    let r_file = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(r_path)?;
+    r_file.set_len((n * n * mem::size_of::<f32>()) as u64)?;
+    if n == 0 {
+        return Ok(0);
+    }
+    // Safety: the files must not be modified by other processes while they are mapped
+    let d_map = unsafe { Mmap::map(&d_file)? };
+    let d = as_f32s(&d_map);
+
+    let panel_rows = panel_rows(n, memory, params);
+    if verbose {
+        eprintln!("n = {}, {} panels of {} rows", n, n.div_ceil(panel_rows), panel_rows);
+    }
+    let row_bytes = n * mem::size_of::<f32>();
+    let start = Instant::now();
+    for first_row in (0..n).step_by(panel_rows) {
+        let rows = first_row..n.min(first_row + panel_rows);
+        // Map only the rows of this panel, such that they can be written back and unmapped when finished
+        let mut r_map = unsafe {
+            MmapOptions::new()
+                .offset((first_row * row_bytes) as u64)
+                .len(rows.len() * row_bytes)
+                .map_mut(&r_file)?
+        };
+        v7_cache_reuse::step_rows_with_params(as_f32s_mut(&mut r_map), d, n, first_row, params);
+        r_map.flush()?;
+        if verbose {
+            eprintln!("rows {}..{} done after {:.1} s", rows.start, rows.end, start.elapsed().as_secs_f64());
+        }
+    }
+    Ok(n)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::path::PathBuf;
+
+    /// Small stripes and blocks, such that all code paths of v7 are taken even for small inputs
+    fn small_params() -> Params {
+        Params {
+            cols_per_stripe: 3,
+            z_block_size: 4,
+            ..Params::default()
+        }
+    }
+
+    /// A path in the temporary directory, unique for this process and name
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("out-of-core-{}-{}", std::process::id(), name))
+    }
+
+    /// n×n matrix with distinct distances and some missing edges
+    fn matrix(n: usize) -> Vec<f32> {
+        (0..n * n).map(|ij| if ij % 7 == 3 { f32::INFINITY } else { ((ij * 37) % 101) as f32 * 0.5 }).collect()
+    }
+
+    fn write_f32s(path: &Path, values: &[f32]) {
+        fs::write(path, values.iter().flat_map(|x| x.to_ne_bytes()).collect::<Vec<u8>>()).unwrap();
+    }
+
+    fn read_f32s(path: &Path) -> Vec<f32> {
+        fs::read(path).unwrap().chunks_exact(4).map(|b| f32::from_ne_bytes([b[0], b[1], b[2], b[3]])).collect()
+    }
+
+    /// Compute r of d with step_files using at most `memory` bytes, and return it with the removed files
+    fn step_via_files(name: &str, d: &[f32], memory: usize) -> io::Result<Vec<f32>> {
+        let (d_path, r_path) = (temp_path(&format!("{}-d", name)), temp_path(&format!("{}-r", name)));
+        write_f32s(&d_path, d);
+        let result = step_files(&d_path, &r_path, memory, &small_params(), false).map(|_| read_f32s(&r_path));
+        let _ = fs::remove_file(&d_path);
+        let _ = fs::remove_file(&r_path);
+        result
+    }
+
+    #[test]
+    fn panel_rows_are_multiples_of_8() {
+        for n in [1, 7, 8, 9, 100, 1003] {
+            let mut previous = 0;
+            for memory in [0, 1 << 10, 1 << 16, 1 << 20, 1 << 30] {
+                let rows = panel_rows(n, memory, &small_params());
+                assert_eq!(rows % simd::f32x8_LENGTH, 0, "n = {}, memory = {}", n, memory);
+                assert!((simd::f32x8_LENGTH..=n.next_multiple_of(simd::f32x8_LENGTH)).contains(&rows));
+                assert!(rows >= previous, "fewer rows with more memory");
+                previous = rows;
+            }
+            // All rows fit into a lot of memory
+            assert_eq!(previous, n.next_multiple_of(simd::f32x8_LENGTH));
+        }
+    }
+
+    #[test]
+    fn step_files_equals_v0() {
+        // Panels of 8 rows with no memory, which do not divide n = 20 and 37, and all rows in one panel
+        for n in [1, 7, 8, 16, 20, 37] {
+            let d = matrix(n);
+            let mut expected = vec![0.0; n * n];
+            v0_baseline::step(&mut expected, &d, n);
+            for memory in [0, 1 << 30] {
+                let r = step_via_files(&format!("equals-v0-{}-{}", n, memory), &d, memory).unwrap();
+                let bits = |v: &[f32]| v.iter().map(|x| x.to_bits()).collect::<Vec<u32>>();
+                assert_eq!(bits(&r), bits(&expected), "n = {}, memory = {}", n, memory);
+            }
+        }
+    }
+
+    #[test]
+    fn empty_file() {
+        assert_eq!(step_via_files("empty", &[], 0).unwrap(), Vec::<f32>::new());
+    }
+
+    #[test]
+    fn file_too_short() {
+        // One element missing from a 5×5 matrix, and a partial f32
+        let d = matrix(5);
+        let e = step_via_files("too-short", &d[..24], 0).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        let path = temp_path("partial-f32");
+        fs::write(&path, [0u8; 6]).unwrap();
+        let e = step_files(&path, temp_path("partial-f32-r"), 0, &small_params(), false).unwrap_err();
+        let _ = fs::remove_file(&path);
+        let _ = fs::remove_file(temp_path("partial-f32-r"));
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,41 @@
+//! Compute r of a matrix d stored in a file into another file, one panel of rows at a time,
+//! see the library of this crate for the file format.
+use std::env;
+use std::process;
+
+use out_of_core::step_files;
+use tools::cpu;
+
+fn usage(program: &str) -> ! {
+    eprintln!("usage: {} D_FILE R_FILE [--memory SIZE] [--quiet]", program);
+    eprintln!("  --memory  memory for the partial results of one panel, e.g. 512M or 8G, default 1G");
+    eprintln!("  --quiet   do not print progress");
+    process::exit(1);
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    let mut paths = Vec::new();
+    let mut memory = 1 << 30;
+    let mut verbose = true;
+    let mut i = 1;
+    while i < args.len() {
+        match (args[i].as_str(), args.get(i + 1)) {
+            ("--memory", Some(v)) => {
+                memory = cpu::parse_size(v).unwrap_or_else(|| usage(&args[0]));
+                i += 1;
+            }
+            ("--quiet", _) => verbose = false,
+            (arg, _) if !arg.starts_with("--") => paths.push(arg.to_string()),
+            _ => usage(&args[0]),
+        }
+        i += 1;
+    }
+    if paths.len() != 2 {
+        usage(&args[0]);
+    }
+    if let Err(e) = step_files(&paths[0], &paths[1], memory, tools::params::get(), verbose) {
+        eprintln!("error: {}", e);
+        process::exit(1);
+    }
+}
@@ -0,0 +1,73 @@
+//! Find the fastest parameters of the step implementations on this machine
+//! and save them into the cache file read by tools::params.
+use std::env;
+use std::path::PathBuf;
+use std::process;
+
+use planner::tune;
+use tools::cpu;
+use tools::params::{self, Params};
+
+fn usage(program: &str) -> ! {
+    eprintln!("usage: {} [--sizes N[,N...]] [--repetitions R] [--output PATH] [--dry-run]", program);
+    eprintln!("  --sizes        input sizes to measure, default 400,1600");
+    eprintln!("  --repetitions  runs per input size, the fastest one is used, default 3");
+    eprintln!("  --output       where to save the parameters, default {}",
+              params::cache_path().map_or("SHORTCUT_PARAMS".to_string(), |p| p.display().to_string()));
+    eprintln!("  --dry-run      only print the parameters");
+    process::exit(1);
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    let mut sizes = vec![400, 1600];
+    let mut repetitions = 3;
+    let mut output = params::cache_path();
+    let mut dry_run = false;
+    let mut i = 1;
+    while i < args.len() {
+        let value = args.get(i + 1);
+        match (args[i].as_str(), value) {
+            ("--sizes", Some(v)) => {
+                sizes = v.split(',').map(|s| s.trim().parse().unwrap_or_else(|_| usage(&args[0]))).collect();
+                i += 1;
+            }
+            ("--repetitions", Some(v)) => {
+                repetitions = v.parse().unwrap_or_else(|_| usage(&args[0]));
+                i += 1;
+            }
+            ("--output", Some(v)) => {
+                output = Some(PathBuf::from(v));
+                i += 1;
+            }
+            ("--dry-run", _) => dry_run = true,
+            _ => usage(&args[0]),
+        }
+        i += 1;
+    }
+
+    println!("tuning with input sizes {:?}, fastest of {} runs", sizes, repetitions);
+    println!("caches: {}", cpu::caches());
+    let best = tune::autotune(Params::for_caches(cpu::caches()), &sizes, repetitions, |dim, value, seconds| {
+        println!("{:>16} = {:<5} {} {:10.6} s", dim.name, dim.show(value), dim.version.name(), seconds);
+    });
+    println!();
+    print!("{}", best);
+
+    if dry_run {
+        return;
+    }
+    match output {
+        Some(path) => {
+            if let Err(e) = best.save(&path) {
+                eprintln!("error: could not write {}: {}", path.display(), e);
+                process::exit(1);
+            }
+            println!("wrote {}", path.display());
+        }
+        None => {
+            eprintln!("error: no cache directory, use --output or set SHORTCUT_PARAMS");
+            process::exit(1);
+        }
+    }
+}
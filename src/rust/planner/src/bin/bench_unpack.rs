@@ -0,0 +1,117 @@
+//! Time writing the 8×8 result blocks of v5-v7 into r, with the scalar simd::extract scatter
+//! these versions used before and with simd::unpack_results and store_padded, which they use now.
+//! Only the write-back is measured, for all blocks of an n×n matrix r, from random result vectors.
+use std::env;
+use std::hint::black_box;
+use std::process;
+use std::time::Instant;
+
+use planner::tune;
+use tools::simd::{self, f32x8};
+
+/// Different result blocks written in turn, so that the results are not known in advance
+const POOL_SIZE: usize = 64;
+
+fn usage(program: &str) -> ! {
+    eprintln!("usage: {} [--sizes N[,N...]] [--repetitions R]", program);
+    eprintln!("  --sizes        sizes n of the matrix r, default 200,1000,1003");
+    eprintln!("  --repetitions  runs per size, the fastest one is used, default 20");
+    process::exit(1);
+}
+
+/// Write the results of one 8×8 block one element at a time, as v5-v7 did before simd::unpack_results
+fn scatter_extract(r_row_block: &mut [f32], n: usize, j: usize, mut tmp: [f32x8; 8]) {
+    // Swap elements of f32x8s at odd indexes to enable a linear iteration
+    // pattern for index tmp_j when extracting elements
+    for i in (1..simd::f32x8_LENGTH).step_by(2) {
+        tmp[i] = simd::swap::<1>(tmp[i]);
+    }
+    for (tmp_i, r_row) in r_row_block.chunks_exact_mut(n).enumerate() {
+        let mut set = |tmp_j: usize, x: f32| {
+            let res_j = j * simd::f32x8_LENGTH + tmp_j;
+            if res_j < n {
+                r_row[res_j] = x;
+            }
+        };
+        set(0, simd::extract::<0>(tmp[tmp_i]));
+        set(1, simd::extract::<1>(tmp[tmp_i ^ 1]));
+        set(2, simd::extract::<2>(tmp[tmp_i ^ 2]));
+        set(3, simd::extract::<3>(tmp[tmp_i ^ 3]));
+        set(4, simd::extract::<4>(tmp[tmp_i ^ 4]));
+        set(5, simd::extract::<5>(tmp[tmp_i ^ 5]));
+        set(6, simd::extract::<6>(tmp[tmp_i ^ 6]));
+        set(7, simd::extract::<7>(tmp[tmp_i ^ 7]));
+    }
+}
+
+/// Write the results of one 8×8 block one row at a time, as v5-v7 do
+fn store_unpacked(r_row_block: &mut [f32], n: usize, j: usize, tmp: [f32x8; 8]) {
+    let rows = simd::unpack_results(tmp);
+    let res_j = j * simd::f32x8_LENGTH;
+    for (r_row, &row) in r_row_block.chunks_exact_mut(n).zip(rows.iter()) {
+        simd::store_padded(&mut r_row[res_j..n.min(res_j + simd::f32x8_LENGTH)], row);
+    }
+}
+
+/// Write all result blocks of r, taking the results from pool, and return the elapsed seconds
+fn write_blocks(write: fn(&mut [f32], usize, usize, [f32x8; 8]), r: &mut [f32], n: usize, pool: &[[f32x8; 8]]) -> f64 {
+    let blocks_per_row = n.div_ceil(simd::f32x8_LENGTH);
+    let begin = Instant::now();
+    for (i, r_row_block) in r.chunks_mut(simd::f32x8_LENGTH * n).enumerate() {
+        for j in 0..blocks_per_row {
+            let tmp = black_box(pool[(i * blocks_per_row + j) % pool.len()]);
+            write(r_row_block, n, j, tmp);
+        }
+    }
+    black_box(&mut *r);
+    begin.elapsed().as_secs_f64()
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    let mut sizes = vec![200, 1000, 1003];
+    let mut repetitions = 20;
+    let mut i = 1;
+    while i < args.len() {
+        let value = args.get(i + 1);
+        match (args[i].as_str(), value) {
+            ("--sizes", Some(v)) => {
+                sizes = v.split(',').map(|s| s.trim().parse().unwrap_or_else(|_| usage(&args[0]))).collect();
+                i += 1;
+            }
+            ("--repetitions", Some(v)) => {
+                repetitions = v.parse().unwrap_or_else(|_| usage(&args[0]));
+                i += 1;
+            }
+            _ => usage(&args[0]),
+        }
+        i += 1;
+    }
+
+    let values = tune::random_input(POOL_SIZE, 1);
+    let pool: Vec<[f32x8; 8]> = values
+        .chunks_exact(simd::f32x8_LENGTH * simd::f32x8_LENGTH)
+        .map(|block| std::array::from_fn(|k| simd::from_slice(&block[k * simd::f32x8_LENGTH..(k + 1) * simd::f32x8_LENGTH])))
+        .collect();
+
+    println!("fastest of {} runs, in ns per 8×8 block", repetitions);
+    println!("{:>6} {:>10} {:>16}", "n", "extract", "unpack_results");
+    for &n in &sizes {
+        // Rows of r that are not overwritten would keep the NaN
+        let mut expected = vec![f32::NAN; n * n];
+        let mut r = vec![f32::NAN; n * n];
+        write_blocks(scatter_extract, &mut expected, n, &pool);
+        write_blocks(store_unpacked, &mut r, n, &pool);
+        if r.iter().map(|x| x.to_bits()).ne(expected.iter().map(|x| x.to_bits())) {
+            eprintln!("error: different results with unpack_results and extract for n = {}", n);
+            process::exit(1);
+        }
+        let blocks = (n.div_ceil(simd::f32x8_LENGTH) * n.div_ceil(simd::f32x8_LENGTH)) as f64;
+        let mut fastest = |write: fn(&mut [f32], usize, usize, [f32x8; 8])| {
+            (0..repetitions.max(1)).map(|_| write_blocks(write, &mut r, n, &pool)).fold(f64::INFINITY, f64::min) * 1e9 / blocks
+        };
+        let extract = fastest(scatter_extract);
+        let unpacked = fastest(store_unpacked);
+        println!("{:>6} {:>10.2} {:>16.2}", n, extract, unpacked);
+    }
+}
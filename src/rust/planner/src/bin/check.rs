@@ -0,0 +1,382 @@
+//! Compare the results of all versions with v0_baseline on small random inputs.
+//! With the c-abi feature, the planned step function is also called through its C ABI wrapper.
+//!
+//! The inputs are small enough for running under Miri, with emulated vector operations.
+//! Miri reports undefined behaviour in crossbeam, used by rayon, so the std-threads backend is needed:
+//! `cargo miri run --bin check --no-default-features --features std-threads,emulated-simd,c-abi`
+//! With the portable-simd feature, which needs a nightly compiler, v5_portable_simd is compared as well:
+//! `cargo +nightly run --release --bin check --features portable-simd,c-abi`
+use std::env;
+use std::fs;
+use std::process;
+
+use planner::incremental::{self, EdgeChange};
+use planner::{tune, Planner, Version};
+use tools::io::{self, Format, Precision};
+use tools::kfilter::KFilter;
+use tools::layout::{self, Layout};
+use tools::params::{self, Params};
+
+#[cfg(feature = "c-abi")]
+extern "C" {
+    fn step(r: *mut f32, d: *const f32, n: i32);
+    fn step_accumulate(r: *mut f32, d: *const f32, n: i32);
+    fn step_in_place(rd: *mut f32, n: i32);
+    fn step_weighted(r: *mut f32, d: *const f32, w: *const f32, n: i32);
+    fn step_strided(r: *mut f32, ldr: i32, r_col_major: i32, d: *const f32, lda: i32, d_col_major: i32, n: i32);
+}
+
+/// Functions computing the rows of r at the given indexes, and the results of (i, j) pairs
+type StepRowSubset = fn(&mut [f32], &[f32], usize, &[usize]);
+type StepPairs = fn(&mut [f32], &[f32], usize, &[(usize, usize)]);
+
+/// Value of the elements of strided buffers outside of the matrix, which must not be read or written
+const PADDING: f32 = -1.0;
+
+/// Compare r with the expected results, printing the first difference
+fn compare(name: &str, n: usize, r: &[f32], expected: &[f32]) -> bool {
+    match r.iter().zip(expected).position(|(x, y)| x.to_bits() != y.to_bits()) {
+        Some(i) => {
+            eprintln!("{} n = {}: r[{}][{}] is {}, expected {}", name, n, i / n, i % n, r[i], expected[i]);
+            false
+        }
+        None => true,
+    }
+}
+
+/// Results of v0_baseline for d
+fn baseline(d: &[f32], n: usize) -> Vec<f32> {
+    let mut r = vec![0.0; n * n];
+    Version::V0Baseline.step(&mut r, d, n);
+    r
+}
+
+/// Results for d by the definition of the filter, looping over its hubs
+fn filtered_reference(d: &[f32], n: usize, filter: &KFilter) -> Vec<f32> {
+    let hubs: Vec<usize> = filter.hubs.clone().unwrap_or_else(|| (0..n).collect());
+    (0..n * n).map(|ij| {
+        let (i, j) = (ij / n, ij % n);
+        hubs.iter()
+            .filter(|&&k| !filter.exclude_endpoints || (k != i && k != j))
+            .fold(f32::INFINITY, |v, &k| v.min(d[n * i + k] + d[n * k + j]))
+    }).collect()
+}
+
+/// Results for d with the vertex weights w by their definition, adding the weight after d[i][k] as all versions do
+fn weighted_reference(d: &[f32], w: &[f32], n: usize) -> Vec<f32> {
+    (0..n * n).map(|ij| {
+        let (i, j) = (ij / n, ij % n);
+        (0..n).fold(f32::INFINITY, |v, k| v.min(d[n * i + k] + w[k] + d[n * k + j]))
+    }).collect()
+}
+
+/// Compare the n×n matrix stored in r with the given layout with the expected results,
+/// and check that the padding between its rows or columns is unchanged
+fn compare_strided(name: &str, n: usize, r: &[f32], r_layout: Layout, expected: &[f32]) -> bool {
+    let matrix_elements = (0..n * n).map(|ij| r_layout.index(ij / n, ij % n));
+    let padding = r.len() - matrix_elements.len();
+    if r.iter().filter(|x| x.to_bits() == PADDING.to_bits()).count() < padding {
+        eprintln!("{} n = {}: padding of r was overwritten", name, n);
+        return false;
+    }
+    compare(name, n, &layout::gather(r, r_layout, n), expected)
+}
+
+/// Seed a planner from a report directory as written by bench.py, in which each version has its own report,
+/// also the Rust-only versions sharing the number of another version
+fn check_report_dir() -> bool {
+    let dir = env::temp_dir().join(format!("shortcut-check-reports-{}", process::id()));
+    // v5_portable_simd with the portable-simd feature
+    let versions = [Version::V5MoreRegisterReuse, Version::ALL[Version::ALL.len() - 1]];
+    let gflops = |version: Version| match version {
+        Version::V5MoreRegisterReuse => 10.0,
+        _ => 20.0,
+    };
+    let seeded = fs::create_dir_all(&dir)
+        .and_then(|_| {
+            for version in versions {
+                let report = format!("N (rows),GFLOP/s\n100,{}\n", gflops(version));
+                fs::write(dir.join(format!("{}.csv", version.short_name())), report)?;
+            }
+            let mut planner = Planner::new();
+            planner.seed_from_report_dir(&dir).map(|seeded| (seeded, planner))
+        });
+    let _ = fs::remove_dir_all(&dir);
+    let (seeded, planner) = match seeded {
+        Ok(seeded) => seeded,
+        Err(e) => {
+            eprintln!("seed_from_report_dir: {}", e);
+            return false;
+        }
+    };
+    let mut ok = true;
+    for version in versions {
+        if Version::from_name(version.short_name()) != Some(version) {
+            eprintln!("from_name({:?}) is not {}", version.short_name(), version.name());
+            ok = false;
+        }
+        let estimate = planner.estimated_gflops(version, 100);
+        if estimate != Some(gflops(version)) {
+            eprintln!("seed_from_report_dir: {} estimated {:?} GFLOP/s, expected {}", version.name(), estimate, gflops(version));
+            ok = false;
+        }
+    }
+    if seeded != 2 {
+        eprintln!("seed_from_report_dir: seeded {} versions, expected 2", seeded);
+        ok = false;
+    }
+    ok
+}
+
+/// Write d in every file format and read it back
+fn check_io(d: &[f32], n: usize) -> bool {
+    let mut ok = true;
+    for format in [Format::Raw, Format::Npy, Format::Csv, Format::MatrixMarket] {
+        for precision in [Precision::F32, Precision::F64] {
+            let mut bytes = Vec::new();
+            let result = io::write(&mut bytes, d, n, format, precision)
+                .and_then(|_| io::read(&bytes[..], format));
+            let name = format!("io {:?} {:?}", format, precision);
+            match result {
+                Ok((d_read, n_read)) if n_read == n => ok &= compare(&name, n, &d_read, d),
+                Ok((_, n_read)) => {
+                    eprintln!("{} n = {}: read n = {}", name, n, n_read);
+                    ok = false;
+                }
+                Err(e) => {
+                    eprintln!("{} n = {}: {}", name, n, e);
+                    ok = false;
+                }
+            }
+        }
+    }
+    ok
+}
+
+fn main() {
+    let sizes: Vec<usize> = match env::args().nth(1) {
+        Some(arg) => arg.split(',').map(|s| s.trim().parse().expect("usage: check [N[,N...]]")).collect(),
+        None => vec![0, 1, 2, 7, 8, 9, 17],
+    };
+    // Small stripes and blocks, such that all code paths are taken even for small inputs
+    let small_params = Params {
+        cols_per_stripe: 3,
+        z_block_size: 4,
+        ..Params::default()
+    };
+    // Miri isolates the process from the file system
+    let mut ok = cfg!(miri) || check_report_dir();
+    for &n in &sizes {
+        let d = tune::random_input(n, n as u32);
+        let expected = baseline(&d, n);
+        for &version in Version::ALL.iter().skip(1) {
+            for params in [params::get(), &small_params] {
+                let mut r = vec![0.0; n * n];
+                version.step_with_params(&mut r, &d, n, params);
+                ok &= compare(version.name(), n, &r, &expected);
+            }
+        }
+        // The portable kernel with each amount of lanes, compared with v5 as well, which has the same results
+        #[cfg(feature = "portable-simd")]
+        for lanes in [4, 8, 16] {
+            let mut r = vec![0.0; n * n];
+            v5_portable_simd::step_with_lanes(&mut r, &d, n, lanes);
+            ok &= compare(&format!("v5_portable_simd with {} lanes", lanes), n, &r, &expected);
+            let mut r_v5 = vec![0.0; n * n];
+            v5_more_register_reuse::step(&mut r_v5, &d, n);
+            ok &= compare(&format!("v5_portable_simd with {} lanes vs v5_more_register_reuse", lanes), n, &r, &r_v5);
+        }
+        // Accumulating into existing results, about half of which are smaller than the new ones, and some infinite
+        let r_old: Vec<f32> = tune::random_input(n, n as u32 + 1).iter().enumerate()
+            .map(|(ij, &x)| if ij % 5 == 0 { f32::INFINITY } else { 2.0 * x })
+            .collect();
+        let expected_acc: Vec<f32> = r_old.iter().zip(&expected).map(|(&x, &y)| tools::min(x, y)).collect();
+        for &version in &Version::ALL {
+            for params in [params::get(), &small_params] {
+                let mut r = r_old.clone();
+                version.step_accumulate_with_params(&mut r, &d, n, params);
+                ok &= compare(&format!("{} accumulate", version.name()), n, &r, &expected_acc);
+            }
+        }
+        // Results overwriting the input
+        for &version in &Version::ALL {
+            for params in [params::get(), &small_params] {
+                let mut rd = d.clone();
+                version.step_in_place_with_params(&mut rd, n, params);
+                ok &= compare(&format!("{} in place", version.name()), n, &rd, &expected);
+            }
+        }
+        // Some hubs, including an unsorted and a duplicate one, no hubs, and excluding the endpoints with and without hubs
+        let hubs: Vec<usize> = (0..n).filter(|k| k % 3 != 1).rev().chain((n > 0).then_some(0)).collect();
+        let filters = [
+            KFilter::hubs(hubs.clone()),
+            KFilter::hubs(Vec::new()),
+            KFilter::exclude_endpoints(),
+            KFilter { hubs: Some(hubs), exclude_endpoints: true },
+        ];
+        for filter in &filters {
+            let expected_filtered = filtered_reference(&d, n, filter);
+            for &version in &Version::ALL {
+                for params in [params::get(), &small_params] {
+                    let mut r = vec![0.0; n * n];
+                    version.step_filtered_with_params(&mut r, &d, n, filter, params);
+                    ok &= compare(&format!("{} filtered {:?}", version.name(), filter), n, &r, &expected_filtered);
+                }
+            }
+        }
+        // Vertex weights, one of them infinite so that no path goes through that vertex
+        let w: Vec<f32> = (0..n).map(|k| if k % 4 == 3 { f32::INFINITY } else { 0.25 * k as f32 }).collect();
+        let expected_weighted = weighted_reference(&d, &w, n);
+        for &version in &Version::ALL {
+            for params in [params::get(), &small_params] {
+                let mut r = vec![0.0; n * n];
+                version.step_weighted_with_params(&mut r, &d, &w, n, params);
+                ok &= compare(&format!("{} weighted", version.name()), n, &r, &expected_weighted);
+            }
+        }
+        // The same for a symmetric input, with and without checking its symmetry
+        let d_sym: Vec<f32> = (0..n * n).map(|ij| d[ij].min(d[n * (ij % n) + ij / n])).collect();
+        let expected_sym = baseline(&d_sym, n);
+        for &version in Version::ALL.iter().skip(1) {
+            for (params, check) in [(params::get(), true), (&small_params, false)] {
+                let mut r = vec![0.0; n * n];
+                version.step_symmetric_with_params(&mut r, &d_sym, n, params, check);
+                ok &= compare(&format!("{} symmetric", version.name()), n, &r, &expected_sym);
+            }
+            // Not symmetric, so the check falls back to computing all results
+            let mut r = vec![0.0; n * n];
+            version.step_symmetric_with_params(&mut r, &d, n, &small_params, true);
+            ok &= compare(&format!("{} symmetric, checked", version.name()), n, &r, &expected);
+        }
+        // The checked variants report whether d was symmetric, d of 1×1 always is
+        for (input, expected_r) in [(&d_sym, &expected_sym), (&d, &expected)] {
+            let symmetric = tools::is_symmetric(input, n);
+            let mut r = vec![0.0; n * n];
+            if v5_more_register_reuse::step_symmetric_checked(&mut r, input, n) != symmetric {
+                eprintln!("v5_more_register_reuse step_symmetric_checked n = {}: expected {}", n, symmetric);
+                ok = false;
+            }
+            ok &= compare("v5_more_register_reuse step_symmetric_checked", n, &r, expected_r);
+            let mut r = vec![0.0; n * n];
+            if v7_cache_reuse::step_symmetric_checked_with_params(&mut r, input, n, &small_params) != symmetric {
+                eprintln!("v7_cache_reuse step_symmetric_checked n = {}: expected {}", n, symmetric);
+                ok = false;
+            }
+            ok &= compare("v7_cache_reuse step_symmetric_checked", n, &r, expected_r);
+        }
+        // Incremental updates after lowering, raising and removing edges, one at a time and batched
+        let changes: Vec<EdgeChange> = (0..n).flat_map(|u| {
+            let v = (3 * u + 1) % n;
+            [
+                EdgeChange { u, v, weight: 0.0 },
+                EdgeChange { u: v, v: u, weight: 0.5 * d[n * v + u] + 1.0 },
+                EdgeChange { u, v: u, weight: f32::INFINITY },
+            ]
+        }).collect();
+        let (mut r, mut d_changed) = (expected.clone(), d.clone());
+        for c in &changes {
+            incremental::update_edge(&mut r, &mut d_changed, n, c.u, c.v, c.weight);
+            ok &= compare(&format!("update_edge {:?}", c), n, &r, &baseline(&d_changed, n));
+        }
+        let (mut r, mut d_changed) = (expected.clone(), d.clone());
+        incremental::update_edges(&mut r, &mut d_changed, n, &changes);
+        ok &= compare("update_edges", n, &r, &baseline(&d_changed, n));
+        // Panels of 8 and 16 rows
+        for panel_rows in [8, 16] {
+            let mut r = vec![0.0; n * n];
+            for (panel, r_rows) in r.chunks_mut(panel_rows * n.max(1)).enumerate() {
+                v7_cache_reuse::step_rows_with_params(r_rows, &d, n, panel * panel_rows, &small_params);
+            }
+            ok &= compare(&format!("v7_cache_reuse step_rows of {} rows", panel_rows), n, &r, &expected);
+        }
+        // Some rows in any order, with duplicates, and (i, j) pairs from all over r
+        let rows: Vec<usize> = (0..n + 3).filter_map(|k| (5 * k + 2).checked_rem(n)).collect();
+        let expected_rows: Vec<f32> = rows.iter().flat_map(|&i| expected[n * i..n * (i + 1)].to_vec()).collect();
+        let pairs: Vec<(usize, usize)> = (0..2 * n).map(|k| ((3 * k + 1) % n, (7 * k) % n)).collect();
+        let expected_pairs: Vec<f32> = pairs.iter().map(|&(i, j)| expected[n * i + j]).collect();
+        let step_row_subsets: [(&str, StepRowSubset); 2] = [
+            ("v3_simd", v3_simd::step_row_subset),
+            ("v5_more_register_reuse", v5_more_register_reuse::step_row_subset),
+        ];
+        for (name, step_row_subset) in step_row_subsets {
+            let mut r_rows = vec![0.0; rows.len() * n];
+            step_row_subset(&mut r_rows, &d, n, &rows);
+            ok &= compare(&format!("{} step_row_subset", name), n, &r_rows, &expected_rows);
+        }
+        let step_pairs: [(&str, StepPairs); 2] = [
+            ("v3_simd", v3_simd::step_pairs),
+            ("v5_more_register_reuse", v5_more_register_reuse::step_pairs),
+        ];
+        for (name, step_pairs) in step_pairs {
+            let mut results = vec![0.0; pairs.len()];
+            step_pairs(&mut results, &d, n, &pairs);
+            ok &= compare(&format!("{} step_pairs", name), n, &results, &expected_pairs);
+            // No pairs of an empty matrix
+            step_pairs(&mut [], &[], 0, &[]);
+        }
+        // A batch of matrices, each with a different input
+        let batch = 3;
+        let d_batch: Vec<f32> = (0..batch).flat_map(|b| tune::random_input(n, (n + b) as u32)).collect();
+        let mut r_batch = vec![0.0; batch * n * n];
+        v5_more_register_reuse::step_batch(&mut r_batch, &d_batch, n);
+        for (r, d) in r_batch.chunks_exact((n * n).max(1)).zip(d_batch.chunks_exact((n * n).max(1))) {
+            ok &= compare("v5_more_register_reuse step_batch", n, r, &baseline(d, n));
+        }
+        // Pairs (distance, cost) in lexicographic order, with distances of few distinct values so that many
+        // paths tie and their costs decide, and some infinite distances
+        let d_dist: Vec<f32> = d.iter().enumerate().map(|(ij, &x)| if ij % 7 == 3 { f32::INFINITY } else { (4.0 * x).floor() }).collect();
+        let d_cost = tune::random_input(n, n as u32 + 100);
+        let (mut expected_dist, mut expected_cost) = (vec![0.0; n * n], vec![0.0; n * n]);
+        v0_baseline::step_lexicographic(&mut expected_dist, &mut expected_cost, &d_dist, &d_cost, n);
+        ok &= compare("v0_baseline lexicographic distances", n, &expected_dist, &baseline(&d_dist, n));
+        let (mut r_dist, mut r_cost) = (vec![0.0; n * n], vec![0.0; n * n]);
+        v5_more_register_reuse::step_lexicographic(&mut r_dist, &mut r_cost, &d_dist, &d_cost, n);
+        ok &= compare("v5_more_register_reuse lexicographic distances", n, &r_dist, &expected_dist);
+        ok &= compare("v5_more_register_reuse lexicographic costs", n, &r_cost, &expected_cost);
+        // Inputs and results with padding between their rows or columns, in all combinations of storage orders.
+        // The padding of d is NaN, which would propagate into the results if it was read.
+        for (r_col_major, d_col_major) in [(false, false), (false, true), (true, false), (true, true)] {
+            let r_layout = Layout { ld: n + 5, col_major: r_col_major };
+            let d_layout = Layout { ld: n + 3, col_major: d_col_major };
+            let mut d_strided = vec![f32::NAN; d_layout.len(n)];
+            layout::scatter(&d, &mut d_strided, d_layout, n);
+            let name = |version: &str| format!("{} strided {:?} {:?}", version, r_layout, d_layout);
+            for &version in &Version::ALL {
+                let mut r = vec![PADDING; r_layout.len(n)];
+                version.step_strided_with_params(&mut r, r_layout, &d_strided, d_layout, n, &small_params);
+                ok &= compare_strided(&name(version.name()), n, &r, r_layout, &expected);
+            }
+            #[cfg(feature = "c-abi")]
+            {
+                let mut r = vec![PADDING; r_layout.len(n)];
+                unsafe {
+                    step_strided(r.as_mut_ptr(), r_layout.ld as i32, r_col_major as i32,
+                                 d_strided.as_ptr(), d_layout.ld as i32, d_col_major as i32, n as i32)
+                };
+                ok &= compare_strided(&name("extern \"C\" step_strided"), n, &r, r_layout, &expected);
+            }
+        }
+        // Matrix files, with some missing edges
+        let d_io: Vec<f32> = d.iter().enumerate().map(|(ij, &x)| if ij % 5 == 1 { f32::INFINITY } else { x }).collect();
+        ok &= check_io(&d_io, n);
+        #[cfg(feature = "c-abi")]
+        {
+            let mut r = vec![0.0; n * n];
+            unsafe { step(r.as_mut_ptr(), d.as_ptr(), n as i32) };
+            ok &= compare("extern \"C\" step", n, &r, &expected);
+            let mut r = r_old.clone();
+            unsafe { step_accumulate(r.as_mut_ptr(), d.as_ptr(), n as i32) };
+            ok &= compare("extern \"C\" step_accumulate", n, &r, &expected_acc);
+            let mut rd = d.clone();
+            unsafe { step_in_place(rd.as_mut_ptr(), n as i32) };
+            ok &= compare("extern \"C\" step_in_place", n, &rd, &expected);
+            let mut r = vec![0.0; n * n];
+            unsafe { step_weighted(r.as_mut_ptr(), d.as_ptr(), w.as_ptr(), n as i32) };
+            ok &= compare("extern \"C\" step_weighted", n, &r, &expected_weighted);
+        }
+    }
+    if !ok {
+        process::exit(1);
+    }
+    println!("all versions agree with {} for n in {:?}", Version::V0Baseline.name(), sizes);
+}
@@ -0,0 +1,105 @@
+//! Update r after some edge weights of d changed, without computing all of r again.
+//!
+//! Only the terms d[u][k] + d[k][j] with k = v and d[i][k] + d[k][v] with k = u contain d[u][v],
+//! so changing it can change only row u and column v of r, which takes O(n²) time to recompute
+//! instead of the O(n³) of a full step.
+//! All functions expect that r contains the results of d before the change.
+use tools::par::prelude::*;
+
+/// New weight of the edge from u to v, i.e. d[u][v]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct EdgeChange {
+    pub u: usize,
+    pub v: usize,
+    pub weight: f32,
+}
+
+/// Set all of r_row to row i of the result, min_k d[i][k] + d[k][j] for each column j,
+/// reading d one row at a time
+fn compute_row(r_row: &mut [f32], d: &[f32], n: usize, i: usize) {
+    r_row.fill(f32::INFINITY);
+    for (&x, d_row) in d[n * i..n * (i + 1)].iter().zip(d.chunks_exact(n)) {
+        for (res, &y) in r_row.iter_mut().zip(d_row) {
+            *res = tools::min(*res, x + y);
+        }
+    }
+}
+
+/// Result for one element of row d_row, given the column of d as a contiguous slice
+fn compute_element(d_row: &[f32], d_col: &[f32]) -> f32 {
+    d_row.iter().zip(d_col).fold(f32::INFINITY, |res, (&x, &y)| tools::min(res, x + y))
+}
+
+/// Column j of d as a contiguous vector
+fn column(d: &[f32], n: usize, j: usize) -> Vec<f32> {
+    d.chunks_exact(n).map(|d_row| d_row[j]).collect()
+}
+
+/// Set d[u][v] to `weight` and update r to the results of the changed d.
+///
+/// Results of row u and column v that were equal to the old term containing d[u][v] may have depended on it,
+/// and are recomputed. All other results are only lowered to the new term, if it is smaller.
+/// If the weight decreases and no result was equal to an old term, this takes only O(n) time.
+pub fn update_edge(r: &mut [f32], d: &mut [f32], n: usize, u: usize, v: usize, weight: f32) {
+    assert!(u < n && v < n, "edge ({}, {}) is outside of the {}×{} matrix", u, v, n, n);
+    let old = d[n * u + v];
+    if old.to_bits() == weight.to_bits() {
+        return;
+    }
+    // Find the results that may have depended on the old weight, before changing it
+    let row_stale = (0..n).any(|j| r[n * u + j] == old + d[n * v + j]);
+    let col_stale: Vec<bool> = (0..n).map(|i| r[n * i + v] == d[n * i + u] + old).collect();
+    d[n * u + v] = weight;
+
+    // Row u: r[u][j] = min_k d[u][k] + d[k][j], of which only the term with k = v changed
+    let r_row = &mut r[n * u..n * (u + 1)];
+    if row_stale {
+        compute_row(r_row, d, n, u);
+    } else {
+        for (res, &y) in r_row.iter_mut().zip(&d[n * v..n * (v + 1)]) {
+            *res = tools::min(*res, weight + y);
+        }
+    }
+    // Column v: r[i][v] = min_k d[i][k] + d[k][v], of which only the term with k = u changed
+    let d_col = column(d, n, v);
+    for (i, &stale) in col_stale.iter().enumerate() {
+        let res = &mut r[n * i + v];
+        *res = if stale {
+            compute_element(&d[n * i..n * (i + 1)], &d_col)
+        } else {
+            tools::min(*res, d[n * i + u] + weight)
+        };
+    }
+}
+
+/// Apply all changes to d, in the given order, and update r to the results of the changed d.
+///
+/// All rows u and columns v of the changed edges are recomputed, in parallel over the rows of r.
+/// This takes O((rows + columns) · n²) time, regardless of how the weights changed.
+pub fn update_edges(r: &mut [f32], d: &mut [f32], n: usize, changes: &[EdgeChange]) {
+    let mut changed_rows = vec![false; n];
+    let mut changed_cols = vec![false; n];
+    for c in changes {
+        assert!(c.u < n && c.v < n, "edge ({}, {}) is outside of the {}×{} matrix", c.u, c.v, n, n);
+        d[n * c.u + c.v] = c.weight;
+        changed_rows[c.u] = true;
+        changed_cols[c.v] = true;
+    }
+    if n == 0 {
+        return;
+    }
+    let d: &[f32] = d;
+    let d_cols: Vec<(usize, Vec<f32>)> = (0..n).filter(|&j| changed_cols[j]).map(|j| (j, column(d, n, j))).collect();
+    let update_row = |(i, r_row): (usize, &mut [f32])| {
+        if changed_rows[i] {
+            compute_row(r_row, d, n, i);
+        } else {
+            for (j, d_col) in &d_cols {
+                r_row[*j] = compute_element(&d[n * i..n * (i + 1)], d_col);
+            }
+        }
+    };
+    r.par_chunks_mut(n)
+        .enumerate()
+        .for_each(update_row);
+}
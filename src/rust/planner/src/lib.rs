@@ -0,0 +1,373 @@
+//! Choose the step implementation and its parameters for a given input size.
+//!
+//! Which version is the fastest depends heavily on n, e.g. for small inputs the preprocessing
+//! in v7 and the few, large tasks of v5 cost more than they save.
+//! Without benchmark results, the planner uses rough size thresholds.
+//! Seeding it with benchmark results from this machine, e.g. CSV reports written by bench.py,
+//! replaces the thresholds by the measured throughput of each version.
+use std::fmt;
+use std::fs;
+use std::io::{self, BufRead};
+use std::path::Path;
+use std::sync::OnceLock;
+
+use tools::{create_extern_c_in_place_wrapper, create_extern_c_strided_wrapper, create_extern_c_weighted_wrapper, create_extern_c_wrapper, par};
+use tools::kfilter::KFilter;
+use tools::layout::Layout;
+use tools::params::Params;
+
+pub mod incremental;
+pub mod tune;
+mod version;
+pub use version::{CpuFeatures, Version};
+
+/// If n is smaller than this, the work per thread is too small to amortize the cost of parallelism
+pub const DEFAULT_PARALLEL_MIN_N: usize = 128;
+
+/// Decision of the planner for one input size
+#[derive(Clone, Debug, PartialEq)]
+pub struct Plan {
+    pub n: usize,
+    pub version: Version,
+    /// Run the main loop in parallel, or all of it in the calling thread
+    pub parallel: bool,
+    /// Amount of threads used if parallel
+    pub threads: usize,
+    /// Adjacent columns of d processed during one pass over the input
+    pub stripe_width: usize,
+    /// Adjacent rows of r computed by one task
+    pub block_height: usize,
+    /// All tunable parameters, of which stripe_width and block_height are derived
+    pub params: Params,
+    /// Why the version was chosen
+    pub version_reason: String,
+    /// Why the main loop does or does not run in parallel
+    pub parallel_reason: String,
+}
+
+impl Plan {
+    /// Compute all results of d into r according to the plan
+    pub fn step(&self, r: &mut [f32], d: &[f32]) {
+        assert_eq!(d.len(), self.n * self.n, "plan is for a different input size");
+        if self.parallel {
+            self.version.step_with_params(r, d, self.n, &self.params);
+        } else {
+            par::run_sequentially(|| self.version.step_with_params(r, d, self.n, &self.params));
+        }
+    }
+
+    /// Like step, but fold the results into r, i.e. r = min(r, d ⊗ d)
+    pub fn step_accumulate(&self, r: &mut [f32], d: &[f32]) {
+        assert_eq!(d.len(), self.n * self.n, "plan is for a different input size");
+        if self.parallel {
+            self.version.step_accumulate_with_params(r, d, self.n, &self.params);
+        } else {
+            par::run_sequentially(|| self.version.step_accumulate_with_params(r, d, self.n, &self.params));
+        }
+    }
+
+    /// Like step, but d is read from rd, which is then overwritten with the results
+    pub fn step_in_place(&self, rd: &mut [f32]) {
+        assert_eq!(rd.len(), self.n * self.n, "plan is for a different input size");
+        if self.parallel {
+            self.version.step_in_place_with_params(rd, self.n, &self.params);
+        } else {
+            par::run_sequentially(|| self.version.step_in_place_with_params(rd, self.n, &self.params));
+        }
+    }
+
+    /// Like step, but minimize only over the intermediate nodes allowed by filter, see tools::kfilter
+    pub fn step_filtered(&self, r: &mut [f32], d: &[f32], filter: &KFilter) {
+        assert_eq!(d.len(), self.n * self.n, "plan is for a different input size");
+        if self.parallel {
+            self.version.step_filtered_with_params(r, d, self.n, filter, &self.params);
+        } else {
+            par::run_sequentially(|| self.version.step_filtered_with_params(r, d, self.n, filter, &self.params));
+        }
+    }
+
+    /// Like step, but with a weight w[k] for each vertex k added to the paths through it, see tools::weights
+    pub fn step_weighted(&self, r: &mut [f32], d: &[f32], w: &[f32]) {
+        assert_eq!(d.len(), self.n * self.n, "plan is for a different input size");
+        if self.parallel {
+            self.version.step_weighted_with_params(r, d, w, self.n, &self.params);
+        } else {
+            par::run_sequentially(|| self.version.step_weighted_with_params(r, d, w, self.n, &self.params));
+        }
+    }
+
+    /// Like step, but for r and d stored with the given layouts, see tools::layout
+    pub fn step_strided(&self, r: &mut [f32], r_layout: Layout, d: &[f32], d_layout: Layout) {
+        if self.parallel {
+            self.version.step_strided_with_params(r, r_layout, d, d_layout, self.n, &self.params);
+        } else {
+            par::run_sequentially(|| self.version.step_strided_with_params(r, r_layout, d, d_layout, self.n, &self.params));
+        }
+    }
+}
+
+impl fmt::Display for Plan {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "n: {}", self.n)?;
+        writeln!(f, "version: {} ({})", self.version.name(), self.version_reason)?;
+        if self.parallel {
+            writeln!(f, "parallel: {} threads ({})", self.threads, self.parallel_reason)?;
+        } else {
+            writeln!(f, "parallel: no ({})", self.parallel_reason)?;
+        }
+        writeln!(f, "stripe width: {} columns", self.stripe_width)?;
+        write!(f, "block height: {} rows", self.block_height)
+    }
+}
+
+/// Benchmark results of one version as (n, GFLOP/s), sorted by n
+type Measurements = Vec<(usize, f64)>;
+
+pub struct Planner {
+    threads: usize,
+    cpu: CpuFeatures,
+    parallel_min_n: usize,
+    params: Params,
+    measurements: Vec<(Version, Measurements)>,
+}
+
+impl Default for Planner {
+    fn default() -> Planner {
+        Planner::new()
+    }
+}
+
+impl Planner {
+    /// Planner for the hardware running this process
+    pub fn new() -> Planner {
+        Planner::with_hardware(par::num_threads(), CpuFeatures::detect())
+    }
+
+    /// Planner for some other, possibly hypothetical, hardware
+    pub fn with_hardware(threads: usize, cpu: CpuFeatures) -> Planner {
+        Planner {
+            threads: threads.max(1),
+            cpu,
+            parallel_min_n: DEFAULT_PARALLEL_MIN_N,
+            params: *tools::params::get(),
+            measurements: Vec::new(),
+        }
+    }
+
+    pub fn set_parallel_min_n(&mut self, n: usize) {
+        self.parallel_min_n = n;
+    }
+
+    /// Use these parameters instead of the ones tuned for this machine
+    pub fn set_params(&mut self, params: Params) {
+        self.params = params;
+    }
+
+    /// Add benchmark results for a version from CSV data with the columns "N (rows)" and "GFLOP/s",
+    /// as written by bench.py. Rows with the same N are averaged.
+    pub fn seed_from_csv<R: BufRead>(&mut self, version: Version, reader: R) -> io::Result<()> {
+        let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidData, msg);
+        let mut lines = reader.lines();
+        let header = match lines.next() {
+            Some(line) => line?,
+            None => return Err(invalid("empty benchmark report".to_string())),
+        };
+        let columns: Vec<&str> = header.split(',').map(str::trim).collect();
+        let column = |name: &str| {
+            columns.iter().position(|&c| c == name)
+                .ok_or_else(|| invalid(format!("benchmark report has no column '{}'", name)))
+        };
+        let (n_col, gflops_col) = (column("N (rows)")?, column("GFLOP/s")?);
+        // (n, sum of GFLOP/s, amount of rows)
+        let mut sums: Vec<(usize, f64, usize)> = Vec::new();
+        for line in lines {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let fields: Vec<&str> = line.split(',').map(str::trim).collect();
+            let field = |i: usize| fields.get(i).ok_or_else(|| invalid(format!("too few columns in '{}'", line)));
+            let n: usize = field(n_col)?.parse().map_err(|_| invalid(format!("invalid N in '{}'", line)))?;
+            let gflops: f64 = field(gflops_col)?.parse().map_err(|_| invalid(format!("invalid GFLOP/s in '{}'", line)))?;
+            match sums.iter_mut().find(|s| s.0 == n) {
+                Some(s) => {
+                    s.1 += gflops;
+                    s.2 += 1;
+                }
+                None => sums.push((n, gflops, 1)),
+            }
+        }
+        let mut measurements: Measurements = sums.into_iter().map(|(n, sum, count)| (n, sum / count as f64)).collect();
+        measurements.sort_by_key(|m| m.0);
+        self.measurements.retain(|m| m.0 != version);
+        if !measurements.is_empty() {
+            self.measurements.push((version, measurements));
+        }
+        Ok(())
+    }
+
+    /// Seed from all reports named by short version names, e.g. "v5.csv", in a directory
+    /// such as 'rust' in the --report_dir of bench.py.
+    /// Returns the amount of versions that had a report.
+    pub fn seed_from_report_dir<P: AsRef<Path>>(&mut self, dir: P) -> io::Result<usize> {
+        let mut seeded = 0;
+        for &version in Version::ALL.iter() {
+            let path = dir.as_ref().join(format!("{}.csv", version.short_name()));
+            if path.is_file() {
+                self.seed_from_csv(version, io::BufReader::new(fs::File::open(path)?))?;
+                seeded += 1;
+            }
+        }
+        Ok(seeded)
+    }
+
+    /// Throughput of a version at n, interpolated linearly in log(n) between the nearest measurements
+    pub fn estimated_gflops(&self, version: Version, n: usize) -> Option<f64> {
+        let (_, m) = self.measurements.iter().find(|m| m.0 == version)?;
+        let (first, last) = (m[0], m[m.len() - 1]);
+        if n <= first.0 {
+            return Some(first.1);
+        }
+        if n >= last.0 {
+            return Some(last.1);
+        }
+        let upper = m.iter().position(|&(m_n, _)| m_n >= n).unwrap();
+        let ((n0, g0), (n1, g1)) = (m[upper - 1], m[upper]);
+        let t = ((n as f64).ln() - (n0 as f64).ln()) / ((n1 as f64).ln() - (n0 as f64).ln());
+        Some(g0 + t * (g1 - g0))
+    }
+
+    fn choose_version(&self, n: usize) -> (Version, String) {
+        let estimates: Vec<(Version, f64)> = Version::ALL.iter()
+            .filter(|v| v.is_supported(&self.cpu))
+            .filter_map(|&v| self.estimated_gflops(v, n).map(|g| (v, g)))
+            .collect();
+        if let Some(&(best, _)) = estimates.iter().max_by(|a, b| a.1.total_cmp(&b.1)) {
+            let all = estimates.iter()
+                .map(|(v, g)| format!("{} {:.2}", v.short_name(), g))
+                .collect::<Vec<String>>()
+                .join(", ");
+            return (best, format!("highest estimated GFLOP/s from benchmarks: {}", all));
+        }
+        if !self.cpu.avx {
+            return (Version::V2InstrLevelParallelism, "no benchmarks, CPU does not support AVX".to_string());
+        }
+        let version = match n {
+            0..=159 => Version::V3Simd,
+            160..=999 => Version::V5MoreRegisterReuse,
+            _ if n < 2500 || !self.cpu.bmi2 => Version::V6Prefetch,
+            _ => Version::V7CacheReuse,
+        };
+        (version, "no benchmarks, default for this size".to_string())
+    }
+
+    pub fn plan(&self, n: usize) -> Plan {
+        let (version, version_reason) = self.choose_version(n);
+        let tasks = version.parallel_tasks(n, &self.params);
+        let (parallel, parallel_reason) = if self.threads < 2 {
+            (false, "only one thread available".to_string())
+        } else if n < self.parallel_min_n {
+            (false, format!("n < {}, too little work per thread", self.parallel_min_n))
+        } else if tasks < self.threads {
+            (false, format!("only {} tasks for {} threads", tasks, self.threads))
+        } else {
+            (true, format!("{} tasks", tasks))
+        };
+        Plan {
+            n,
+            version,
+            parallel,
+            threads: if parallel { self.threads } else { 1 },
+            stripe_width: version.stripe_width(n, &self.params),
+            block_height: version.block_height(&self.params),
+            params: self.params,
+            version_reason,
+            parallel_reason,
+        }
+    }
+
+    /// Human readable description of the plan for n and the hardware it was made for
+    pub fn explain(&self, n: usize) -> String {
+        format!("hardware: {} threads, CPU features: {}, caches: {}\n{}",
+                self.threads, self.cpu.names(), tools::cpu::caches(), self.plan(n))
+    }
+
+    /// Compute all results of d into r with the version and parameters planned for n
+    pub fn step(&self, r: &mut [f32], d: &[f32], n: usize) {
+        self.plan(n).step(r, d);
+    }
+
+    /// Fold the results of d into r with the version and parameters planned for n, i.e. r = min(r, d ⊗ d)
+    pub fn step_accumulate(&self, r: &mut [f32], d: &[f32], n: usize) {
+        self.plan(n).step_accumulate(r, d);
+    }
+
+    /// Overwrite d in rd with its results, with the version and parameters planned for n
+    pub fn step_in_place(&self, rd: &mut [f32], n: usize) {
+        self.plan(n).step_in_place(rd);
+    }
+
+    /// Compute the results of d over the intermediate nodes allowed by filter with the version and parameters planned for n
+    pub fn step_filtered(&self, r: &mut [f32], d: &[f32], n: usize, filter: &KFilter) {
+        self.plan(n).step_filtered(r, d, filter);
+    }
+
+    /// Compute the results of d with the vertex weights w with the version and parameters planned for n
+    pub fn step_weighted(&self, r: &mut [f32], d: &[f32], w: &[f32], n: usize) {
+        self.plan(n).step_weighted(r, d, w);
+    }
+
+    /// Like step, but for r and d stored with the given layouts, see tools::layout
+    pub fn step_strided(&self, r: &mut [f32], r_layout: Layout, d: &[f32], d_layout: Layout, n: usize) {
+        self.plan(n).step_strided(r, r_layout, d, d_layout);
+    }
+}
+
+/// Planner for this machine, created on first use
+fn planner() -> &'static Planner {
+    static PLANNER: OnceLock<Planner> = OnceLock::new();
+    PLANNER.get_or_init(Planner::new)
+}
+
+/// Compute r with the version planned for n on this machine, with the tuned parameters
+#[inline]
+pub fn step(r: &mut [f32], d: &[f32], n: usize) {
+    planner().step(r, d, n);
+}
+
+/// Like step, but fold the results into r, i.e. r = min(r, d ⊗ d)
+#[inline]
+pub fn step_accumulate(r: &mut [f32], d: &[f32], n: usize) {
+    planner().step_accumulate(r, d, n);
+}
+
+/// Like step, but d is read from rd, which is then overwritten with the results
+#[inline]
+pub fn step_in_place(rd: &mut [f32], n: usize) {
+    planner().step_in_place(rd, n);
+}
+
+/// Like step, but minimize only over the intermediate nodes allowed by filter, see tools::kfilter
+#[inline]
+pub fn step_filtered(r: &mut [f32], d: &[f32], n: usize, filter: &KFilter) {
+    planner().step_filtered(r, d, n, filter);
+}
+
+/// Like step, but with a weight w[k] for each vertex k added to the paths through it, see tools::weights
+#[inline]
+pub fn step_weighted(r: &mut [f32], d: &[f32], w: &[f32], n: usize) {
+    planner().step_weighted(r, d, w, n);
+}
+
+/// Like step, but for r and d stored with the given layouts, see tools::layout
+#[inline]
+pub fn step_strided(r: &mut [f32], r_layout: Layout, d: &[f32], d_layout: Layout, n: usize) {
+    planner().step_strided(r, r_layout, d, d_layout, n);
+}
+
+
+create_extern_c_wrapper!(step, step);
+create_extern_c_wrapper!(step_accumulate, step_accumulate);
+create_extern_c_in_place_wrapper!(step_in_place, step_in_place);
+create_extern_c_weighted_wrapper!(step_weighted, step_weighted);
+create_extern_c_strided_wrapper!(step_strided, step_strided);
@@ -0,0 +1,120 @@
+//! Search for the parameters of the step implementations that are fastest on this machine.
+//!
+//! Every parameter affects only one version, so the parameters are tuned independently
+//! by timing that version with each candidate value on some representative input sizes.
+use std::time::Instant;
+
+use tools::params::{self, Params};
+
+use crate::Version;
+
+/// One tunable parameter and the values to try for it
+pub struct Dimension {
+    pub name: &'static str,
+    /// The version whose running time depends on the parameter
+    pub version: Version,
+    pub candidates: Vec<usize>,
+    set: fn(&mut Params, usize),
+    show: fn(usize) -> String,
+}
+
+impl Dimension {
+    pub fn apply(&self, params: &mut Params, value: usize) {
+        (self.set)(params, value)
+    }
+
+    /// Human readable form of a candidate value
+    pub fn show(&self, value: usize) -> String {
+        (self.show)(value)
+    }
+}
+
+pub fn dimensions() -> Vec<Dimension> {
+    vec![
+        Dimension {
+            name: "block_size",
+            version: Version::V2InstrLevelParallelism,
+            candidates: params::BLOCK_SIZES.to_vec(),
+            set: |p, v| p.block_size = v,
+            show: |v| v.to_string(),
+        },
+        // Height and width are tuned together, the candidates are indexes to BLOCK_SHAPES
+        Dimension {
+            name: "block_shape",
+            version: Version::V4RegisterReuse,
+            candidates: (0..params::BLOCK_SHAPES.len()).collect(),
+            set: |p, v| (p.block_height, p.block_width) = params::BLOCK_SHAPES[v],
+            show: |v| format!("{}x{}", params::BLOCK_SHAPES[v].0, params::BLOCK_SHAPES[v].1),
+        },
+        Dimension {
+            name: "prefetch_length",
+            version: Version::V6Prefetch,
+            candidates: vec![0, 4, 8, 12, 16, 20, 24, 32, 48, 64],
+            set: |p, v| p.prefetch_length = v,
+            show: |v| v.to_string(),
+        },
+        Dimension {
+            name: "cols_per_stripe",
+            version: Version::V7CacheReuse,
+            candidates: vec![100, 200, 300, 400, 500, 750, 1000, 1500, 2000],
+            set: |p, v| p.cols_per_stripe = v,
+            show: |v| v.to_string(),
+        },
+        Dimension {
+            name: "z_block_size",
+            version: Version::V7CacheReuse,
+            candidates: vec![1, 4, 16, 64, 256, 1024],
+            set: |p, v| p.z_block_size = v,
+            show: |v| v.to_string(),
+        },
+    ]
+}
+
+/// Deterministic, uniformly distributed input in [0, 1) using xorshift
+pub fn random_input(n: usize, seed: u32) -> Vec<f32> {
+    let mut state = seed.max(1);
+    (0..n * n).map(|_| {
+        state ^= state << 13;
+        state ^= state >> 17;
+        state ^= state << 5;
+        (state >> 8) as f32 / (1 << 24) as f32
+    }).collect()
+}
+
+/// Total seconds spent by the fastest of `repetitions` runs of a version on each input size
+pub fn measure(version: Version, params: &Params, sizes: &[usize], repetitions: usize) -> f64 {
+    sizes.iter().map(|&n| {
+        let d = random_input(n, n as u32);
+        let mut r = vec![0.0; n * n];
+        (0..repetitions.max(1)).map(|_| {
+            let begin = Instant::now();
+            version.step_with_params(&mut r, &d, n, params);
+            begin.elapsed().as_secs_f64()
+        }).fold(f64::INFINITY, f64::min)
+    }).sum()
+}
+
+/// Tune all parameters, starting from `initial`.
+/// `report` is called with each dimension, candidate value and its measured seconds.
+pub fn autotune<F>(initial: Params, sizes: &[usize], repetitions: usize, mut report: F) -> Params
+where
+    F: FnMut(&Dimension, usize, f64),
+{
+    let mut best = initial;
+    for dim in dimensions() {
+        let mut fastest = (f64::INFINITY, None);
+        for &value in &dim.candidates {
+            let mut params = best;
+            dim.apply(&mut params, value);
+            let seconds = measure(dim.version, &params, sizes, repetitions);
+            report(&dim, value, seconds);
+            if seconds < fastest.0 {
+                fastest = (seconds, Some(value));
+            }
+        }
+        if let Some(value) = fastest.1 {
+            dim.apply(&mut best, value);
+        }
+    }
+    best
+}
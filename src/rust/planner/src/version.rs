@@ -0,0 +1,323 @@
+use tools::kfilter::KFilter;
+use tools::layout::{self, Layout};
+use tools::params::Params;
+
+/// All step implementations, in the order of src/step_implementations.txt,
+/// followed by v5_portable_simd from src/rust_only_step_implementations.txt with the cargo feature portable-simd
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Version {
+    V0Baseline,
+    V1LinearReading,
+    V2InstrLevelParallelism,
+    V3Simd,
+    V4RegisterReuse,
+    V5MoreRegisterReuse,
+    V6Prefetch,
+    V7CacheReuse,
+    #[cfg(feature = "portable-simd")]
+    V5PortableSimd,
+}
+
+impl Version {
+    #[cfg(not(feature = "portable-simd"))]
+    pub const ALL: [Version; 8] = [
+        Version::V0Baseline,
+        Version::V1LinearReading,
+        Version::V2InstrLevelParallelism,
+        Version::V3Simd,
+        Version::V4RegisterReuse,
+        Version::V5MoreRegisterReuse,
+        Version::V6Prefetch,
+        Version::V7CacheReuse,
+    ];
+    #[cfg(feature = "portable-simd")]
+    pub const ALL: [Version; 9] = [
+        Version::V0Baseline,
+        Version::V1LinearReading,
+        Version::V2InstrLevelParallelism,
+        Version::V3Simd,
+        Version::V4RegisterReuse,
+        Version::V5MoreRegisterReuse,
+        Version::V6Prefetch,
+        Version::V7CacheReuse,
+        Version::V5PortableSimd,
+    ];
+
+    /// Name of the crate implementing this version
+    pub fn name(self) -> &'static str {
+        match self {
+            Version::V0Baseline => "v0_baseline",
+            Version::V1LinearReading => "v1_linear_reading",
+            Version::V2InstrLevelParallelism => "v2_instr_level_parallelism",
+            Version::V3Simd => "v3_simd",
+            Version::V4RegisterReuse => "v4_register_reuse",
+            Version::V5MoreRegisterReuse => "v5_more_register_reuse",
+            Version::V6Prefetch => "v6_prefetch",
+            Version::V7CacheReuse => "v7_cache_reuse",
+            #[cfg(feature = "portable-simd")]
+            Version::V5PortableSimd => "v5_portable_simd",
+        }
+    }
+
+    /// Short name used in benchmark reports, e.g. "v5".
+    /// Rust-only versions share the number of another version, so bench.py names their reports by the crate name.
+    pub fn short_name(self) -> &'static str {
+        match self {
+            #[cfg(feature = "portable-simd")]
+            Version::V5PortableSimd => self.name(),
+            _ => &self.name()[..2],
+        }
+    }
+
+    /// Find a version by its crate name or short name, e.g. both "v7_cache_reuse" and "v7" are accepted
+    pub fn from_name(name: &str) -> Option<Version> {
+        Version::ALL.iter().cloned().find(|v| v.name() == name || v.short_name() == name)
+    }
+
+    /// True if the version can run on a CPU with the given features
+    pub fn is_supported(self, cpu: &CpuFeatures) -> bool {
+        // Emulated vector operations and Z-order curve keys need no extensions
+        if tools::simd::IS_EMULATED {
+            return true;
+        }
+        match self {
+            Version::V0Baseline | Version::V1LinearReading | Version::V2InstrLevelParallelism => true,
+            Version::V3Simd | Version::V4RegisterReuse | Version::V5MoreRegisterReuse | Version::V6Prefetch => cpu.avx,
+            // Z-order curve keys are computed with pdep
+            Version::V7CacheReuse => cpu.avx && cpu.bmi2,
+            // Compiled for the vectors enabled for the target, see v5_portable_simd::LANES
+            #[cfg(feature = "portable-simd")]
+            Version::V5PortableSimd => true,
+        }
+    }
+
+    /// Amount of adjacent rows of r computed by one task
+    pub fn block_height(self, params: &Params) -> usize {
+        match self {
+            Version::V0Baseline
+            | Version::V1LinearReading
+            | Version::V2InstrLevelParallelism
+            | Version::V3Simd => 1,
+            Version::V4RegisterReuse => params.block_height,
+            Version::V5MoreRegisterReuse | Version::V6Prefetch | Version::V7CacheReuse => tools::simd::f32x8_LENGTH,
+            #[cfg(feature = "portable-simd")]
+            Version::V5PortableSimd => v5_portable_simd::LANES,
+        }
+    }
+
+    /// Amount of adjacent columns of d processed during one pass over the input
+    pub fn stripe_width(self, n: usize, params: &Params) -> usize {
+        match self {
+            Version::V7CacheReuse => n.min(params.cols_per_stripe),
+            _ => n,
+        }
+    }
+
+    /// Amount of independent tasks in the main loop, which can be run in parallel
+    pub fn parallel_tasks(self, n: usize, params: &Params) -> usize {
+        let row_blocks = n.div_ceil(self.block_height(params));
+        match self {
+            // Pairs of row blocks of vd and vt are processed independently, in Z-order blocks
+            Version::V7CacheReuse => {
+                let pairs = row_blocks * row_blocks;
+                let pairs_per_task = params.z_block_size.min(pairs / (4 * tools::par::num_threads())).max(1);
+                pairs.div_ceil(pairs_per_task)
+            }
+            _ => row_blocks,
+        }
+    }
+
+    /// Compute r with the parameters tuned for this machine
+    pub fn step(self, r: &mut [f32], d: &[f32], n: usize) {
+        self.step_with_params(r, d, n, tools::params::get());
+    }
+
+    pub fn step_with_params(self, r: &mut [f32], d: &[f32], n: usize, params: &Params) {
+        match self {
+            Version::V0Baseline => v0_baseline::step(r, d, n),
+            Version::V1LinearReading => v1_linear_reading::step(r, d, n),
+            Version::V2InstrLevelParallelism => v2_instr_level_parallelism::step_with_params(r, d, n, params),
+            Version::V3Simd => v3_simd::step(r, d, n),
+            Version::V4RegisterReuse => v4_register_reuse::step_with_params(r, d, n, params),
+            Version::V5MoreRegisterReuse => v5_more_register_reuse::step(r, d, n),
+            Version::V6Prefetch => v6_prefetch::step_with_params(r, d, n, params),
+            Version::V7CacheReuse => v7_cache_reuse::step_with_params(r, d, n, params),
+            #[cfg(feature = "portable-simd")]
+            Version::V5PortableSimd => v5_portable_simd::step(r, d, n),
+        }
+    }
+
+    /// Fold the results of d into r, i.e. r = min(r, d ⊗ d), with the parameters tuned for this machine
+    pub fn step_accumulate(self, r: &mut [f32], d: &[f32], n: usize) {
+        self.step_accumulate_with_params(r, d, n, tools::params::get());
+    }
+
+    pub fn step_accumulate_with_params(self, r: &mut [f32], d: &[f32], n: usize, params: &Params) {
+        match self {
+            Version::V0Baseline => v0_baseline::step_accumulate(r, d, n),
+            Version::V1LinearReading => v1_linear_reading::step_accumulate(r, d, n),
+            Version::V2InstrLevelParallelism => v2_instr_level_parallelism::step_accumulate_with_params(r, d, n, params),
+            Version::V3Simd => v3_simd::step_accumulate(r, d, n),
+            Version::V4RegisterReuse => v4_register_reuse::step_accumulate_with_params(r, d, n, params),
+            Version::V5MoreRegisterReuse => v5_more_register_reuse::step_accumulate(r, d, n),
+            Version::V6Prefetch => v6_prefetch::step_accumulate_with_params(r, d, n, params),
+            Version::V7CacheReuse => v7_cache_reuse::step_accumulate_with_params(r, d, n, params),
+            // v5_portable_simd has only step, so its results are merged into r afterwards
+            #[cfg(feature = "portable-simd")]
+            Version::V5PortableSimd => {
+                let mut tmp = vec![0.0; n * n];
+                v5_portable_simd::step(&mut tmp, d, n);
+                r.iter_mut().zip(tmp).for_each(|(x, y)| *x = tools::min(*x, y));
+            }
+        }
+    }
+
+    /// Compute r from d stored in rd, overwriting d with the results, with the parameters tuned for this machine
+    pub fn step_in_place(self, rd: &mut [f32], n: usize) {
+        self.step_in_place_with_params(rd, n, tools::params::get());
+    }
+
+    /// Compute r from d stored in rd, overwriting d with the results. v3 to v7 pack all of d before
+    /// writing the first result, the other versions compute from a copy of d.
+    pub fn step_in_place_with_params(self, rd: &mut [f32], n: usize, params: &Params) {
+        match self {
+            Version::V0Baseline => v0_baseline::step_in_place(rd, n),
+            Version::V1LinearReading => v1_linear_reading::step_in_place(rd, n),
+            Version::V2InstrLevelParallelism => v2_instr_level_parallelism::step_in_place_with_params(rd, n, params),
+            Version::V3Simd => v3_simd::step_in_place(rd, n),
+            Version::V4RegisterReuse => v4_register_reuse::step_in_place_with_params(rd, n, params),
+            Version::V5MoreRegisterReuse => v5_more_register_reuse::step_in_place(rd, n),
+            Version::V6Prefetch => v6_prefetch::step_in_place_with_params(rd, n, params),
+            Version::V7CacheReuse => v7_cache_reuse::step_in_place_with_params(rd, n, params),
+            #[cfg(feature = "portable-simd")]
+            Version::V5PortableSimd => {
+                let d = rd.to_vec();
+                v5_portable_simd::step(rd, &d, n);
+            }
+        }
+    }
+
+    /// Compute r over the intermediate nodes allowed by filter, see tools::kfilter, with the parameters tuned for this machine
+    pub fn step_filtered(self, r: &mut [f32], d: &[f32], n: usize, filter: &KFilter) {
+        self.step_filtered_with_params(r, d, n, filter, tools::params::get());
+    }
+
+    /// Compute r over the intermediate nodes allowed by filter. v3 to v7 pack only the hubs into vd and vt,
+    /// v0 to v2 skip the excluded elements with a mask.
+    pub fn step_filtered_with_params(self, r: &mut [f32], d: &[f32], n: usize, filter: &KFilter, params: &Params) {
+        match self {
+            Version::V0Baseline => v0_baseline::step_filtered(r, d, n, filter),
+            Version::V1LinearReading => v1_linear_reading::step_filtered(r, d, n, filter),
+            Version::V2InstrLevelParallelism => v2_instr_level_parallelism::step_filtered_with_params(r, d, n, filter, params),
+            Version::V3Simd => v3_simd::step_filtered(r, d, n, filter),
+            Version::V4RegisterReuse => v4_register_reuse::step_filtered_with_params(r, d, n, filter, params),
+            Version::V5MoreRegisterReuse => v5_more_register_reuse::step_filtered(r, d, n, filter),
+            Version::V6Prefetch => v6_prefetch::step_filtered_with_params(r, d, n, filter, params),
+            Version::V7CacheReuse => v7_cache_reuse::step_filtered_with_params(r, d, n, filter, params),
+            // v5_portable_simd has only step, the filter is applied by v5_more_register_reuse with the same kernel
+            #[cfg(feature = "portable-simd")]
+            Version::V5PortableSimd => v5_more_register_reuse::step_filtered(r, d, n, filter),
+        }
+    }
+
+    /// Compute r with a weight w[k] for each vertex k, see tools::weights, with the parameters tuned for this machine
+    pub fn step_weighted(self, r: &mut [f32], d: &[f32], w: &[f32], n: usize) {
+        self.step_weighted_with_params(r, d, w, n, tools::params::get());
+    }
+
+    /// Compute r with a weight w[k] for each vertex k. v2 to v7 add the weights to vd while packing it,
+    /// v0 and v1 add them to each element of d they read from a row.
+    pub fn step_weighted_with_params(self, r: &mut [f32], d: &[f32], w: &[f32], n: usize, params: &Params) {
+        match self {
+            Version::V0Baseline => v0_baseline::step_weighted(r, d, w, n),
+            Version::V1LinearReading => v1_linear_reading::step_weighted(r, d, w, n),
+            Version::V2InstrLevelParallelism => v2_instr_level_parallelism::step_weighted_with_params(r, d, w, n, params),
+            Version::V3Simd => v3_simd::step_weighted(r, d, w, n),
+            Version::V4RegisterReuse => v4_register_reuse::step_weighted_with_params(r, d, w, n, params),
+            Version::V5MoreRegisterReuse => v5_more_register_reuse::step_weighted(r, d, w, n),
+            Version::V6Prefetch => v6_prefetch::step_weighted_with_params(r, d, w, n, params),
+            Version::V7CacheReuse => v7_cache_reuse::step_weighted_with_params(r, d, w, n, params),
+            // v5_portable_simd has only step, the weights are added by v5_more_register_reuse with the same kernel
+            #[cfg(feature = "portable-simd")]
+            Version::V5PortableSimd => v5_more_register_reuse::step_weighted(r, d, w, n),
+        }
+    }
+
+    /// Compute r for r and d stored with the given layouts, see tools::layout, with the parameters tuned for this machine
+    pub fn step_strided(self, r: &mut [f32], r_layout: Layout, d: &[f32], d_layout: Layout, n: usize) {
+        self.step_strided_with_params(r, r_layout, d, d_layout, n, tools::params::get());
+    }
+
+    /// Compute r for r and d stored with the given layouts. v3 to v7 read d through its layout while packing it
+    /// and write r with its row stride, the other versions compute on dense copies of d and r.
+    pub fn step_strided_with_params(self, r: &mut [f32], r_layout: Layout, d: &[f32], d_layout: Layout, n: usize, params: &Params) {
+        match self {
+            Version::V3Simd => v3_simd::step_strided(r, r_layout, d, d_layout, n),
+            Version::V4RegisterReuse => v4_register_reuse::step_strided_with_params(r, r_layout, d, d_layout, n, params),
+            Version::V5MoreRegisterReuse => v5_more_register_reuse::step_strided(r, r_layout, d, d_layout, n),
+            Version::V6Prefetch => v6_prefetch::step_strided_with_params(r, r_layout, d, d_layout, n, params),
+            Version::V7CacheReuse => v7_cache_reuse::step_strided_with_params(r, r_layout, d, d_layout, n, params),
+            _ => layout::step_dense(r, r_layout, d, d_layout, n, |r, d, n| self.step_with_params(r, d, n, params)),
+        }
+    }
+
+    /// Compute r for a symmetric d with the parameters tuned for this machine, see step_symmetric_with_params
+    pub fn step_symmetric(self, r: &mut [f32], d: &[f32], n: usize, check: bool) {
+        self.step_symmetric_with_params(r, d, n, tools::params::get(), check);
+    }
+
+    /// Compute r for a symmetric d. v5 and v7 compute only the results on and above the diagonal
+    /// and mirror them, the other versions compute all results as usual.
+    /// If check is true and d is not symmetric, all results are computed as usual.
+    pub fn step_symmetric_with_params(self, r: &mut [f32], d: &[f32], n: usize, params: &Params, check: bool) {
+        match self {
+            Version::V5MoreRegisterReuse if check => {
+                v5_more_register_reuse::step_symmetric_checked(r, d, n);
+            }
+            Version::V5MoreRegisterReuse => v5_more_register_reuse::step_symmetric(r, d, n),
+            Version::V7CacheReuse if check => {
+                v7_cache_reuse::step_symmetric_checked_with_params(r, d, n, params);
+            }
+            Version::V7CacheReuse => v7_cache_reuse::step_symmetric_with_params(r, d, n, params),
+            // All results are computed, so d needs no check
+            _ => self.step_with_params(r, d, n, params),
+        }
+    }
+}
+
+/// Instruction set extensions required by some of the versions
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CpuFeatures {
+    pub avx: bool,
+    pub avx2: bool,
+    pub bmi2: bool,
+}
+
+impl CpuFeatures {
+    /// Features supported by the CPU running this process
+    #[cfg(target_arch = "x86_64")]
+    pub fn detect() -> CpuFeatures {
+        CpuFeatures {
+            avx: is_x86_feature_detected!("avx"),
+            avx2: is_x86_feature_detected!("avx2"),
+            bmi2: is_x86_feature_detected!("bmi2"),
+        }
+    }
+
+    /// None of the features exist on other architectures
+    #[cfg(not(target_arch = "x86_64"))]
+    pub fn detect() -> CpuFeatures {
+        CpuFeatures::default()
+    }
+
+    /// Names of all supported features, separated by spaces
+    pub fn names(&self) -> String {
+        let names = [("avx", self.avx), ("avx2", self.avx2), ("bmi2", self.bmi2)];
+        let supported: Vec<&str> = names.iter().filter(|(_, s)| *s).map(|(name, _)| *name).collect();
+        if supported.is_empty() {
+            "none".to_string()
+        } else {
+            supported.join(" ")
+        }
+    }
+}
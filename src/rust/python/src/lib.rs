@@ -0,0 +1,102 @@
+//! Python extension module computing r of NumPy arrays with all step implementations.
+//!
+//! ```python
+//! import numpy as np
+//! import shortcut_comparison as sc
+//! d = np.random.rand(1000, 1000).astype(np.float32)
+//! r = sc.step(d)                    # version planned for n on this machine
+//! r = sc.step(d, version="v7")      # the same as sc.v7_cache_reuse.step(d)
+//! ```
+//!
+//! d must be a square, C-contiguous array of float32, which is read in place without copying.
+//! The GIL is released while computing, so other Python threads can run in the meantime.
+use numpy::{PyArray2, PyArrayMethods, PyReadonlyArray2, PyUntypedArrayMethods};
+use pyo3::exceptions::{PyRuntimeError, PyValueError};
+use pyo3::prelude::*;
+
+use planner::{CpuFeatures, Version};
+
+/// Compute r of d with the given version, or with the planned version if there is none
+fn step_array<'py>(py: Python<'py>, d: PyReadonlyArray2<'py, f32>, version: Option<Version>) -> PyResult<Bound<'py, PyArray2<f32>>> {
+    let n = match d.shape() {
+        &[rows, cols] if rows == cols => rows,
+        shape => return Err(PyValueError::new_err(format!("d must be a square matrix, got shape {:?}", shape))),
+    };
+    if !d.is_c_contiguous() {
+        return Err(PyValueError::new_err("d must be C-contiguous, see numpy.ascontiguousarray"));
+    }
+    if let Some(version) = version {
+        if !version.is_supported(&CpuFeatures::detect()) {
+            return Err(PyRuntimeError::new_err(format!("{} is not supported by this CPU, which has {}",
+                                                       version.name(), CpuFeatures::detect().names())));
+        }
+    }
+    let r = PyArray2::<f32>::zeros(py, [n, n], false);
+    {
+        let d = d.as_slice()?;
+        let mut r_rw = r.readwrite();
+        let r_slice = r_rw.as_slice_mut()?;
+        py.detach(|| match version {
+            Some(version) => version.step(r_slice, d, n),
+            None => planner::step(r_slice, d, n),
+        });
+    }
+    Ok(r)
+}
+
+/// One step implementation, available as module attribute with the name of its crate, e.g. v7_cache_reuse
+#[pyclass(name = "Version", frozen)]
+struct PyVersion {
+    version: Version,
+}
+
+#[pymethods]
+impl PyVersion {
+    /// Name of the crate implementing this version, e.g. "v7_cache_reuse"
+    #[getter]
+    fn name(&self) -> &'static str {
+        self.version.name()
+    }
+
+    /// True if this version can run on this CPU
+    #[getter]
+    fn supported(&self) -> bool {
+        self.version.is_supported(&CpuFeatures::detect())
+    }
+
+    /// Compute r[i][j] = min_k d[i][k] + d[k][j] of the square float32 array d into a new array
+    fn step<'py>(&self, py: Python<'py>, d: PyReadonlyArray2<'py, f32>) -> PyResult<Bound<'py, PyArray2<f32>>> {
+        step_array(py, d, Some(self.version))
+    }
+
+    fn __repr__(&self) -> String {
+        format!("<Version {}>", self.version.name())
+    }
+}
+
+/// Compute r[i][j] = min_k d[i][k] + d[k][j] of the square float32 array d into a new array,
+/// with the given version, e.g. "v7" or "v7_cache_reuse", or with the version planned for n
+#[pyfunction]
+#[pyo3(signature = (d, version = None))]
+fn step<'py>(py: Python<'py>, d: PyReadonlyArray2<'py, f32>, version: Option<&str>) -> PyResult<Bound<'py, PyArray2<f32>>> {
+    let version = match version {
+        Some(name) => Some(Version::from_name(name)
+            .ok_or_else(|| PyValueError::new_err(format!("unknown version '{}'", name)))?),
+        None => None,
+    };
+    step_array(py, d, version)
+}
+
+#[pymodule]
+fn shortcut_comparison(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_function(wrap_pyfunction!(step, m)?)?;
+    m.add_class::<PyVersion>()?;
+    let mut versions = Vec::new();
+    for &version in Version::ALL.iter() {
+        let v = Bound::new(m.py(), PyVersion { version })?;
+        m.add(version.name(), &v)?;
+        versions.push(v);
+    }
+    m.add("versions", versions)?;
+    Ok(())
+}
@@ -0,0 +1,102 @@
+//! Weighted, directed graphs read from DIMACS shortest path files or plain edge lists.
+use std::io::{self, BufRead, Write};
+
+/// Text formats of graphs
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GraphFormat {
+    /// Format of the 9th DIMACS implementation challenge, with a line 'p sp N M',
+    /// followed by M arcs 'a U V W' with vertices numbered from 1
+    Dimacs,
+    /// One edge 'U V W' per line, with vertices numbered from 0, and comments starting with '#' or '%'
+    EdgeList,
+}
+
+pub struct Graph {
+    /// Amount of vertices
+    pub n: usize,
+    /// (u, v, weight), with vertices numbered from 0
+    pub edges: Vec<(usize, usize, f32)>,
+}
+
+fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Parse the fields of an edge, `first_vertex` is the number of the first vertex in the file
+fn parse_edge(fields: &[&str], first_vertex: usize, line: &str) -> io::Result<(usize, usize, f32)> {
+    let invalid = || invalid_data(format!("invalid edge '{}'", line));
+    let vertex = |s: &str| match s.parse::<usize>() {
+        Ok(v) if v >= first_vertex => Ok(v - first_vertex),
+        _ => Err(invalid()),
+    };
+    match fields {
+        [u, v, w] => Ok((vertex(u)?, vertex(v)?, w.parse().map_err(|_| invalid())?)),
+        _ => Err(invalid()),
+    }
+}
+
+impl Graph {
+    pub fn read<R: BufRead>(reader: R, format: GraphFormat) -> io::Result<Graph> {
+        let mut n = None;
+        let mut edges = Vec::new();
+        for line in reader.lines() {
+            let line = line?;
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            match (format, &fields[..]) {
+                (_, []) => {}
+                (GraphFormat::Dimacs, ["c", ..]) => {}
+                (GraphFormat::Dimacs, ["p", "sp", vertices, _arcs]) => {
+                    n = Some(vertices.parse().map_err(|_| invalid_data(format!("invalid problem line '{}'", line)))?);
+                }
+                (GraphFormat::Dimacs, ["a", edge @ ..]) => edges.push(parse_edge(edge, 1, &line)?),
+                (GraphFormat::EdgeList, [first, ..]) if first.starts_with('#') || first.starts_with('%') => {}
+                (GraphFormat::EdgeList, edge) => edges.push(parse_edge(edge, 0, &line)?),
+                _ => return Err(invalid_data(format!("invalid line '{}'", line))),
+            }
+        }
+        let max_vertex = edges.iter().map(|&(u, v, _)| u.max(v) + 1).max().unwrap_or(0);
+        let n = match n {
+            Some(n) if max_vertex > n => {
+                return Err(invalid_data(format!("edge to vertex {} in a graph of {} vertices", max_vertex, n)));
+            }
+            Some(n) => n,
+            None if format == GraphFormat::Dimacs => return Err(invalid_data("no problem line 'p sp N M'".to_string())),
+            None => max_vertex,
+        };
+        Ok(Graph { n, edges })
+    }
+
+    /// Adjacency matrix, with the weight of the lightest edge from i to j or infinity at d[i][j],
+    /// and zeros on the diagonal, unless `zero_diagonal` is false
+    pub fn distance_matrix(&self, zero_diagonal: bool) -> Vec<f32> {
+        let n = self.n;
+        let mut d = vec![f32::INFINITY; n * n];
+        if zero_diagonal {
+            for i in 0..n {
+                d[n * i + i] = 0.0;
+            }
+        }
+        for &(u, v, w) in &self.edges {
+            d[n * u + v] = tools::min(d[n * u + v], w);
+        }
+        d
+    }
+
+    /// Write the graph in the given format, numbering vertices as when reading it
+    pub fn write<W: Write>(&self, mut writer: W, format: GraphFormat) -> io::Result<()> {
+        match format {
+            GraphFormat::Dimacs => {
+                writeln!(writer, "p sp {} {}", self.n, self.edges.len())?;
+                for &(u, v, w) in &self.edges {
+                    writeln!(writer, "a {} {} {}", u + 1, v + 1, w)?;
+                }
+            }
+            GraphFormat::EdgeList => {
+                for &(u, v, w) in &self.edges {
+                    writeln!(writer, "{} {} {}", u, v, w)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,132 @@
+//! Compute r for the distance matrix d of a weighted, directed graph, i.e. the shortest paths of at most 2 edges,
+//! and write it as a matrix file or as the list of edges that are improved by a path through another vertex.
+use std::env;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::process;
+
+use planner::{CpuFeatures, Version};
+use tools::io::{Format, Precision};
+
+mod graph;
+
+use graph::{Graph, GraphFormat};
+
+fn usage(program: &str) -> ! {
+    eprintln!("usage: {} GRAPH [--impl VERSION] [--output FILE [--format FORMAT] [--f64]] [--edges FILE]", program);
+    eprintln!("       [--edge-list] [--infinite-diagonal]");
+    eprintln!("  GRAPH                DIMACS shortest path file (.gr) or edge list 'U V W' with vertices numbered from 0");
+    eprintln!("  --impl               version computing r, e.g. v7 or v7_cache_reuse, default: planned for n");
+    eprintln!("  --output             write r as a matrix");
+    eprintln!("  --format             raw, npy, csv or matrix-market, default: from the extension of the output file");
+    eprintln!("  --f64                write elements of raw and npy files as f64");
+    eprintln!("  --edges              write the edges whose weight is lowered by a path of 2 edges, in the format of GRAPH,");
+    eprintln!("                       '-' for stdout, the default if no output file is given");
+    eprintln!("  --edge-list          read GRAPH as edge list regardless of its extension");
+    eprintln!("  --infinite-diagonal  no edges from vertices to themselves, r then contains only paths of exactly 2 edges");
+    process::exit(1);
+}
+
+/// Edges (i, j, r[i][j]) with r[i][j] < d[i][j], including new edges where d[i][j] is infinite
+fn improved_edges(r: &[f32], d: &[f32], n: usize) -> Graph {
+    let edges = r.iter()
+        .zip(d)
+        .enumerate()
+        .filter(|&(_, (x, y))| x < y)
+        .map(|(ij, (&x, _))| (ij / n, ij % n, x))
+        .collect();
+    Graph { n, edges }
+}
+
+fn create(path: &str) -> io::Result<Box<dyn Write>> {
+    Ok(match path {
+        "-" => Box::new(BufWriter::new(io::stdout())),
+        _ => Box::new(BufWriter::new(File::create(path)?)),
+    })
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    let mut graph_path = None;
+    let mut version = None;
+    let mut output = None;
+    let mut format = None;
+    let mut precision = Precision::F32;
+    let mut edges_path = None;
+    let mut graph_format = None;
+    let mut zero_diagonal = true;
+    let mut i = 1;
+    while i < args.len() {
+        match (args[i].as_str(), args.get(i + 1)) {
+            ("--impl", Some(v)) => {
+                version = Some(Version::from_name(v).unwrap_or_else(|| usage(&args[0])));
+                i += 1;
+            }
+            ("--output", Some(v)) => {
+                output = Some(v.clone());
+                i += 1;
+            }
+            ("--format", Some(v)) => {
+                format = Some(Format::from_name(v).unwrap_or_else(|| usage(&args[0])));
+                i += 1;
+            }
+            ("--edges", Some(v)) => {
+                edges_path = Some(v.clone());
+                i += 1;
+            }
+            ("--f64", _) => precision = Precision::F64,
+            ("--edge-list", _) => graph_format = Some(GraphFormat::EdgeList),
+            ("--infinite-diagonal", _) => zero_diagonal = false,
+            (arg, _) if !arg.starts_with("--") && graph_path.is_none() => graph_path = Some(arg.to_string()),
+            _ => usage(&args[0]),
+        }
+        i += 1;
+    }
+    let graph_path = graph_path.unwrap_or_else(|| usage(&args[0]));
+    let graph_format = graph_format.unwrap_or(match Path::new(&graph_path).extension() {
+        Some(ext) if ext == "gr" => GraphFormat::Dimacs,
+        _ => GraphFormat::EdgeList,
+    });
+    let format = match (&output, format) {
+        (Some(path), None) => Some(Format::from_path(path).unwrap_or_else(|| {
+            eprintln!("unknown matrix format of '{}', use --format", path);
+            process::exit(1);
+        })),
+        (_, format) => format,
+    };
+    if let Some(version) = version {
+        if !version.is_supported(&CpuFeatures::detect()) {
+            eprintln!("{} is not supported by this CPU, which has {}", version.name(), CpuFeatures::detect().names());
+            process::exit(1);
+        }
+    }
+    if let Err(e) = run(&graph_path, graph_format, zero_diagonal, version, output.as_deref().zip(format), precision,
+                        edges_path.as_deref().or(if output.is_none() { Some("-") } else { None })) {
+        eprintln!("error: {}", e);
+        process::exit(1);
+    }
+}
+
+fn run(graph_path: &str, graph_format: GraphFormat, zero_diagonal: bool, version: Option<Version>,
+       output: Option<(&str, Format)>, precision: Precision, edges_path: Option<&str>) -> io::Result<()> {
+    let graph = Graph::read(BufReader::new(File::open(graph_path)?), graph_format)?;
+    let n = graph.n;
+    let d = graph.distance_matrix(zero_diagonal);
+    let mut r = vec![0.0; n * n];
+    match version {
+        Some(version) => version.step(&mut r, &d, n),
+        None => planner::step(&mut r, &d, n),
+    }
+    if let Some((path, format)) = output {
+        let mut writer = BufWriter::new(File::create(path)?);
+        tools::io::write(&mut writer, &r, n, format, precision)?;
+        writer.flush()?;
+    }
+    if let Some(path) = edges_path {
+        let mut writer = create(path)?;
+        improved_edges(&r, &d, n).write(&mut writer, graph_format)?;
+        writer.flush()?;
+    }
+    Ok(())
+}
@@ -0,0 +1,298 @@
+//! Cache sizes of the CPU running this process.
+//!
+//! The sizes are read from CPUID leaf 4 (deterministic cache parameters) and, if that is not
+//! available, e.g. on AMD CPUs, from /sys/devices/system/cpu/cpu0/cache.
+//! Setting the environment variable SHORTCUT_CACHES, e.g. to 'l1=32K, l2=256K, l3=8M, line=64',
+//! overrides the detected sizes, which makes everything derived from them deterministic.
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::mem;
+use std::path::Path;
+use std::sync::OnceLock;
+
+use crate::simd::f32x8;
+
+/// Sizes in bytes of the data caches
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Caches {
+    /// Level 1 data cache of one core
+    pub l1: usize,
+    /// Level 2 cache, usually private to one core
+    pub l2: usize,
+    /// Level 3 cache, usually shared by all cores
+    pub l3: usize,
+    pub line_size: usize,
+}
+
+/// Used for the levels that could not be detected
+impl Default for Caches {
+    fn default() -> Caches {
+        Caches {
+            l1: 32 << 10,
+            l2: 256 << 10,
+            l3: 8 << 20,
+            line_size: 64,
+        }
+    }
+}
+
+fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Parse sizes such as '48K', '2048K' or '8M' as written by the kernel in /sys
+pub fn parse_size(s: &str) -> Option<usize> {
+    let s = s.trim();
+    let (digits, shift) = match s.as_bytes().last()? {
+        b'K' | b'k' => (&s[..s.len() - 1], 10),
+        b'M' | b'm' => (&s[..s.len() - 1], 20),
+        b'G' | b'g' => (&s[..s.len() - 1], 30),
+        _ => (s, 0),
+    };
+    digits.trim().parse::<usize>().ok().map(|size| size << shift)
+}
+
+impl Caches {
+    /// Record a cache of the given level, ignoring instruction caches and levels above 3
+    fn set(&mut self, level: u32, is_data: bool, size: usize, line_size: usize) {
+        if !is_data || size == 0 {
+            return;
+        }
+        match level {
+            1 => self.l1 = size,
+            2 => self.l2 = size,
+            3 => self.l3 = size,
+            _ => return,
+        }
+        if line_size > 0 {
+            self.line_size = line_size;
+        }
+    }
+
+    /// Caches reported by CPUID leaf 4, None if the CPU does not support it
+    #[cfg(target_arch = "x86_64")]
+    pub fn from_cpuid() -> Option<Caches> {
+        use core::arch::x86_64::{__cpuid, __cpuid_count};
+        if __cpuid(0).eax < 4 {
+            return None;
+        }
+        let mut caches = Caches::default();
+        let mut found = false;
+        for subleaf in 0.. {
+            let info = __cpuid_count(4, subleaf);
+            // 0: no more caches, 1: data, 2: instruction, 3: unified
+            let cache_type = info.eax & 0x1f;
+            if cache_type == 0 {
+                break;
+            }
+            let level = (info.eax >> 5) & 0x7;
+            let line_size = (info.ebx & 0xfff) as usize + 1;
+            let partitions = ((info.ebx >> 12) & 0x3ff) as usize + 1;
+            let ways = (info.ebx >> 22) as usize + 1;
+            let sets = info.ecx as usize + 1;
+            caches.set(level, cache_type != 2, ways * partitions * line_size * sets, line_size);
+            found = true;
+        }
+        if found { Some(caches) } else { None }
+    }
+
+    #[cfg(not(target_arch = "x86_64"))]
+    pub fn from_cpuid() -> Option<Caches> {
+        None
+    }
+
+    /// Caches described in a directory such as /sys/devices/system/cpu/cpu0/cache,
+    /// which contains one 'indexN' directory with the files level, type, size and
+    /// coherency_line_size for each cache
+    pub fn from_sysfs<P: AsRef<Path>>(dir: P) -> Option<Caches> {
+        let mut caches = Caches::default();
+        let mut found = false;
+        for entry in fs::read_dir(dir).ok()? {
+            let path = entry.ok()?.path();
+            if !path.file_name()?.to_str()?.starts_with("index") {
+                continue;
+            }
+            let read = |name: &str| fs::read_to_string(path.join(name)).ok();
+            let level = match read("level").and_then(|s| s.trim().parse().ok()) {
+                Some(level) => level,
+                None => continue,
+            };
+            let is_data = read("type").is_some_and(|s| s.trim() != "Instruction");
+            let size = read("size").and_then(|s| parse_size(&s)).unwrap_or(0);
+            let line_size = read("coherency_line_size").and_then(|s| s.trim().parse().ok()).unwrap_or(0);
+            caches.set(level, is_data, size, line_size);
+            found = true;
+        }
+        if found { Some(caches) } else { None }
+    }
+
+    /// Caches of the CPU running this process, or the defaults if nothing could be detected
+    pub fn detect() -> Caches {
+        // Miri supports neither CPUID nor, with isolation, reading files
+        if cfg!(miri) {
+            return Caches::default();
+        }
+        Caches::from_cpuid()
+            .or_else(|| Caches::from_sysfs("/sys/devices/system/cpu/cpu0/cache"))
+            .unwrap_or_default()
+    }
+
+    /// Parse comma separated 'name=size' pairs with the names l1, l2, l3 and line.
+    /// Sizes may have a K, M or G suffix. Missing sizes have their default values.
+    pub fn parse(s: &str) -> io::Result<Caches> {
+        let mut caches = Caches::default();
+        for item in s.split(',').map(str::trim).filter(|item| !item.is_empty()) {
+            let (name, value) = match item.split_once('=') {
+                Some((name, value)) => (name.trim(), value.trim()),
+                None => return Err(invalid_data(format!("expected 'name=size', got '{}'", item))),
+            };
+            let size = match parse_size(value) {
+                Some(size) if size > 0 => size,
+                _ => return Err(invalid_data(format!("invalid size for {}: '{}'", name, value))),
+            };
+            match name {
+                "l1" => caches.l1 = size,
+                "l2" => caches.l2 = size,
+                "l3" => caches.l3 = size,
+                "line" => caches.line_size = size,
+                _ => return Err(invalid_data(format!("unknown cache '{}'", name))),
+            }
+        }
+        Ok(caches)
+    }
+
+    /// Widest stripe, in columns, such that one row of vd and one row of vt of the stripe
+    /// fit together into the L1 cache.
+    /// The width is a multiple of the f32x8 vectors per cache line, so that rows start at line boundaries.
+    pub fn stripe_width(&self) -> usize {
+        let row_pair_bytes = 2 * mem::size_of::<f32x8>();
+        let vecs_per_line = (self.line_size / mem::size_of::<f32x8>()).max(1);
+        let width = self.l1 / row_pair_bytes;
+        (width - width % vecs_per_line).max(vecs_per_line)
+    }
+
+    /// Amount of (i, j) row pairs of a stripe computed by one task, if `threads` tasks run at the same time.
+    /// Pairs adjacent on the Z-order curve form square tiles of s×s pairs, which read s rows of vd
+    /// and s rows of vt. This is the largest such tile whose rows fit into the caches of the core running it,
+    /// i.e. into its L2 cache or into its share of the L3 cache, whichever is larger.
+    pub fn z_block_size(&self, stripe_width: usize, threads: usize) -> usize {
+        let row_bytes = stripe_width * mem::size_of::<f32x8>();
+        let cache_bytes = self.l2.max(self.l3 / threads.max(1));
+        let mut side = 1;
+        while 2 * (2 * side) * row_bytes <= cache_bytes {
+            side *= 2;
+        }
+        side * side
+    }
+}
+
+impl fmt::Display for Caches {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "l1={}K, l2={}K, l3={}K, line={}", self.l1 >> 10, self.l2 >> 10, self.l3 >> 10, self.line_size)
+    }
+}
+
+/// Caches given by SHORTCUT_CACHES, or detected if it is not set.
+/// Detection happens only once, at the first call.
+pub fn caches() -> &'static Caches {
+    static CACHES: OnceLock<Caches> = OnceLock::new();
+    CACHES.get_or_init(|| overridden(env::var("SHORTCUT_CACHES").ok().as_deref()).unwrap_or_else(Caches::detect))
+}
+
+/// Caches given by the value of SHORTCUT_CACHES, None if it is not set or invalid
+fn overridden(value: Option<&str>) -> Option<Caches> {
+    Caches::parse(value?)
+        .map_err(|e| eprintln!("warning: ignoring SHORTCUT_CACHES: {}", e))
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::params::Params;
+
+    fn caches(l1: usize, l2: usize, line_size: usize) -> Caches {
+        Caches { l1, l2, line_size, ..Caches::default() }
+    }
+
+    #[test]
+    fn parse_size_suffixes() {
+        assert_eq!(parse_size("64"), Some(64));
+        assert_eq!(parse_size("48K\n"), Some(48 << 10));
+        assert_eq!(parse_size("2048k"), Some(2048 << 10));
+        assert_eq!(parse_size(" 8M"), Some(8 << 20));
+        assert_eq!(parse_size("1G"), Some(1 << 30));
+        for s in ["", "K", "-1K", "1.5M", "8MB", "M8"] {
+            assert_eq!(parse_size(s), None, "{:?}", s);
+        }
+    }
+
+    #[test]
+    fn parse() {
+        let expected = Caches { l1: 48 << 10, l2: 2 << 20, l3: 30 << 20, line_size: 128 };
+        assert_eq!(Caches::parse("l1=48K, l2=2M, l3=30M, line=128").unwrap(), expected);
+        assert_eq!(Caches::parse(" line = 128 ,l3=30M,,l2=2048K, l1=49152 ").unwrap(), expected);
+        assert_eq!(Caches::parse(&expected.to_string()).unwrap(), expected);
+    }
+
+    #[test]
+    fn parse_keeps_defaults_of_missing_sizes() {
+        assert_eq!(Caches::parse("").unwrap(), Caches::default());
+        assert_eq!(Caches::parse("l2=1M").unwrap(), Caches { l2: 1 << 20, ..Caches::default() });
+    }
+
+    #[test]
+    fn parse_errors() {
+        for s in ["l1", "l1=", "l1=0", "l1=32X", "l4=32K", "L1=32K", "l1=32K; l2=256K"] {
+            let e = Caches::parse(s).unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidData, "{:?}", s);
+        }
+    }
+
+    #[test]
+    fn override_falls_back_to_detection() {
+        assert_eq!(overridden(None), None);
+        assert_eq!(overridden(Some("l1=32K, l2=tiny")), None);
+        assert_eq!(overridden(Some("l1=64K")), Some(Caches { l1: 64 << 10, ..Caches::default() }));
+    }
+
+    #[test]
+    fn stripe_width() {
+        // One row of vd and one of vt take 64 bytes per column
+        assert_eq!(caches(32 << 10, 0, 64).stripe_width(), 512);
+        assert_eq!(caches(48 << 10, 0, 64).stripe_width(), 768);
+        // Rounded down to whole cache lines of 2 or 4 vectors
+        assert_eq!(caches(200, 0, 64).stripe_width(), 2);
+        assert_eq!(caches(460, 0, 128).stripe_width(), 4);
+        // At least one cache line, even if the L1 cache is too small
+        assert_eq!(caches(1, 0, 64).stripe_width(), 2);
+        assert_eq!(caches(1, 0, 16).stripe_width(), 1);
+    }
+
+    #[test]
+    fn z_block_size() {
+        let caches = |l2: usize, l3: usize| Caches { l2, l3, ..Caches::default() };
+        // A tile of 8×8 pairs reads 16 rows of 16K
+        assert_eq!(caches(256 << 10, 0).z_block_size(512, 1), 64);
+        assert_eq!(caches((256 << 10) - 1, 0).z_block_size(512, 1), 16);
+        assert_eq!(caches(2 << 20, 0).z_block_size(512, 1), 4096);
+        // The share of the L3 cache of each of 8 threads fits a tile of 32×32 pairs
+        assert_eq!(caches(256 << 10, 8 << 20).z_block_size(512, 8), 1024);
+        assert_eq!(caches(256 << 10, 8 << 20).z_block_size(512, 0), 65536);
+        // The L2 cache is larger than the share of 64 threads
+        assert_eq!(caches(256 << 10, 8 << 20).z_block_size(512, 64), 64);
+        // At least one pair
+        assert_eq!(caches(0, 0).z_block_size(512, 1), 1);
+    }
+
+    #[test]
+    fn params_for_caches() {
+        let params = Params::for_caches(&Caches::parse("l1=32K, l2=256K, l3=256K").unwrap());
+        assert_eq!((params.cols_per_stripe, params.z_block_size), (512, 64));
+        let params = Params::for_caches(&Caches::parse("l1=48K, l2=2M, l3=2M, line=128").unwrap());
+        assert_eq!((params.cols_per_stripe, params.z_block_size), (768, 1024));
+        params.validate().unwrap();
+    }
+}
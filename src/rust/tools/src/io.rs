@@ -0,0 +1,447 @@
+//! Reading and writing n×n distance matrices in files.
+//!
+//! Supported formats:
+//!
+//! * raw: a 16 byte header, the bytes 'SCMX', the element size 4 or 8 and n as little-endian u32 and u64,
+//!   followed by the elements in row-major order as little-endian f32 or f64,
+//! * NumPy .npy files of shape (n, n) with little-endian float32 or float64 elements,
+//! * CSV with one row of comma separated elements per line, where infinities are written as 'inf',
+//! * Matrix Market coordinate format, where missing entries are f32::INFINITY, i.e. no edge.
+//!
+//! All readers return the elements as f32 in row-major order, together with n.
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+/// File formats of distance matrices
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    Raw,
+    Npy,
+    Csv,
+    MatrixMarket,
+}
+
+/// Type of the elements written into raw and .npy files
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Precision {
+    F32,
+    F64,
+}
+
+impl Precision {
+    fn size(self) -> usize {
+        match self {
+            Precision::F32 => 4,
+            Precision::F64 => 8,
+        }
+    }
+}
+
+impl Format {
+    /// Format given by the file extension, i.e. .bin or .raw, .npy, .csv or .mtx
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Option<Format> {
+        match path.as_ref().extension()?.to_str()?.to_ascii_lowercase().as_str() {
+            "bin" | "raw" => Some(Format::Raw),
+            "npy" => Some(Format::Npy),
+            "csv" => Some(Format::Csv),
+            "mtx" => Some(Format::MatrixMarket),
+            _ => None,
+        }
+    }
+
+    /// Format given by a name, which is either the name of the format or one of its file extensions
+    pub fn from_name(name: &str) -> Option<Format> {
+        match name.to_ascii_lowercase().as_str() {
+            "raw" | "bin" => Some(Format::Raw),
+            "npy" => Some(Format::Npy),
+            "csv" => Some(Format::Csv),
+            "mtx" | "matrix-market" => Some(Format::MatrixMarket),
+            _ => None,
+        }
+    }
+}
+
+fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Read a matrix from a file in the format given by its extension
+pub fn read_file<P: AsRef<Path>>(path: P) -> io::Result<(Vec<f32>, usize)> {
+    let format = Format::from_path(&path).ok_or_else(|| {
+        invalid_data(format!("unknown matrix format of '{}'", path.as_ref().display()))
+    })?;
+    read(BufReader::new(File::open(path)?), format)
+}
+
+/// Write a matrix into a file in the format given by its extension, with f32 elements
+pub fn write_file<P: AsRef<Path>>(path: P, d: &[f32], n: usize) -> io::Result<()> {
+    let format = Format::from_path(&path).ok_or_else(|| {
+        invalid_data(format!("unknown matrix format of '{}'", path.as_ref().display()))
+    })?;
+    let mut writer = BufWriter::new(File::create(path)?);
+    write(&mut writer, d, n, format, Precision::F32)?;
+    writer.flush()
+}
+
+pub fn read<R: BufRead>(reader: R, format: Format) -> io::Result<(Vec<f32>, usize)> {
+    match format {
+        Format::Raw => read_raw(reader),
+        Format::Npy => read_npy(reader),
+        Format::Csv => read_csv(reader),
+        Format::MatrixMarket => read_matrix_market(reader),
+    }
+}
+
+/// Write d in the given format, the precision applies only to raw and .npy files
+pub fn write<W: Write>(writer: W, d: &[f32], n: usize, format: Format, precision: Precision) -> io::Result<()> {
+    assert_eq!(d.len(), n * n, "d must contain n * n elements");
+    match format {
+        Format::Raw => write_raw(writer, d, n, precision),
+        Format::Npy => write_npy(writer, d, n, precision),
+        Format::Csv => write_csv(writer, d, n),
+        Format::MatrixMarket => write_matrix_market(writer, d, n),
+    }
+}
+
+const RAW_MAGIC: &[u8; 4] = b"SCMX";
+
+/// Amount of elements of an n×n matrix and their size in bytes, or an error if either overflows,
+/// since n is read from the header of a file
+fn matrix_size(n: usize, element_size: usize) -> io::Result<(usize, usize)> {
+    let too_large = || invalid_data(format!("matrix size {} is too large", n));
+    let len = n.checked_mul(n).ok_or_else(too_large)?;
+    let bytes = len.checked_mul(element_size).filter(|&b| b <= isize::MAX as usize).ok_or_else(too_large)?;
+    Ok((len, bytes))
+}
+
+/// Read n * n little-endian elements of the given size
+fn read_elements<R: Read>(reader: R, n: usize, precision: Precision) -> io::Result<Vec<f32>> {
+    let (len, byte_len) = matrix_size(n, precision.size())?;
+    let mut bytes = Vec::new();
+    reader.take(byte_len as u64).read_to_end(&mut bytes)?;
+    if bytes.len() != byte_len {
+        return Err(invalid_data(format!("expected {} elements, the file ends after {}", len, bytes.len() / precision.size())));
+    }
+    Ok(match precision {
+        Precision::F32 => bytes.chunks_exact(4).map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]])).collect(),
+        Precision::F64 => bytes.chunks_exact(8).map(|b| {
+            f64::from_le_bytes([b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7]]) as f32
+        }).collect(),
+    })
+}
+
+fn write_elements<W: Write>(mut writer: W, d: &[f32], precision: Precision) -> io::Result<()> {
+    let bytes: Vec<u8> = match precision {
+        Precision::F32 => d.iter().flat_map(|x| x.to_le_bytes()).collect(),
+        Precision::F64 => d.iter().flat_map(|&x| (x as f64).to_le_bytes()).collect(),
+    };
+    writer.write_all(&bytes)
+}
+
+pub fn read_raw<R: Read>(mut reader: R) -> io::Result<(Vec<f32>, usize)> {
+    let mut header = [0; 16];
+    reader.read_exact(&mut header)?;
+    if &header[..4] != RAW_MAGIC {
+        return Err(invalid_data("raw matrix does not start with 'SCMX'".to_string()));
+    }
+    let precision = match u32::from_le_bytes([header[4], header[5], header[6], header[7]]) {
+        4 => Precision::F32,
+        8 => Precision::F64,
+        size => return Err(invalid_data(format!("unsupported element size {}", size))),
+    };
+    let mut n_bytes = [0; 8];
+    n_bytes.copy_from_slice(&header[8..]);
+    let n = u64::from_le_bytes(n_bytes) as usize;
+    Ok((read_elements(reader, n, precision)?, n))
+}
+
+pub fn write_raw<W: Write>(mut writer: W, d: &[f32], n: usize, precision: Precision) -> io::Result<()> {
+    writer.write_all(RAW_MAGIC)?;
+    writer.write_all(&(precision.size() as u32).to_le_bytes())?;
+    writer.write_all(&(n as u64).to_le_bytes())?;
+    write_elements(writer, d, precision)
+}
+
+const NPY_MAGIC: &[u8; 6] = b"\x93NUMPY";
+
+/// Value of a key in the header of an .npy file, which is a Python dict literal such as
+/// "{'descr': '<f4', 'fortran_order': False, 'shape': (3, 3), }"
+fn npy_header_value<'a>(header: &'a str, key: &str) -> io::Result<&'a str> {
+    let missing = || invalid_data(format!("no '{}' in .npy header '{}'", key, header));
+    let start = header.find(&format!("'{}'", key)).ok_or_else(missing)? + key.len() + 2;
+    let value = header[start..].trim_start().strip_prefix(':').ok_or_else(missing)?.trim_start();
+    // The value ends at the comma or brace after it, except for tuples, which end at the closing parenthesis
+    let end = if value.starts_with('(') {
+        value.find(')').map(|i| i + 1)
+    } else {
+        value.find([',', '}'])
+    };
+    Ok(value[..end.ok_or_else(missing)?].trim())
+}
+
+pub fn read_npy<R: Read>(mut reader: R) -> io::Result<(Vec<f32>, usize)> {
+    let mut magic = [0; 8];
+    reader.read_exact(&mut magic)?;
+    if &magic[..6] != NPY_MAGIC {
+        return Err(invalid_data("not an .npy file".to_string()));
+    }
+    // Version 1 has a 2 byte header length, versions 2 and 3 a 4 byte length
+    let header_len = match magic[6] {
+        1 => {
+            let mut len = [0; 2];
+            reader.read_exact(&mut len)?;
+            u16::from_le_bytes(len) as usize
+        }
+        2 | 3 => {
+            let mut len = [0; 4];
+            reader.read_exact(&mut len)?;
+            u32::from_le_bytes(len) as usize
+        }
+        version => return Err(invalid_data(format!("unsupported .npy version {}", version))),
+    };
+    let mut header = vec![0; header_len];
+    reader.read_exact(&mut header)?;
+    let header = String::from_utf8(header).map_err(|_| invalid_data("invalid .npy header".to_string()))?;
+    let precision = match npy_header_value(&header, "descr")?.trim_matches(|c| c == '\'' || c == '"') {
+        "<f4" => Precision::F32,
+        "<f8" => Precision::F64,
+        descr => return Err(invalid_data(format!("unsupported .npy element type {}, expected '<f4' or '<f8'", descr))),
+    };
+    let fortran_order = match npy_header_value(&header, "fortran_order")? {
+        "False" => false,
+        "True" => true,
+        value => return Err(invalid_data(format!("invalid fortran_order {}", value))),
+    };
+    let shape = npy_header_value(&header, "shape")?;
+    let dims: Vec<usize> = shape
+        .trim_matches(|c| c == '(' || c == ')')
+        .split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(|s| s.parse().map_err(|_| invalid_data(format!("invalid .npy shape {}", shape))))
+        .collect::<io::Result<_>>()?;
+    let n = match dims[..] {
+        [rows, cols] if rows == cols => rows,
+        _ => return Err(invalid_data(format!("expected a square matrix, got shape {}", shape))),
+    };
+    let mut d = read_elements(reader, n, precision)?;
+    if fortran_order {
+        // Column-major, so the elements are those of the transpose
+        d = (0..n * n).map(|ij| d[n * (ij % n) + ij / n]).collect();
+    }
+    Ok((d, n))
+}
+
+pub fn write_npy<W: Write>(mut writer: W, d: &[f32], n: usize, precision: Precision) -> io::Result<()> {
+    let descr = match precision {
+        Precision::F32 => "<f4",
+        Precision::F64 => "<f8",
+    };
+    let mut header = format!("{{'descr': '{}', 'fortran_order': False, 'shape': ({}, {}), }}", descr, n, n);
+    // The header is padded with spaces and ends with a newline, such that the data starts at a multiple of 64 bytes
+    let unpadded = NPY_MAGIC.len() + 2 + 2 + header.len() + 1;
+    header.push_str(&" ".repeat(unpadded.next_multiple_of(64) - unpadded));
+    header.push('\n');
+    writer.write_all(NPY_MAGIC)?;
+    writer.write_all(&[1, 0])?;
+    writer.write_all(&(header.len() as u16).to_le_bytes())?;
+    writer.write_all(header.as_bytes())?;
+    write_elements(writer, d, precision)
+}
+
+/// Parse an element, accepting e.g. 'inf', 'Infinity' and '-inf' for infinities
+fn parse_element(s: &str) -> io::Result<f32> {
+    s.trim().parse().map_err(|_| invalid_data(format!("invalid element '{}'", s.trim())))
+}
+
+pub fn read_csv<R: BufRead>(reader: R) -> io::Result<(Vec<f32>, usize)> {
+    let mut d = Vec::new();
+    let mut rows = 0;
+    for line in reader.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let row: Vec<f32> = line.split(',').map(parse_element).collect::<io::Result<_>>()?;
+        if rows > 0 && row.len() * rows != d.len() {
+            return Err(invalid_data(format!("row {} has {} elements, expected {}", rows, row.len(), d.len() / rows)));
+        }
+        d.extend(row);
+        rows += 1;
+    }
+    if d.len() != rows * rows {
+        return Err(invalid_data(format!("expected a square matrix, got {} rows of {} elements", rows, d.len() / rows.max(1))));
+    }
+    Ok((d, rows))
+}
+
+/// Elements are written in the shortest form that reads back as the same f32, infinities as 'inf'
+pub fn write_csv<W: Write>(mut writer: W, d: &[f32], n: usize) -> io::Result<()> {
+    for row in d.chunks_exact(n.max(1)) {
+        let line: Vec<String> = row.iter().map(|x| x.to_string()).collect();
+        writeln!(writer, "{}", line.join(","))?;
+    }
+    Ok(())
+}
+
+/// Read a Matrix Market file in coordinate format, with real or integer values and general or symmetric structure.
+/// Entries missing from the file are f32::INFINITY, and entries given several times have the smallest value.
+pub fn read_matrix_market<R: BufRead>(reader: R) -> io::Result<(Vec<f32>, usize)> {
+    let mut lines = reader.lines();
+    let banner = lines.next().ok_or_else(|| invalid_data("empty Matrix Market file".to_string()))??;
+    let banner: Vec<String> = banner.split_whitespace().map(str::to_ascii_lowercase).collect();
+    let symmetric = match banner.iter().map(String::as_str).collect::<Vec<&str>>()[..] {
+        ["%%matrixmarket", "matrix", "coordinate", "real" | "integer", "general"] => false,
+        ["%%matrixmarket", "matrix", "coordinate", "real" | "integer", "symmetric"] => true,
+        _ => return Err(invalid_data(format!("unsupported Matrix Market header '{}'", banner.join(" ")))),
+    };
+    let mut d = Vec::new();
+    let mut n = None;
+    for line in lines {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('%') {
+            continue;
+        }
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let index = |s: &str| s.parse::<usize>().map_err(|_| invalid_data(format!("invalid index in '{}'", line)));
+        match (n, &fields[..]) {
+            // The first line after the comments has the size and the amount of entries
+            (None, [rows, cols, _entries]) => {
+                let (rows, cols) = (index(rows)?, index(cols)?);
+                if rows != cols {
+                    return Err(invalid_data(format!("expected a square matrix, got {}×{}", rows, cols)));
+                }
+                // The size is checked before allocating, so that a wrong header is an error instead of an abort
+                let (len, _) = matrix_size(rows, std::mem::size_of::<f32>())?;
+                d.try_reserve_exact(len).map_err(|_| invalid_data(format!("matrix size {} is too large", rows)))?;
+                d.resize(len, f32::INFINITY);
+                n = Some(rows);
+            }
+            (Some(n), [i, j, value]) => {
+                let (i, j, value) = (index(i)?, index(j)?, parse_element(value)?);
+                if i == 0 || j == 0 || i > n || j > n {
+                    return Err(invalid_data(format!("entry ({}, {}) is outside of the {}×{} matrix", i, j, n, n)));
+                }
+                let (i, j) = (i - 1, j - 1);
+                d[n * i + j] = crate::min(d[n * i + j], value);
+                if symmetric {
+                    d[n * j + i] = crate::min(d[n * j + i], value);
+                }
+            }
+            _ => return Err(invalid_data(format!("invalid line '{}'", line))),
+        }
+    }
+    let n = n.ok_or_else(|| invalid_data("Matrix Market file has no size line".to_string()))?;
+    Ok((d, n))
+}
+
+/// Write all elements except infinities as entries of a Matrix Market file in coordinate format
+pub fn write_matrix_market<W: Write>(mut writer: W, d: &[f32], n: usize) -> io::Result<()> {
+    let entries = d.iter().filter(|&&x| x != f32::INFINITY).count();
+    writeln!(writer, "%%MatrixMarket matrix coordinate real general")?;
+    writeln!(writer, "{} {} {}", n, n, entries)?;
+    for (ij, &x) in d.iter().enumerate().filter(|(_, &x)| x != f32::INFINITY) {
+        writeln!(writer, "{} {} {}", ij / n + 1, ij % n + 1, x)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FORMATS: [Format; 4] = [Format::Raw, Format::Npy, Format::Csv, Format::MatrixMarket];
+    const PRECISIONS: [Precision; 2] = [Precision::F32, Precision::F64];
+
+    /// An n×n matrix with fractions, negative elements and infinities, which are missing entries in Matrix Market files
+    fn matrix(n: usize) -> Vec<f32> {
+        (0..n * n).map(|ij| match ij % 5 {
+            0 => f32::INFINITY,
+            1 => -(ij as f32),
+            _ => 0.1 * ij as f32,
+        }).collect()
+    }
+
+    fn write_to_vec(d: &[f32], n: usize, format: Format, precision: Precision) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        write(&mut bytes, d, n, format, precision).unwrap();
+        bytes
+    }
+
+    fn read_err(bytes: &[u8], format: Format) -> io::ErrorKind {
+        read(bytes, format).expect_err("reading should fail").kind()
+    }
+
+    /// Header of a raw file with the given element size and n
+    fn raw_header(size: u32, n: u64) -> Vec<u8> {
+        [&RAW_MAGIC[..], &size.to_le_bytes(), &n.to_le_bytes()].concat()
+    }
+
+    #[test]
+    fn round_trip() {
+        for format in FORMATS {
+            for precision in PRECISIONS {
+                for n in [0, 1, 2, 7, 16] {
+                    let d = matrix(n);
+                    let bytes = write_to_vec(&d, n, format, precision);
+                    let (read_d, read_n) = read(&bytes[..], format).unwrap();
+                    assert_eq!(read_n, n, "{:?} {:?}", format, precision);
+                    let bits = |v: &[f32]| v.iter().map(|x| x.to_bits()).collect::<Vec<u32>>();
+                    assert_eq!(bits(&read_d), bits(&d), "{:?} {:?} n = {}", format, precision, n);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn npy_fortran_order() {
+        let header = "{'descr': '<f4', 'fortran_order': True, 'shape': (2, 2), }";
+        let mut bytes = [&NPY_MAGIC[..], &[1, 0], &(header.len() as u16).to_le_bytes(), header.as_bytes()].concat();
+        for x in [1.0f32, 2.0, 3.0, 4.0] {
+            bytes.extend(x.to_le_bytes());
+        }
+        assert_eq!(read(&bytes[..], Format::Npy).unwrap(), (vec![1.0, 3.0, 2.0, 4.0], 2));
+    }
+
+    #[test]
+    fn truncated() {
+        let d = matrix(4);
+        for format in [Format::Raw, Format::Npy] {
+            for precision in PRECISIONS {
+                let bytes = write_to_vec(&d, 4, format, precision);
+                // Within the elements and within the header
+                assert_eq!(read_err(&bytes[..bytes.len() - 1], format), io::ErrorKind::InvalidData);
+                assert_eq!(read_err(&bytes[..10], format), io::ErrorKind::UnexpectedEof);
+            }
+        }
+        assert_eq!(read_err(b"1,2\n3\n", Format::Csv), io::ErrorKind::InvalidData);
+        assert_eq!(read_err(b"1,2\n3,4\n5,6\n", Format::Csv), io::ErrorKind::InvalidData);
+        assert_eq!(read_err(b"%%MatrixMarket matrix coordinate real general\n% no size\n", Format::MatrixMarket), io::ErrorKind::InvalidData);
+        assert_eq!(read_err(b"", Format::MatrixMarket), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn bad_headers() {
+        assert_eq!(read_err(&[b"XXXX".to_vec(), raw_header(4, 1)[4..].to_vec()].concat(), Format::Raw), io::ErrorKind::InvalidData);
+        assert_eq!(read_err(&raw_header(3, 1), Format::Raw), io::ErrorKind::InvalidData);
+        // The byte count of 2^31 × 2^31 f32 elements wraps to 0 in 64 bits, n × n overflows for 2^32
+        assert_eq!(read_err(&raw_header(4, 1 << 31), Format::Raw), io::ErrorKind::InvalidData);
+        assert_eq!(read_err(&raw_header(8, 1 << 32), Format::Raw), io::ErrorKind::InvalidData);
+        assert_eq!(read_err(&raw_header(8, u64::MAX), Format::Raw), io::ErrorKind::InvalidData);
+        let npy = |header: &str| [&NPY_MAGIC[..], &[1, 0], &(header.len() as u16).to_le_bytes(), header.as_bytes()].concat();
+        assert_eq!(read_err(&npy("{'descr': '<i4', 'fortran_order': False, 'shape': (1, 1), }"), Format::Npy), io::ErrorKind::InvalidData);
+        assert_eq!(read_err(&npy("{'descr': '<f4', 'fortran_order': False, 'shape': (2, 3), }"), Format::Npy), io::ErrorKind::InvalidData);
+        assert_eq!(read_err(&npy("{'descr': '<f4', 'shape': (1, 1), }"), Format::Npy), io::ErrorKind::InvalidData);
+        assert_eq!(read_err(&npy("{'descr': '<f4', 'fortran_order': False, 'shape': (4294967296, 4294967296), }"), Format::Npy), io::ErrorKind::InvalidData);
+        assert_eq!(read_err(b"1,x\n3,4\n", Format::Csv), io::ErrorKind::InvalidData);
+        let mtx = |lines: &str| format!("%%MatrixMarket matrix coordinate real general\n{}", lines).into_bytes();
+        assert_eq!(read_err(b"%%MatrixMarket matrix array real general\n1 1\n0\n", Format::MatrixMarket), io::ErrorKind::InvalidData);
+        assert_eq!(read_err(&mtx("2 3 0\n"), Format::MatrixMarket), io::ErrorKind::InvalidData);
+        assert_eq!(read_err(&mtx("2 2 1\n3 1 1.0\n"), Format::MatrixMarket), io::ErrorKind::InvalidData);
+        // Sizes whose elements overflow or cannot be allocated are rejected before allocating
+        assert_eq!(read_err(&mtx("4294967296 4294967296 0\n"), Format::MatrixMarket), io::ErrorKind::InvalidData);
+        assert_eq!(read_err(&mtx("1073741824 1073741824 0\n"), Format::MatrixMarket), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,67 @@
+//! Register blocked micro-kernel for the min-plus product of row blocks.
+//!
+//! A tile of HEIGHT × WIDTH accumulators combines HEIGHT rows of vd with WIDTH rows of vt.
+//! Each column is loaded only once for HEIGHT × WIDTH results, so larger tiles need fewer loads
+//! per result, until the accumulators no longer fit into the 16 vector registers.
+//! HEIGHT and WIDTH are compile time constants, so the compiler can unroll all loops over the tile.
+use crate::simd::{self, f32x8};
+
+/// Vector types that can be accumulated in a tile
+pub trait MinPlus: Copy {
+    /// Initial value of all accumulators
+    fn infinity() -> Self;
+    /// Element-wise min(acc, x + y)
+    fn min_plus(acc: Self, x: Self, y: Self) -> Self;
+}
+
+impl MinPlus for f32x8 {
+    #[inline(always)]
+    fn infinity() -> f32x8 {
+        simd::f32x8_infty()
+    }
+
+    #[inline(always)]
+    fn min_plus(acc: f32x8, x: f32x8, y: f32x8) -> f32x8 {
+        simd::min(acc, simd::add(x, y))
+    }
+}
+
+/// Split a row block into its first `N` rows of length `row_len`
+#[inline(always)]
+pub fn rows<T, const N: usize>(row_block: &[T], row_len: usize) -> [&[T]; N] {
+    std::array::from_fn(|b| &row_block[b * row_len..(b + 1) * row_len])
+}
+
+/// Accumulate `tile[a][b] = min(tile[a][b], min_k vd_rows[a][k] + vt_rows[b][k])`
+/// over all columns k of the rows, which must all have the same length
+#[inline(always)]
+pub fn accumulate_tile<T: MinPlus, const HEIGHT: usize, const WIDTH: usize>(
+    tile: &mut [[T; WIDTH]; HEIGHT],
+    vd_rows: &[&[T]; HEIGHT],
+    vt_rows: &[&[T]; WIDTH],
+) {
+    let cols = if HEIGHT > 0 { vd_rows[0].len() } else { 0 };
+    debug_assert!(vd_rows.iter().chain(vt_rows.iter()).all(|row| row.len() == cols));
+    // Move horizontally, loading two 'vertical stripes' of HEIGHT and WIDTH elements at each column
+    for col in 0..cols {
+        let ds: [T; HEIGHT] = std::array::from_fn(|a| vd_rows[a][col]);
+        let ts: [T; WIDTH] = std::array::from_fn(|b| vt_rows[b][col]);
+        // Combine all pairs of elements from the HEIGHT + WIDTH rows
+        for (tile_row, &d0) in tile.iter_mut().zip(&ds) {
+            for (acc, &t0) in tile_row.iter_mut().zip(&ts) {
+                *acc = T::min_plus(*acc, d0, t0);
+            }
+        }
+    }
+}
+
+/// Min-plus products of all pairs of HEIGHT rows of vd and WIDTH rows of vt
+#[inline(always)]
+pub fn min_plus_tile<T: MinPlus, const HEIGHT: usize, const WIDTH: usize>(
+    vd_rows: &[&[T]; HEIGHT],
+    vt_rows: &[&[T]; WIDTH],
+) -> [[T; WIDTH]; HEIGHT] {
+    let mut tile = [[T::infinity(); WIDTH]; HEIGHT];
+    accumulate_tile(&mut tile, vd_rows, vt_rows);
+    tile
+}
@@ -0,0 +1,122 @@
+//! Restricting the intermediate nodes k in r[i][j] = min_k d[i][k] + d[k][j].
+//!
+//! With a set of hubs, e.g. for transit-node routing, only the hubs are intermediate nodes,
+//! and excluding the endpoints skips k = i and k = j, e.g. for paths with exactly two edges.
+//!
+//! The SIMD versions apply the filter while packing d: the rows of vd and vt get one element for each hub only,
+//! so they are shorter and the kernels are unchanged. The path through k = i is d[i][i] + d[i][j],
+//! so it is excluded by packing d[i][i] as f32::INFINITY into row i of vd, and the path through k = j
+//! by packing d[j][j] as f32::INFINITY into row j of vt.
+//! The scalar versions skip the same elements with the mask of all hubs.
+use crate::layout::Layout;
+use crate::simd::{self, f32x8};
+
+/// Intermediate nodes k over which the results are minimized
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct KFilter {
+    /// Only these k, which must be less than n, or all k if None
+    pub hubs: Option<Vec<usize>>,
+    /// Skip k = i and k = j in r[i][j]
+    pub exclude_endpoints: bool,
+}
+
+impl KFilter {
+    /// All k, as in step
+    pub const ALL: KFilter = KFilter { hubs: None, exclude_endpoints: false };
+
+    /// Only the given k
+    pub fn hubs(hubs: Vec<usize>) -> KFilter {
+        KFilter { hubs: Some(hubs), exclude_endpoints: false }
+    }
+
+    /// All k except i and j
+    pub fn exclude_endpoints() -> KFilter {
+        KFilter { hubs: None, exclude_endpoints: true }
+    }
+
+    /// True if all k are intermediate nodes
+    pub fn is_all(&self) -> bool {
+        self.hubs.is_none() && !self.exclude_endpoints
+    }
+
+    /// Panic if some hub is not a node of an n×n matrix
+    pub fn check(&self, n: usize) {
+        if let Some(hubs) = &self.hubs {
+            assert!(hubs.iter().all(|&k| k < n), "hubs must be less than n = {}", n);
+        }
+    }
+
+    /// Amount of intermediate nodes, i.e. the length of the packed rows of d
+    pub fn len(&self, n: usize) -> usize {
+        self.hubs.as_ref().map_or(n, Vec::len)
+    }
+
+    /// Intermediate node at index c of the packed rows
+    #[inline]
+    pub fn k(&self, c: usize) -> usize {
+        self.hubs.as_ref().map_or(c, |hubs| hubs[c])
+    }
+
+    /// For each k in 0..n, true if k is a hub, for the versions looping over all k
+    pub fn mask(&self, n: usize) -> Vec<bool> {
+        match &self.hubs {
+            Some(hubs) => {
+                let mut mask = vec![false; n];
+                for &k in hubs {
+                    mask[k] = true;
+                }
+                mask
+            }
+            None => vec![true; n],
+        }
+    }
+
+    /// True if d[i][k], in row i of d or column i if transposed, is on no path through an intermediate node,
+    /// with the mask of this filter
+    #[inline]
+    pub fn excludes(&self, mask: &[bool], i: usize, k: usize) -> bool {
+        !mask[k] || (self.exclude_endpoints && k == i)
+    }
+
+    /// Element c of the packed row i of the n×n matrix d stored with the given layout
+    #[inline]
+    fn element(&self, d: &[f32], layout: Layout, i: usize, c: usize) -> f32 {
+        let k = self.k(c);
+        if self.exclude_endpoints && k == i { f32::INFINITY } else { d[layout.index(i, k)] }
+    }
+}
+
+/// Like simd::pack_columns_strided, but for the packed rows of d, i.e. out[c] gets element col + c
+/// of the packed rows row..row + 8, and col_end is at most filter.len(n).
+/// Use d_layout.transposed() for packing columns of d.
+#[allow(clippy::too_many_arguments)]
+pub fn pack_columns(d: &[f32], layout: Layout, n: usize, filter: &KFilter, row: usize, col: usize, col_end: usize, out: &mut [f32x8]) {
+    if filter.is_all() {
+        return simd::pack_columns_strided(d, layout, n, row, col, col_end, out);
+    }
+    for (c, v) in (col..).zip(out.iter_mut()) {
+        let column: [f32; simd::f32x8_LENGTH] = std::array::from_fn(|b| {
+            if row + b < n && c < col_end { filter.element(d, layout, row + b, c) } else { f32::INFINITY }
+        });
+        *v = simd::from_slice(&column);
+    }
+}
+
+/// Like simd::pack_rows_strided, but for the packed rows of d,
+/// i.e. row b of out gets vector jv with the elements 8 * jv.. of packed row row + b.
+/// vecs_per_row must be filter.len(n).div_ceil(8).
+pub fn pack_rows(d: &[f32], layout: Layout, n: usize, filter: &KFilter, row: usize, out: &mut [f32x8], vecs_per_row: usize) {
+    if filter.is_all() {
+        return simd::pack_rows_strided(d, layout, n, row, out, vecs_per_row);
+    }
+    let len = filter.len(n);
+    for (i, out_row) in (row..).zip(out.chunks_exact_mut(vecs_per_row)) {
+        for (jv, v) in out_row.iter_mut().enumerate() {
+            let elements: [f32; simd::f32x8_LENGTH] = std::array::from_fn(|b| {
+                let c = jv * simd::f32x8_LENGTH + b;
+                if i < n && c < len { filter.element(d, layout, i, c) } else { f32::INFINITY }
+            });
+            *v = simd::from_slice(&elements);
+        }
+    }
+}
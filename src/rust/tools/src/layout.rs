@@ -0,0 +1,83 @@
+//! Storage of an n×n matrix inside a possibly larger buffer, like the leading dimension arguments of BLAS.
+//!
+//! Element (i, j) of a row-major matrix is at index ld * i + j, and of a column-major one at index ld * j + i,
+//! so a matrix can be a block of a larger matrix with ld columns (or rows, if column-major) without copying it out.
+//! A column-major result needs no special handling, since r transposed is the result of d transposed:
+//! r[j][i] = min_k d[j][k] + d[k][i] = min_k dᵀ[i][k] + dᵀ[k][j].
+//! Computing a row-major r from a column-major d, or the other way around, therefore only swaps
+//! which of the packed matrices vd and vt is read from rows of the buffer and which from columns.
+//! The SIMD versions read d through its layout only while packing it and write r with its row stride,
+//! so the kernels are the same as for dense matrices.
+
+/// Storage order and leading dimension of an n×n matrix
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Layout {
+    /// Distance between the first elements of adjacent rows, or columns if col_major is true, at least n
+    pub ld: usize,
+    pub col_major: bool,
+}
+
+impl Layout {
+    /// The layout used by all step functions without layout arguments, densely packed rows
+    pub fn dense(n: usize) -> Layout {
+        Layout { ld: n, col_major: false }
+    }
+
+    pub fn row_major(ld: usize) -> Layout {
+        Layout { ld, col_major: false }
+    }
+
+    pub fn col_major(ld: usize) -> Layout {
+        Layout { ld, col_major: true }
+    }
+
+    /// The same buffer viewed as storage of the transposed matrix
+    pub fn transposed(self) -> Layout {
+        Layout { ld: self.ld, col_major: !self.col_major }
+    }
+
+    /// Index of element (i, j) in the buffer
+    #[inline]
+    pub fn index(self, i: usize, j: usize) -> usize {
+        if self.col_major { self.ld * j + i } else { self.ld * i + j }
+    }
+
+    /// Minimum length of a buffer containing an n×n matrix with this layout,
+    /// the last row (or column) may end after n elements
+    pub fn len(self, n: usize) -> usize {
+        if n == 0 { 0 } else { self.ld * (n - 1) + n }
+    }
+
+    /// Panic if ld is smaller than n or the buffer is too short
+    pub fn check(self, n: usize, buffer_len: usize, name: &str) {
+        assert!(self.ld >= n, "leading dimension {} of {} is smaller than n = {}", self.ld, name, n);
+        assert!(buffer_len >= self.len(n), "{} has {} elements, but {} are needed for n = {} with leading dimension {}",
+                name, buffer_len, self.len(n), n, self.ld);
+    }
+}
+
+/// Copy the n×n matrix d with the given layout into a new dense row-major matrix
+pub fn gather(d: &[f32], layout: Layout, n: usize) -> Vec<f32> {
+    (0..n * n).map(|ij| d[layout.index(ij / n, ij % n)]).collect()
+}
+
+/// Copy the dense row-major n×n matrix src into r with the given layout, leaving other elements of r unchanged
+pub fn scatter(src: &[f32], r: &mut [f32], layout: Layout, n: usize) {
+    for (ij, &x) in src.iter().enumerate().take(n * n) {
+        r[layout.index(ij / n, ij % n)] = x;
+    }
+}
+
+/// Compute r with a step function for dense row-major matrices, copying d and r between the layouts.
+/// For versions which do not read d through the layout while packing it.
+pub fn step_dense<F>(r: &mut [f32], r_layout: Layout, d: &[f32], d_layout: Layout, n: usize, step: F)
+where
+    F: FnOnce(&mut [f32], &[f32], usize),
+{
+    r_layout.check(n, r.len(), "r");
+    d_layout.check(n, d.len(), "d");
+    let d_dense = gather(d, d_layout, n);
+    let mut r_dense = vec![0.0; n * n];
+    step(&mut r_dense, &d_dense, n);
+    scatter(&r_dense, r, r_layout, n);
+}
@@ -0,0 +1,36 @@
+//! Two criteria, e.g. travel time and monetary cost, minimized in lexicographic order.
+//!
+//! Each element of d and r is a pair (distance, cost), stored as two n×n matrices of the same layout.
+//! The pairs of a path are added element-wise, and a pair is smaller than another one if its distance is,
+//! or if both distances are equal and its cost is smaller, so the cost only breaks ties of the distance.
+//! The SIMD versions keep the distances and the costs in separate f32x8 vectors, whose lanes are paired,
+//! and select the costs with blends on the compare masks of the distances, see min_pairs.
+//! Minimizing is associative and commutative, so the scalar and SIMD versions have identical results.
+use crate::simd::{self, f32x8};
+
+/// Panic if the distances and costs of d are not both n×n matrices
+pub fn check(d_dist: &[f32], d_cost: &[f32], n: usize) {
+    assert_eq!(d_dist.len(), n * n, "d_dist must contain the n×n distances");
+    assert_eq!(d_cost.len(), n * n, "d_cost must contain the n×n costs");
+}
+
+/// The lexicographically smaller pair of (dist, cost) and (new_dist, new_cost), for the scalar versions
+#[inline]
+pub fn min_pair((dist, cost): (f32, f32), (new_dist, new_cost): (f32, f32)) -> (f32, f32) {
+    if new_dist < dist {
+        (new_dist, new_cost)
+    } else if new_dist == dist {
+        (dist, cost.min(new_cost))
+    } else {
+        (dist, cost)
+    }
+}
+
+/// The lexicographically smaller pair of each lane of (dist, cost) and (new_dist, new_cost).
+/// The cost is the minimum of both costs where the distances are equal, else the cost of the smaller distance.
+#[inline]
+pub fn min_pairs((dist, cost): (f32x8, f32x8), (new_dist, new_cost): (f32x8, f32x8)) -> (f32x8, f32x8) {
+    let tied = simd::blendv(cost, simd::min(cost, new_cost), simd::cmp_eq(new_dist, dist));
+    let cost = simd::blendv(tied, new_cost, simd::cmp_lt(new_dist, dist));
+    (simd::min(dist, new_dist), cost)
+}
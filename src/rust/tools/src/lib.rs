@@ -1,30 +1,128 @@
-#![feature(core_intrinsics)]
-extern crate core;
 // For interleaving bits to construct Z-order curve
+#[cfg(not(any(feature = "emulated-simd", not(target_arch = "x86_64"))))]
 use core::arch::x86_64::_pdep_u32;
 
+pub mod cpu;
+pub mod io;
+pub mod kernel;
+pub mod kfilter;
+pub mod layout;
+pub mod lexicographic;
+pub mod par;
+pub mod params;
 pub mod simd;
 pub mod timer;
+pub mod weights;
 
 /// Extern C-ABI wrapper for moving data by raw pointers to a Rust 'step'-implementation
+/// The wrapper is only defined if the calling crate enables its 'c-abi' feature,
+/// since linking several crates exporting the same symbol into one binary would fail
 #[macro_export]
 macro_rules! create_extern_c_wrapper {
     ($extern_func:ident, $wrapped_func:ident) => {
-        #[no_mangle]
-        pub extern "C" fn $extern_func(r_raw: *mut f32, d_raw: *const f32, n: i32) {
-            // Catch any unwinding panics so that they won't propagate over the ABI to the calling program, which would be undefined behaviour
-            let result = std::panic::catch_unwind(|| {
-                // Wrap raw pointers into 'not unsafe' Rust slices with a well defined size
-                let d = unsafe { std::slice::from_raw_parts(d_raw, (n * n) as usize) };
-                let mut r = unsafe { std::slice::from_raw_parts_mut(r_raw, (n * n) as usize) };
-                // Evaluate the wrapped function
-                $wrapped_func(&mut r, d, n as usize);
-            });
-            // Print an error to stderr if something went horribly wrong
-            if result.is_err() {
-                eprintln!("error: rust panicked");
+        // Defined in an anonymous const so that the exported symbol can have the same name as the wrapped function
+        #[cfg(feature = "c-abi")]
+        const _: () = {
+            /// # Safety
+            /// `r_raw` and `d_raw` must both point to `n * n` valid, non-overlapping f32 values,
+            /// see create_extern_c_in_place_wrapper for computing the results into the input
+            #[no_mangle]
+            pub unsafe extern "C" fn $extern_func(r_raw: *mut f32, d_raw: *const f32, n: i32) {
+                // Catch any unwinding panics so that they won't propagate over the ABI to the calling program, which would be undefined behaviour
+                let result = std::panic::catch_unwind(|| {
+                    // Wrap raw pointers into 'not unsafe' Rust slices with a well defined size
+                    let d = unsafe { std::slice::from_raw_parts(d_raw, (n * n) as usize) };
+                    let mut r = unsafe { std::slice::from_raw_parts_mut(r_raw, (n * n) as usize) };
+                    // Evaluate the wrapped function, 'self::' skips the extern function in this block
+                    self::$wrapped_func(&mut r, d, n as usize);
+                });
+                // Print an error to stderr if something went horribly wrong
+                if result.is_err() {
+                    eprintln!("error: rust panicked");
+                }
             }
-        }
+        };
+    };
+}
+
+/// Extern C-ABI wrapper like create_extern_c_wrapper, for a Rust 'step_in_place'-implementation,
+/// which reads d from the same buffer it writes the results to.
+/// The SIMD versions pack all of d into vd and vt before the first result is written, the scalar versions copy d.
+#[macro_export]
+macro_rules! create_extern_c_in_place_wrapper {
+    ($extern_func:ident, $wrapped_func:ident) => {
+        #[cfg(feature = "c-abi")]
+        const _: () = {
+            /// # Safety
+            /// `rd_raw` must point to `n * n` valid f32 values
+            #[no_mangle]
+            pub unsafe extern "C" fn $extern_func(rd_raw: *mut f32, n: i32) {
+                let result = std::panic::catch_unwind(|| {
+                    let rd = unsafe { std::slice::from_raw_parts_mut(rd_raw, (n * n) as usize) };
+                    self::$wrapped_func(rd, n as usize);
+                });
+                if result.is_err() {
+                    eprintln!("error: rust panicked");
+                }
+            }
+        };
+    };
+}
+
+/// Extern C-ABI wrapper like create_extern_c_wrapper, for a Rust 'step_weighted'-implementation
+/// taking a weight for each of the n vertices, see tools::weights
+#[macro_export]
+macro_rules! create_extern_c_weighted_wrapper {
+    ($extern_func:ident, $wrapped_func:ident) => {
+        #[cfg(feature = "c-abi")]
+        const _: () = {
+            /// # Safety
+            /// `r_raw` and `d_raw` must both point to `n * n` valid, non-overlapping f32 values,
+            /// and `w_raw` to `n` valid f32 values
+            #[no_mangle]
+            pub unsafe extern "C" fn $extern_func(r_raw: *mut f32, d_raw: *const f32, w_raw: *const f32, n: i32) {
+                let result = std::panic::catch_unwind(|| {
+                    let d = unsafe { std::slice::from_raw_parts(d_raw, (n * n) as usize) };
+                    let w = unsafe { std::slice::from_raw_parts(w_raw, n as usize) };
+                    let r = unsafe { std::slice::from_raw_parts_mut(r_raw, (n * n) as usize) };
+                    self::$wrapped_func(r, d, w, n as usize);
+                });
+                if result.is_err() {
+                    eprintln!("error: rust panicked");
+                }
+            }
+        };
+    };
+}
+
+/// Extern C-ABI wrapper like create_extern_c_wrapper, for a Rust 'step_strided'-implementation taking
+/// the leading dimensions ldr and lda and non-zero col_major flags for column-major matrices, see tools::layout
+#[macro_export]
+macro_rules! create_extern_c_strided_wrapper {
+    ($extern_func:ident, $wrapped_func:ident) => {
+        #[cfg(feature = "c-abi")]
+        const _: () = {
+            /// # Safety
+            /// `r_raw` and `d_raw` must point to n×n matrices with leading dimensions `ldr` and `lda`,
+            /// i.e. `ld * (n - 1) + n` valid f32 values, which must not overlap
+            #[no_mangle]
+            pub unsafe extern "C" fn $extern_func(r_raw: *mut f32, ldr: i32, r_col_major: i32,
+                                                  d_raw: *const f32, lda: i32, d_col_major: i32, n: i32) {
+                let result = std::panic::catch_unwind(|| {
+                    let r_layout = $crate::layout::Layout { ld: ldr as usize, col_major: r_col_major != 0 };
+                    let d_layout = $crate::layout::Layout { ld: lda as usize, col_major: d_col_major != 0 };
+                    let n = n as usize;
+                    // Check the leading dimensions before computing the lengths of the slices from them
+                    assert!(r_layout.ld >= n && d_layout.ld >= n, "leading dimensions must be at least n");
+                    let d = unsafe { std::slice::from_raw_parts(d_raw, d_layout.len(n)) };
+                    let r = unsafe { std::slice::from_raw_parts_mut(r_raw, r_layout.len(n)) };
+                    self::$wrapped_func(r, r_layout, d, d_layout, n);
+                });
+                if result.is_err() {
+                    eprintln!("error: rust panicked");
+                }
+            }
+        };
     };
 }
 
@@ -36,6 +134,7 @@ pub fn min(x: f32, y: f32) -> f32 {
 // ANCHOR_END: min
 
 // ANCHOR: z_encode
+#[cfg(not(any(feature = "emulated-simd", not(target_arch = "x86_64"))))]
 #[inline]
 pub fn z_encode(x: u32, y: u32) -> u32 {
     let odd_bits = 0x55555555;
@@ -43,3 +142,38 @@ pub fn z_encode(x: u32, y: u32) -> u32 {
     unsafe { _pdep_u32(x, odd_bits) | _pdep_u32(y, even_bits) }
 }
 // ANCHOR_END: z_encode
+
+/// Same as above, but without the BMI2 instruction pdep, which exists only on x86_64
+#[cfg(any(feature = "emulated-simd", not(target_arch = "x86_64")))]
+#[inline]
+pub fn z_encode(x: u32, y: u32) -> u32 {
+    // Move the lowest 16 bits of x to every other bit, halving the distance in each step
+    let spread = |x: u32| {
+        let x = (x | (x << 8)) & 0x00FF00FF;
+        let x = (x | (x << 4)) & 0x0F0F0F0F;
+        let x = (x | (x << 2)) & 0x33333333;
+        (x | (x << 1)) & 0x55555555
+    };
+    spread(x & 0xFFFF) | (spread(y & 0xFFFF) << 1)
+}
+
+/// True if the n×n row-major matrix d equals its transpose, bit for bit
+pub fn is_symmetric(d: &[f32], n: usize) -> bool {
+    (0..n).all(|i| (0..i).all(|j| d[n * i + j].to_bits() == d[n * j + i].to_bits()))
+}
+
+/// Copy the upper triangle of the n×n row-major matrix r into its lower triangle,
+/// e.g. after computing only the upper triangle of a symmetric result
+pub fn mirror_upper_triangle(r: &mut [f32], n: usize) {
+    // Copy square tiles, such that the columns read from the upper triangle stay in cache
+    const TILE: usize = 64;
+    for i0 in (0..n).step_by(TILE) {
+        for j0 in (0..=i0).step_by(TILE) {
+            for i in i0..n.min(i0 + TILE) {
+                for j in j0..i.min(j0 + TILE) {
+                    r[n * i + j] = r[n * j + i];
+                }
+            }
+        }
+    }
+}
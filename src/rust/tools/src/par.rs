@@ -0,0 +1,553 @@
+//! Data-parallel iteration over slices.
+//!
+//! The step implementations describe their loops once, using an API that mirrors the subset of
+//! rayon they need, and the backend executing those loops is selected with cargo features:
+//!
+//! * `rayon` (default): work-stealing with the rayon thread pool,
+//! * `std-threads`: `std::thread::scope` with one static partition per thread,
+//! * `no-multi-thread`: everything runs sequentially in the calling thread.
+//!
+//! If several backends are enabled, `no-multi-thread` takes precedence over `std-threads`,
+//! which takes precedence over `rayon`.
+//! Regardless of the backend, `run_sequentially` executes all loops of a closure in the calling thread.
+//!
+//! E.g. the rows of `r` and `d` can be processed in parallel with
+//! `r.par_chunks_mut(n).zip(d.par_chunks(n)).enumerate().for_each(|(i, (r_row, d_row))| ...)`
+use std::cell::Cell;
+use std::cmp;
+use std::iter;
+use std::ops;
+use std::slice;
+
+pub mod prelude {
+    pub use super::{ParallelSlice, ParallelSliceMut, Producer};
+}
+
+/// A sequence of items of known length that can be split into two independent halves,
+/// such that each half can be consumed in a different thread.
+pub trait Producer: Send + Sized {
+    type Item;
+    type IntoIter: Iterator<Item = Self::Item>;
+
+    /// Amount of items produced
+    fn len(&self) -> usize;
+
+    /// Split into two producers, the first one producing the items before `index`
+    fn split_at(self, index: usize) -> (Self, Self);
+
+    /// Sequential iterator over all items
+    fn into_iter(self) -> Self::IntoIter;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Pair up items of both producers, stopping at the end of the shorter one
+    fn zip<P: Producer>(self, other: P) -> Zip<Self, P> {
+        Zip { a: self, b: other }
+    }
+
+    /// Pair up items with their index
+    fn enumerate(self) -> Enumerate<Self> {
+        Enumerate { base: self, offset: 0 }
+    }
+
+    /// Apply `f` on all items, possibly in parallel, using the backend selected by cargo features
+    fn for_each<F>(self, f: F)
+    where
+        F: Fn(Self::Item) + Sync + Send,
+    {
+        if is_sequential() {
+            sequential_backend::for_each(self, f)
+        } else {
+            backend::for_each(self, f)
+        }
+    }
+}
+
+/// Parallel iteration over a shared slice
+pub trait ParallelSlice<T: Sync> {
+    /// Like `slice::chunks`
+    fn par_chunks(&self, chunk_size: usize) -> Chunks<'_, T>;
+    /// Like `slice::iter`
+    fn par_iter(&self) -> Iter<'_, T>;
+}
+
+/// Parallel iteration over a mutable slice
+pub trait ParallelSliceMut<T: Send> {
+    /// Like `slice::chunks_mut`
+    fn par_chunks_mut(&mut self, chunk_size: usize) -> ChunksMut<'_, T>;
+    /// Like `slice::iter_mut`
+    fn par_iter_mut(&mut self) -> IterMut<'_, T>;
+    /// Like `slice::sort_unstable`
+    fn par_sort_unstable(&mut self)
+    where
+        T: Ord;
+    /// Like `slice::sort_unstable_by_key`
+    fn par_sort_unstable_by_key<K, F>(&mut self, f: F)
+    where
+        K: Ord,
+        F: Fn(&T) -> K + Sync;
+}
+
+impl<T: Sync> ParallelSlice<T> for [T] {
+    fn par_chunks(&self, chunk_size: usize) -> Chunks<'_, T> {
+        assert!(chunk_size > 0, "chunk size must be non-zero");
+        Chunks { slice: self, chunk_size }
+    }
+
+    fn par_iter(&self) -> Iter<'_, T> {
+        Iter { slice: self }
+    }
+}
+
+impl<T: Send> ParallelSliceMut<T> for [T] {
+    fn par_chunks_mut(&mut self, chunk_size: usize) -> ChunksMut<'_, T> {
+        assert!(chunk_size > 0, "chunk size must be non-zero");
+        ChunksMut { slice: self, chunk_size }
+    }
+
+    fn par_iter_mut(&mut self) -> IterMut<'_, T> {
+        IterMut { slice: self }
+    }
+
+    fn par_sort_unstable(&mut self)
+    where
+        T: Ord,
+    {
+        if is_sequential() {
+            sequential_backend::sort_unstable(self)
+        } else {
+            backend::sort_unstable(self)
+        }
+    }
+
+    fn par_sort_unstable_by_key<K, F>(&mut self, f: F)
+    where
+        K: Ord,
+        F: Fn(&T) -> K + Sync,
+    {
+        if is_sequential() {
+            sequential_backend::sort_unstable_by_key(self, f)
+        } else {
+            backend::sort_unstable_by_key(self, f)
+        }
+    }
+}
+
+/// Returns the amount of threads the selected backend uses at most
+pub fn num_threads() -> usize {
+    backend::num_threads()
+}
+
+thread_local! {
+    static RUN_SEQUENTIALLY: Cell<bool> = const { Cell::new(false) };
+}
+
+fn is_sequential() -> bool {
+    RUN_SEQUENTIALLY.with(Cell::get)
+}
+
+/// Call `f` such that all parallel loops started from this thread run sequentially in this thread,
+/// e.g. when the input is so small that the cost of parallelism outweighs the gains
+pub fn run_sequentially<R, F: FnOnce() -> R>(f: F) -> R {
+    // Restores the previous mode also if f panics
+    struct Restore(bool);
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            RUN_SEQUENTIALLY.with(|s| s.set(self.0));
+        }
+    }
+    let _restore = Restore(RUN_SEQUENTIALLY.with(|s| s.replace(true)));
+    f()
+}
+
+pub struct Chunks<'a, T: Sync> {
+    slice: &'a [T],
+    chunk_size: usize,
+}
+
+impl<'a, T: Sync> Producer for Chunks<'a, T> {
+    type Item = &'a [T];
+    type IntoIter = slice::Chunks<'a, T>;
+
+    fn len(&self) -> usize {
+        self.slice.len().div_ceil(self.chunk_size)
+    }
+
+    fn split_at(self, index: usize) -> (Self, Self) {
+        let mid = cmp::min(index * self.chunk_size, self.slice.len());
+        let (left, right) = self.slice.split_at(mid);
+        (Chunks { slice: left, ..self }, Chunks { slice: right, ..self })
+    }
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.slice.chunks(self.chunk_size)
+    }
+}
+
+pub struct ChunksMut<'a, T: Send> {
+    slice: &'a mut [T],
+    chunk_size: usize,
+}
+
+impl<'a, T: Send> Producer for ChunksMut<'a, T> {
+    type Item = &'a mut [T];
+    type IntoIter = slice::ChunksMut<'a, T>;
+
+    fn len(&self) -> usize {
+        self.slice.len().div_ceil(self.chunk_size)
+    }
+
+    fn split_at(self, index: usize) -> (Self, Self) {
+        let mid = cmp::min(index * self.chunk_size, self.slice.len());
+        let chunk_size = self.chunk_size;
+        let (left, right) = self.slice.split_at_mut(mid);
+        (ChunksMut { slice: left, chunk_size }, ChunksMut { slice: right, chunk_size })
+    }
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.slice.chunks_mut(self.chunk_size)
+    }
+}
+
+pub struct Iter<'a, T: Sync> {
+    slice: &'a [T],
+}
+
+impl<'a, T: Sync> Producer for Iter<'a, T> {
+    type Item = &'a T;
+    type IntoIter = slice::Iter<'a, T>;
+
+    fn len(&self) -> usize {
+        self.slice.len()
+    }
+
+    fn split_at(self, index: usize) -> (Self, Self) {
+        let (left, right) = self.slice.split_at(index);
+        (Iter { slice: left }, Iter { slice: right })
+    }
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.slice.iter()
+    }
+}
+
+pub struct IterMut<'a, T: Send> {
+    slice: &'a mut [T],
+}
+
+impl<'a, T: Send> Producer for IterMut<'a, T> {
+    type Item = &'a mut T;
+    type IntoIter = slice::IterMut<'a, T>;
+
+    fn len(&self) -> usize {
+        self.slice.len()
+    }
+
+    fn split_at(self, index: usize) -> (Self, Self) {
+        let (left, right) = self.slice.split_at_mut(index);
+        (IterMut { slice: left }, IterMut { slice: right })
+    }
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.slice.iter_mut()
+    }
+}
+
+pub struct Zip<A: Producer, B: Producer> {
+    a: A,
+    b: B,
+}
+
+impl<A: Producer, B: Producer> Producer for Zip<A, B> {
+    type Item = (A::Item, B::Item);
+    type IntoIter = iter::Zip<A::IntoIter, B::IntoIter>;
+
+    fn len(&self) -> usize {
+        cmp::min(self.a.len(), self.b.len())
+    }
+
+    fn split_at(self, index: usize) -> (Self, Self) {
+        let (a_left, a_right) = self.a.split_at(index);
+        let (b_left, b_right) = self.b.split_at(index);
+        (Zip { a: a_left, b: b_left }, Zip { a: a_right, b: b_right })
+    }
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.a.into_iter().zip(self.b.into_iter())
+    }
+}
+
+pub struct Enumerate<P: Producer> {
+    base: P,
+    offset: usize,
+}
+
+impl<P: Producer> Producer for Enumerate<P> {
+    type Item = (usize, P::Item);
+    type IntoIter = iter::Zip<ops::RangeFrom<usize>, P::IntoIter>;
+
+    fn len(&self) -> usize {
+        self.base.len()
+    }
+
+    fn split_at(self, index: usize) -> (Self, Self) {
+        let (left, right) = self.base.split_at(index);
+        (Enumerate { base: left, offset: self.offset },
+         Enumerate { base: right, offset: self.offset + index })
+    }
+
+    fn into_iter(self) -> Self::IntoIter {
+        (self.offset..).zip(self.base.into_iter())
+    }
+}
+
+/// Work-stealing backend, producers are split in halves lazily whenever rayon decides a task should be divided
+#[cfg(feature = "rayon")]
+pub mod rayon_backend {
+    use super::Producer;
+    use rayon::prelude::*;
+
+    pub fn for_each<P, F>(producer: P, f: F)
+    where
+        P: Producer,
+        F: Fn(P::Item) + Sync + Send,
+    {
+        let split_half = |p: P| {
+            if p.len() > 1 {
+                let mid = p.len() / 2;
+                let (left, right) = p.split_at(mid);
+                (left, Some(right))
+            } else {
+                (p, None)
+            }
+        };
+        rayon::iter::split(producer, split_half).for_each(|p| p.into_iter().for_each(&f));
+    }
+
+    pub fn sort_unstable_by_key<T, K, F>(v: &mut [T], f: F)
+    where
+        T: Send,
+        K: Ord,
+        F: Fn(&T) -> K + Sync,
+    {
+        v.par_sort_unstable_by_key(f)
+    }
+
+    pub fn sort_unstable<T: Ord + Send>(v: &mut [T]) {
+        v.par_sort_unstable()
+    }
+
+    pub fn num_threads() -> usize {
+        rayon::current_num_threads()
+    }
+}
+
+/// Static partitioning backend, each thread in a `std::thread::scope` processes one contiguous range of items of equal size
+pub mod std_threads_backend {
+    use super::Producer;
+    use std::thread;
+
+    pub fn for_each<P, F>(producer: P, f: F)
+    where
+        P: Producer,
+        F: Fn(P::Item) + Sync + Send,
+    {
+        let partition_len = std::cmp::max(1, producer.len().div_ceil(num_threads()));
+        let f = &f;
+        thread::scope(|s| {
+            let mut rest = producer;
+            while rest.len() > partition_len {
+                let (partition, tail) = rest.split_at(partition_len);
+                s.spawn(move || partition.into_iter().for_each(f));
+                rest = tail;
+            }
+            // The calling thread processes the last partition
+            rest.into_iter().for_each(f);
+        });
+    }
+
+    // Sorting is not partitioned, it runs sequentially in the calling thread
+    pub use super::sequential_backend::{sort_unstable, sort_unstable_by_key};
+
+    /// Amount of threads available to this process, respecting e.g. CPU affinity masks set with taskset
+    pub fn num_threads() -> usize {
+        thread::available_parallelism().map_or(1, |n| n.get())
+    }
+}
+
+/// Sequential backend, everything runs in the calling thread
+pub mod sequential_backend {
+    use super::Producer;
+
+    pub fn for_each<P, F>(producer: P, f: F)
+    where
+        P: Producer,
+        F: Fn(P::Item) + Sync + Send,
+    {
+        producer.into_iter().for_each(f)
+    }
+
+    pub fn sort_unstable_by_key<T, K, F>(v: &mut [T], f: F)
+    where
+        T: Send,
+        K: Ord,
+        F: Fn(&T) -> K + Sync,
+    {
+        v.sort_unstable_by_key(f)
+    }
+
+    pub fn sort_unstable<T: Ord + Send>(v: &mut [T]) {
+        v.sort_unstable()
+    }
+
+    pub fn num_threads() -> usize {
+        1
+    }
+}
+
+#[cfg(feature = "no-multi-thread")]
+use self::sequential_backend as backend;
+#[cfg(all(feature = "std-threads", not(feature = "no-multi-thread")))]
+use self::std_threads_backend as backend;
+#[cfg(all(feature = "rayon", not(any(feature = "std-threads", feature = "no-multi-thread"))))]
+use self::rayon_backend as backend;
+#[cfg(not(any(feature = "rayon", feature = "std-threads", feature = "no-multi-thread")))]
+compile_error!("no parallel backend selected, enable one of the features 'rayon', 'std-threads' or 'no-multi-thread'");
+
+/// The public API runs on the backend selected by cargo features, e.g.
+/// `cargo test --no-default-features --features std-threads`, the backends are also tested directly
+#[cfg(test)]
+mod tests {
+    use super::prelude::*;
+    use super::*;
+
+    /// Enough items for splitting them between all threads
+    const LEN: usize = 1000;
+
+    /// Fill each chunk of 7 elements with the index of the chunk, using the given for_each of a backend
+    macro_rules! test_backend {
+        ($name:ident, $backend:path) => {
+            #[test]
+            fn $name() {
+                use $backend as b;
+                let mut v = vec![usize::MAX; LEN];
+                b::for_each(v.par_chunks_mut(7).enumerate(), |(i, chunk)| chunk.fill(i));
+                assert!(v.iter().enumerate().all(|(k, &i)| i == k / 7));
+                let mut w = vec![3, 1, 2, 5, 4];
+                b::sort_unstable(&mut w);
+                assert_eq!(w, [1, 2, 3, 4, 5]);
+                b::sort_unstable_by_key(&mut w, |&x| cmp::Reverse(x));
+                assert_eq!(w, [5, 4, 3, 2, 1]);
+                assert!(b::num_threads() >= 1);
+            }
+        };
+    }
+
+    #[cfg(feature = "rayon")]
+    test_backend!(rayon_backend_fills_chunks, rayon_backend);
+    test_backend!(std_threads_backend_fills_chunks, std_threads_backend);
+    test_backend!(sequential_backend_fills_chunks, sequential_backend);
+
+    #[test]
+    fn chunks_split_at() {
+        let v: Vec<usize> = (0..10).collect();
+        let chunks = v.par_chunks(4);
+        assert_eq!(chunks.len(), 3);
+        let (left, right) = chunks.split_at(1);
+        assert_eq!(left.into_iter().collect::<Vec<_>>(), [&v[..4]]);
+        assert_eq!(right.into_iter().collect::<Vec<_>>(), [&v[4..8], &v[8..]]);
+        // Splitting after the end leaves the right half empty
+        let (left, right) = v.par_chunks(4).split_at(5);
+        assert_eq!((left.len(), right.len()), (3, 0));
+    }
+
+    #[test]
+    fn enumerate_zip_split_at() {
+        let a: Vec<usize> = (0..10).collect();
+        let b: Vec<usize> = (100..107).collect();
+        let producer = a.par_iter().zip(b.par_iter()).enumerate();
+        assert_eq!(producer.len(), 7);
+        let (left, right) = producer.split_at(3);
+        let (middle, right) = right.split_at(2);
+        let items: Vec<(usize, (&usize, &usize))> = left.into_iter().chain(middle.into_iter()).chain(right.into_iter()).collect();
+        assert_eq!(items, (0..7).map(|k| (k, (&a[k], &b[k]))).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn chunks_mut_zip_chunks() {
+        let d: Vec<usize> = (0..LEN).collect();
+        let mut r = vec![0; LEN];
+        r.par_chunks_mut(10)
+            .zip(d.par_chunks(10))
+            .for_each(|(r_chunk, d_chunk)| {
+                for (x, &y) in r_chunk.iter_mut().zip(d_chunk) {
+                    *x = 2 * y;
+                }
+            });
+        assert!(r.iter().zip(&d).all(|(&x, &y)| x == 2 * y));
+    }
+
+    #[test]
+    fn zip_stops_at_shorter() {
+        let mut r = vec![0; LEN];
+        let ones = vec![1; LEN / 2];
+        r.par_iter_mut()
+            .zip(ones.par_iter())
+            .for_each(|(x, &y)| *x = y);
+        assert_eq!(r.iter().sum::<usize>(), LEN / 2);
+        assert!(r[LEN / 2..].iter().all(|&x| x == 0));
+    }
+
+    #[test]
+    fn iter_mut_enumerate() {
+        let mut v = vec![0; LEN];
+        v.par_iter_mut().enumerate().for_each(|(i, x)| *x = i * i);
+        assert!(v.iter().enumerate().all(|(i, &x)| x == i * i));
+    }
+
+    #[test]
+    fn empty() {
+        let mut v: Vec<f32> = Vec::new();
+        v.par_chunks_mut(8).for_each(|_| panic!("no chunks expected"));
+        v.par_iter_mut().enumerate().for_each(|_| panic!("no items expected"));
+        v.par_chunks(8).zip(v.par_iter()).for_each(|_| panic!("no items expected"));
+    }
+
+    #[test]
+    #[should_panic(expected = "chunk size must be non-zero")]
+    fn zero_chunk_size() {
+        let mut v = [0; 4];
+        v.par_chunks_mut(0);
+    }
+
+    #[test]
+    fn sort() {
+        let mut v: Vec<usize> = (0..LEN).map(|k| (7919 * k) % LEN).collect();
+        v.par_sort_unstable();
+        assert!(v.iter().enumerate().all(|(k, &x)| x == k));
+        v.par_sort_unstable_by_key(|&x| cmp::Reverse(x));
+        assert!(v.iter().enumerate().all(|(k, &x)| x == LEN - 1 - k));
+    }
+
+    #[test]
+    fn run_sequentially_in_calling_thread() {
+        let caller = std::thread::current().id();
+        let mut v = vec![0; LEN];
+        run_sequentially(|| {
+            v.par_iter_mut().for_each(|x| {
+                assert_eq!(std::thread::current().id(), caller);
+                *x = 1;
+            })
+        });
+        assert_eq!(v.iter().sum::<usize>(), LEN);
+        // The previous mode is restored, also after a panic
+        assert!(!is_sequential());
+        let result = std::panic::catch_unwind(|| run_sequentially(|| panic!("in run_sequentially")));
+        assert!(result.is_err());
+        assert!(!is_sequential());
+        run_sequentially(|| run_sequentially(|| assert!(is_sequential())));
+        assert!(!is_sequential());
+    }
+}
@@ -0,0 +1,172 @@
+//! Tunable parameters of the step implementations.
+//!
+//! The defaults were tuned for one machine. Without tuned parameters, the stripe width
+//! and Z-order block size of v7 are derived from the cache sizes given by tools::cpu.
+//! The autotune program of the planner crate searches for better values on the current
+//! machine and saves them into a cache file, which is read when the parameters are first needed.
+//! The cache file is given by the environment variable SHORTCUT_PARAMS or, if it is not set,
+//! `$XDG_CACHE_HOME/shortcut-comparison/params.txt`, defaulting to `$HOME/.cache/...`.
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+use crate::cpu::{self, Caches};
+use crate::par;
+
+/// Supported values for the amount of f32 values processed independently in v2
+pub const BLOCK_SIZES: [usize; 5] = [1, 2, 4, 8, 16];
+/// Supported (height, width) shapes of the register tile in v4
+pub const BLOCK_SHAPES: [(usize, usize); 10] = [
+    (1, 1), (2, 2), (2, 4), (3, 2), (3, 3), (3, 4), (4, 2), (4, 3), (4, 4), (6, 2),
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Params {
+    /// v2: amount of independent f32 accumulators in the innermost loop
+    pub block_size: usize,
+    /// v4: amount of rows of vd in one row block
+    pub block_height: usize,
+    /// v4: amount of rows of vt in one row block, each pair of row blocks yields block_height × block_width results
+    pub block_width: usize,
+    /// v6: how many f32x8 vectors ahead of the current column to prefetch
+    pub prefetch_length: usize,
+    /// v7: how many adjacent columns to process during one pass
+    pub cols_per_stripe: usize,
+    /// v7: how many row pairs adjacent on the Z-order curve one task processes for each stripe
+    pub z_block_size: usize,
+}
+
+impl Default for Params {
+    fn default() -> Params {
+        Params {
+            block_size: 4,
+            block_height: 3,
+            block_width: 3,
+            prefetch_length: 20,
+            cols_per_stripe: 500,
+            z_block_size: 1,
+        }
+    }
+}
+
+fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+impl Params {
+    /// Defaults, with the stripe width and Z-order block size of v7 fitted to the given caches
+    /// and the threads of tools::par
+    pub fn for_caches(caches: &Caches) -> Params {
+        let cols_per_stripe = caches.stripe_width();
+        Params {
+            cols_per_stripe,
+            z_block_size: caches.z_block_size(cols_per_stripe, par::num_threads()),
+            ..Params::default()
+        }
+    }
+
+    /// Check that all parameters have values the step implementations support
+    pub fn validate(&self) -> io::Result<()> {
+        if !BLOCK_SIZES.contains(&self.block_size) {
+            return Err(invalid_data(format!("block_size must be one of {:?}, not {}", BLOCK_SIZES, self.block_size)));
+        }
+        if !BLOCK_SHAPES.contains(&(self.block_height, self.block_width)) {
+            return Err(invalid_data(format!("(block_height, block_width) must be one of {:?}, not ({}, {})",
+                                            BLOCK_SHAPES, self.block_height, self.block_width)));
+        }
+        if self.cols_per_stripe == 0 {
+            return Err(invalid_data("cols_per_stripe must be positive".to_string()));
+        }
+        if self.z_block_size == 0 {
+            return Err(invalid_data("z_block_size must be positive".to_string()));
+        }
+        Ok(())
+    }
+
+    /// Parse lines of 'name = value', empty lines and lines starting with '#' are ignored.
+    /// Parameters missing from the input have their default values,
+    /// except block_width, which defaults to block_height for files written before it existed.
+    pub fn parse(s: &str) -> io::Result<Params> {
+        let mut params = Params::default();
+        let mut block_width = None;
+        for line in s.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (name, value) = match line.split_once('=') {
+                Some((name, value)) => (name.trim(), value.trim()),
+                None => return Err(invalid_data(format!("expected 'name = value', got '{}'", line))),
+            };
+            let value: usize = value.parse().map_err(|_| invalid_data(format!("invalid value for {}: '{}'", name, value)))?;
+            match name {
+                "block_size" => params.block_size = value,
+                "block_height" => params.block_height = value,
+                "block_width" => block_width = Some(value),
+                "prefetch_length" => params.prefetch_length = value,
+                "cols_per_stripe" => params.cols_per_stripe = value,
+                "z_block_size" => params.z_block_size = value,
+                _ => return Err(invalid_data(format!("unknown parameter '{}'", name))),
+            }
+        }
+        params.block_width = block_width.unwrap_or(params.block_height);
+        params.validate()?;
+        Ok(params)
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Params> {
+        Params::parse(&fs::read_to_string(path)?)
+    }
+
+    /// Write all parameters to a file, creating its parent directories if needed
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let path = path.as_ref();
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(path, self.to_string())
+    }
+}
+
+impl fmt::Display for Params {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "block_size = {}", self.block_size)?;
+        writeln!(f, "block_height = {}", self.block_height)?;
+        writeln!(f, "block_width = {}", self.block_width)?;
+        writeln!(f, "prefetch_length = {}", self.prefetch_length)?;
+        writeln!(f, "cols_per_stripe = {}", self.cols_per_stripe)?;
+        writeln!(f, "z_block_size = {}", self.z_block_size)
+    }
+}
+
+/// Path of the cache file for tuned parameters, None if no suitable directory is known
+pub fn cache_path() -> Option<PathBuf> {
+    if let Some(path) = env::var_os("SHORTCUT_PARAMS") {
+        return Some(PathBuf::from(path));
+    }
+    let cache_dir = match env::var_os("XDG_CACHE_HOME") {
+        Some(dir) => PathBuf::from(dir),
+        None => PathBuf::from(env::var_os("HOME")?).join(".cache"),
+    };
+    Some(cache_dir.join("shortcut-comparison").join("params.txt"))
+}
+
+/// Parameters from the cache file, or the defaults for the caches of this CPU if there is no cache file.
+/// The file is read only once, at the first call.
+pub fn get() -> &'static Params {
+    static PARAMS: OnceLock<Params> = OnceLock::new();
+    PARAMS.get_or_init(|| {
+        let path = match cache_path() {
+            // Miri does not allow reading files with isolation
+            _ if cfg!(miri) => return Params::for_caches(cpu::caches()),
+            Some(path) if path.is_file() => path,
+            _ => return Params::for_caches(cpu::caches()),
+        };
+        Params::load(&path).unwrap_or_else(|e| {
+            eprintln!("warning: ignoring tuned parameters in {}: {}", path.display(), e);
+            Params::for_caches(cpu::caches())
+        })
+    })
+}
@@ -1,101 +1,600 @@
-use std::arch::x86_64::*; // Intel SIMD intrinsic mappings
+//! 8 f32 values in one 256-bit vector and the operations the step implementations need on them.
+//!
+//! By default, all operations are AVX intrinsics. With the cargo feature `emulated-simd`,
+//! or on targets other than x86_64, they are implemented with plain arrays instead,
+//! with identical results, e.g. for running the step implementations under Miri or on CPUs without AVX.
+use std::fmt;
+
+use crate::layout::Layout;
 
-#[allow(non_camel_case_types)]
-pub type f32x8 = __m256;
 #[allow(non_upper_case_globals)]
 pub const f32x8_LENGTH: usize = 8;
 
-/// Return a 256-bit vector containing 8 infinity values of f32
-#[inline]
-pub fn f32x8_infty() -> f32x8 {
-    unsafe { _mm256_set1_ps(std::f32::INFINITY) }
+/// True if the vector operations are emulated without SIMD instructions
+pub const IS_EMULATED: bool = cfg!(any(feature = "emulated-simd", not(target_arch = "x86_64")));
+
+/// Vector operations with AVX intrinsics
+#[cfg(not(any(feature = "emulated-simd", not(target_arch = "x86_64"))))]
+mod avx_backend {
+    use std::arch::x86_64::*; // Intel SIMD intrinsic mappings
+    use std::ops::Add;
+
+    use super::f32x8_LENGTH;
+
+    /// 8 f32 values in one 256-bit vector register.
+    ///
+    /// Elements are numbered in the order of from_slice and `From<[f32; 8]>`, which put element 0
+    /// into the highest 32 bits of the register, while load, loadu, store and storeu use memory order,
+    /// i.e. the first f32 in memory is in the lowest 32 bits.
+    #[allow(non_camel_case_types)]
+    #[derive(Clone, Copy)]
+    #[repr(transparent)]
+    pub struct f32x8(__m256);
+
+    impl f32x8 {
+        /// Load the first 8 elements of a slice, which must be aligned to 32 bytes, in memory order
+        #[inline]
+        pub fn load(s: &[f32]) -> f32x8 {
+            assert!(s.len() >= f32x8_LENGTH);
+            assert_eq!(s.as_ptr().align_offset(std::mem::align_of::<f32x8>()), 0, "unaligned load");
+            f32x8(unsafe { _mm256_load_ps(s.as_ptr()) })
+        }
+
+        /// Load the first 8 elements of a slice in memory order
+        #[inline]
+        pub fn loadu(s: &[f32]) -> f32x8 {
+            assert!(s.len() >= f32x8_LENGTH);
+            f32x8(unsafe { _mm256_loadu_ps(s.as_ptr()) })
+        }
+
+        /// Store all elements in memory order into the first 8 elements of a slice aligned to 32 bytes
+        #[inline]
+        pub fn store(self, dst: &mut [f32]) {
+            assert!(dst.len() >= f32x8_LENGTH);
+            assert_eq!(dst.as_ptr().align_offset(std::mem::align_of::<f32x8>()), 0, "unaligned store");
+            unsafe { _mm256_store_ps(dst.as_mut_ptr(), self.0) }
+        }
+
+        /// Store all elements in memory order into the first 8 elements of a slice
+        #[inline]
+        pub fn storeu(self, dst: &mut [f32]) {
+            assert!(dst.len() >= f32x8_LENGTH);
+            unsafe { _mm256_storeu_ps(dst.as_mut_ptr(), self.0) }
+        }
+
+        /// Store the first dst.len() < 8 elements in memory order
+        #[inline]
+        pub(super) fn store_partial(self, dst: &mut [f32]) {
+            debug_assert!(dst.len() < f32x8_LENGTH);
+            // All bits set for the first dst.len() elements, loaded from an offset into a sliding window
+            const MASK_WINDOW: [i32; 16] = [-1, -1, -1, -1, -1, -1, -1, -1, 0, 0, 0, 0, 0, 0, 0, 0];
+            unsafe {
+                let mask = _mm256_loadu_si256(MASK_WINDOW[f32x8_LENGTH - dst.len()..].as_ptr() as *const __m256i);
+                _mm256_maskstore_ps(dst.as_mut_ptr(), mask, self.0)
+            }
+        }
+
+        /// Element-wise minimum, if either element is NaN, the element of `other` is returned
+        #[inline]
+        pub fn min(self, other: f32x8) -> f32x8 {
+            f32x8(unsafe { _mm256_min_ps(self.0, other.0) })
+        }
+
+        /// Permute W wide chunks with adjacent chunks, W must be 1, 2 or 4.
+        /// E.g.
+        /// swap::<1>([0, 1, 2, 3, 4, 5, 6, 7]) == [1, 0, 3, 2, 5, 4, 7, 6]
+        /// swap::<2>([0, 1, 2, 3, 4, 5, 6, 7]) == [2, 3, 0, 1, 6, 7, 4, 5]
+        /// swap::<4>([0, 1, 2, 3, 4, 5, 6, 7]) == [4, 5, 6, 7, 0, 1, 2, 3]
+        ///
+        /// To make sense of the 8-bit shuffle control, read it in binary from right to left
+        /// e.g. for W = 1, control is 10_11_00_01.
+        /// Reading from right to left in 2 bit chunks we get (1, 0, 3, 2) for the 1st 128-bit lane,
+        /// and (5, 4, 7, 6) for the 2nd 128-bit lane.
+        /// Width 4 swaps both 128-bit lanes.
+        ///
+        #[inline]
+        pub fn swap<const W: usize>(self) -> f32x8 {
+            const { assert!(W == 1 || W == 2 || W == 4, "invalid shuffle width for 256-bit vector, must be 1, 2, or 4") };
+            f32x8(unsafe {
+                match W {
+                    1 => _mm256_permute_ps(self.0, 0b_10_11_00_01),
+                    2 => _mm256_permute_ps(self.0, 0b_01_00_11_10),
+                    _ => _mm256_permute2f128_ps(self.0, self.0, 1),
+                }
+            })
+        }
+
+        /// Take the elements in memory order at bits set in MASK from `other`, the rest from self
+        #[inline]
+        pub(super) fn blend<const MASK: i32>(self, other: f32x8) -> f32x8 {
+            f32x8(unsafe { _mm256_blend_ps::<MASK>(self.0, other.0) })
+        }
+
+        /// Mask of the elements where self is less than other, with all bits set, the others are 0
+        #[inline]
+        pub fn lt_mask(self, other: f32x8) -> f32x8 {
+            f32x8(unsafe { _mm256_cmp_ps::<_CMP_LT_OQ>(self.0, other.0) })
+        }
+
+        /// Mask of the elements where self equals other, with all bits set, the others are 0
+        #[inline]
+        pub fn eq_mask(self, other: f32x8) -> f32x8 {
+            f32x8(unsafe { _mm256_cmp_ps::<_CMP_EQ_OQ>(self.0, other.0) })
+        }
+
+        /// Take the elements where the highest bit of mask is set from `other`, the rest from self
+        #[inline]
+        pub fn blendv(self, other: f32x8, mask: f32x8) -> f32x8 {
+            f32x8(unsafe { _mm256_blendv_ps(self.0, other.0, mask.0) })
+        }
+
+        /// The lowest 32 bits as a float, i.e. element 7
+        #[inline]
+        pub fn lowest(self) -> f32 {
+            unsafe { _mm256_cvtss_f32(self.0) }
+        }
+    }
+
+    impl Add for f32x8 {
+        type Output = f32x8;
+
+        #[inline]
+        fn add(self, other: f32x8) -> f32x8 {
+            f32x8(unsafe { _mm256_add_ps(self.0, other.0) })
+        }
+    }
+
+    /// Element i of the vector is a[i]
+    impl From<[f32; 8]> for f32x8 {
+        #[inline]
+        fn from(a: [f32; 8]) -> f32x8 {
+            f32x8(unsafe { _mm256_set_ps(a[0], a[1], a[2], a[3], a[4], a[5], a[6], a[7]) })
+        }
+    }
+
+    impl From<f32x8> for [f32; 8] {
+        #[inline]
+        fn from(v: f32x8) -> [f32; 8] {
+            let mut a = [0.0; f32x8_LENGTH];
+            v.storeu(&mut a);
+            a.reverse();
+            a
+        }
+    }
+
+    /// Return a 256-bit vector containing 8 infinity values of f32
+    #[inline]
+    pub fn f32x8_infty() -> f32x8 {
+        f32x8(unsafe { _mm256_set1_ps(f32::INFINITY) })
+    }
+
+    /// Transpose 8 vectors as rows of an 8×8 matrix, such that element k of result c is element c of row k.
+    /// Element k means the kth 32 bits, starting from the lowest.
+    /// Pairs of rows are first interleaved within 128-bit lanes, then 64-bit pairs are combined
+    /// and finally the 128-bit lanes are exchanged.
+    #[inline]
+    pub fn transpose_8x8(rows: [f32x8; 8]) -> [f32x8; 8] {
+        let rows = rows.map(|v| v.0);
+        let cols = unsafe {
+            let t0 = _mm256_unpacklo_ps(rows[0], rows[1]);
+            let t1 = _mm256_unpackhi_ps(rows[0], rows[1]);
+            let t2 = _mm256_unpacklo_ps(rows[2], rows[3]);
+            let t3 = _mm256_unpackhi_ps(rows[2], rows[3]);
+            let t4 = _mm256_unpacklo_ps(rows[4], rows[5]);
+            let t5 = _mm256_unpackhi_ps(rows[4], rows[5]);
+            let t6 = _mm256_unpacklo_ps(rows[6], rows[7]);
+            let t7 = _mm256_unpackhi_ps(rows[6], rows[7]);
+            let u0 = _mm256_shuffle_ps(t0, t2, 0b_01_00_01_00);
+            let u1 = _mm256_shuffle_ps(t0, t2, 0b_11_10_11_10);
+            let u2 = _mm256_shuffle_ps(t1, t3, 0b_01_00_01_00);
+            let u3 = _mm256_shuffle_ps(t1, t3, 0b_11_10_11_10);
+            let u4 = _mm256_shuffle_ps(t4, t6, 0b_01_00_01_00);
+            let u5 = _mm256_shuffle_ps(t4, t6, 0b_11_10_11_10);
+            let u6 = _mm256_shuffle_ps(t5, t7, 0b_01_00_01_00);
+            let u7 = _mm256_shuffle_ps(t5, t7, 0b_11_10_11_10);
+            [
+                _mm256_permute2f128_ps(u0, u4, 0x20),
+                _mm256_permute2f128_ps(u1, u5, 0x20),
+                _mm256_permute2f128_ps(u2, u6, 0x20),
+                _mm256_permute2f128_ps(u3, u7, 0x20),
+                _mm256_permute2f128_ps(u0, u4, 0x31),
+                _mm256_permute2f128_ps(u1, u5, 0x31),
+                _mm256_permute2f128_ps(u2, u6, 0x31),
+                _mm256_permute2f128_ps(u3, u7, 0x31),
+            ]
+        };
+        cols.map(f32x8)
+    }
+
+    /// Reverse the order of all elements, e.g. between the order of from_slice and memory order
+    #[inline]
+    pub fn reverse(v: f32x8) -> f32x8 {
+        f32x8(unsafe { _mm256_permute_ps(_mm256_permute2f128_ps(v.0, v.0, 1), 0b_00_01_10_11) })
+    }
+
+    // ANCHOR: prefetch
+    #[inline]
+    pub fn prefetch(p: *const f32x8, offset: isize) {
+        unsafe { _mm_prefetch(p.wrapping_offset(offset) as *const i8, _MM_HINT_T0) }
+    }
+    // ANCHOR_END: prefetch
+}
+
+/// Vector operations on plain arrays, with the same results and memory layout as avx_backend
+#[cfg(any(feature = "emulated-simd", not(target_arch = "x86_64")))]
+mod emulated_backend {
+    use std::ops::Add;
+
+    use super::f32x8_LENGTH;
+
+    /// 8 f32 values stored in memory order, aligned like a 256-bit vector register, see avx_backend::f32x8
+    #[allow(non_camel_case_types)]
+    #[derive(Clone, Copy)]
+    #[repr(C, align(32))]
+    pub struct f32x8([f32; 8]);
+
+    impl f32x8 {
+        #[inline]
+        pub fn load(s: &[f32]) -> f32x8 {
+            assert_eq!(s.as_ptr().align_offset(std::mem::align_of::<f32x8>()), 0, "unaligned load");
+            f32x8::loadu(s)
+        }
+
+        #[inline]
+        pub fn loadu(s: &[f32]) -> f32x8 {
+            let mut a = [0.0; f32x8_LENGTH];
+            a.copy_from_slice(&s[..f32x8_LENGTH]);
+            f32x8(a)
+        }
+
+        #[inline]
+        pub fn store(self, dst: &mut [f32]) {
+            assert_eq!(dst.as_ptr().align_offset(std::mem::align_of::<f32x8>()), 0, "unaligned store");
+            self.storeu(dst)
+        }
+
+        #[inline]
+        pub fn storeu(self, dst: &mut [f32]) {
+            dst[..f32x8_LENGTH].copy_from_slice(&self.0)
+        }
+
+        #[inline]
+        pub(super) fn store_partial(self, dst: &mut [f32]) {
+            debug_assert!(dst.len() < f32x8_LENGTH);
+            let len = dst.len();
+            dst.copy_from_slice(&self.0[..len])
+        }
+
+        /// Same as _mm256_min_ps, which returns the second operand unless the first one is smaller
+        #[inline]
+        pub fn min(self, other: f32x8) -> f32x8 {
+            f32x8(std::array::from_fn(|k| crate::min(self.0[k], other.0[k])))
+        }
+
+        /// Exchanging W wide chunks moves element k to k ^ W, W must be 1, 2 or 4
+        #[inline]
+        pub fn swap<const W: usize>(self) -> f32x8 {
+            const { assert!(W == 1 || W == 2 || W == 4, "invalid shuffle width for 256-bit vector, must be 1, 2, or 4") };
+            f32x8(std::array::from_fn(|k| self.0[k ^ W]))
+        }
+
+        #[inline]
+        pub(super) fn blend<const MASK: i32>(self, other: f32x8) -> f32x8 {
+            f32x8(std::array::from_fn(|k| if MASK & (1 << k) != 0 { other.0[k] } else { self.0[k] }))
+        }
+
+        /// Comparison masks have all bits set, like _mm256_cmp_ps, which makes the true elements NaN
+        #[inline]
+        fn mask(m: [bool; 8]) -> f32x8 {
+            f32x8(m.map(|b| f32::from_bits(if b { u32::MAX } else { 0 })))
+        }
+
+        #[inline]
+        pub fn lt_mask(self, other: f32x8) -> f32x8 {
+            f32x8::mask(std::array::from_fn(|k| self.0[k] < other.0[k]))
+        }
+
+        #[inline]
+        pub fn eq_mask(self, other: f32x8) -> f32x8 {
+            f32x8::mask(std::array::from_fn(|k| self.0[k] == other.0[k]))
+        }
+
+        /// Same as _mm256_blendv_ps, which only reads the sign bit of each element of mask
+        #[inline]
+        pub fn blendv(self, other: f32x8, mask: f32x8) -> f32x8 {
+            f32x8(std::array::from_fn(|k| if mask.0[k].is_sign_negative() { other.0[k] } else { self.0[k] }))
+        }
+
+        #[inline]
+        pub fn lowest(self) -> f32 {
+            self.0[0]
+        }
+    }
+
+    impl Add for f32x8 {
+        type Output = f32x8;
+
+        #[inline]
+        fn add(self, other: f32x8) -> f32x8 {
+            f32x8(std::array::from_fn(|k| self.0[k] + other.0[k]))
+        }
+    }
+
+    impl From<[f32; 8]> for f32x8 {
+        #[inline]
+        fn from(mut a: [f32; 8]) -> f32x8 {
+            a.reverse();
+            f32x8(a)
+        }
+    }
+
+    impl From<f32x8> for [f32; 8] {
+        #[inline]
+        fn from(v: f32x8) -> [f32; 8] {
+            let mut a = v.0;
+            a.reverse();
+            a
+        }
+    }
+
+    #[inline]
+    pub fn f32x8_infty() -> f32x8 {
+        f32x8([f32::INFINITY; f32x8_LENGTH])
+    }
+
+    #[inline]
+    pub fn transpose_8x8(rows: [f32x8; 8]) -> [f32x8; 8] {
+        std::array::from_fn(|c| f32x8(std::array::from_fn(|k| rows[k].0[c])))
+    }
+
+    #[inline]
+    pub fn reverse(v: f32x8) -> f32x8 {
+        let mut a = v.0;
+        a.reverse();
+        f32x8(a)
+    }
+
+    /// Prefetching is only a hint, so it does nothing here
+    #[inline]
+    pub fn prefetch(_p: *const f32x8, _offset: isize) {}
+}
+
+#[cfg(not(any(feature = "emulated-simd", not(target_arch = "x86_64"))))]
+use self::avx_backend as backend;
+#[cfg(any(feature = "emulated-simd", not(target_arch = "x86_64")))]
+use self::emulated_backend as backend;
+
+pub use self::backend::{f32x8, f32x8_infty, prefetch, reverse, transpose_8x8};
+
+impl f32x8 {
+    /// Element I, which must be less than 8
+    #[inline]
+    pub fn extract<const I: usize>(self) -> f32 {
+        const { assert!(I < f32x8_LENGTH, "invalid index for vector containing 8 elements") };
+        <[f32; 8]>::from(self)[I]
+    }
+}
+
+impl PartialEq for f32x8 {
+    fn eq(&self, other: &f32x8) -> bool {
+        <[f32; 8]>::from(*self) == <[f32; 8]>::from(*other)
+    }
+}
+
+impl fmt::Debug for f32x8 {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "f32x8({:?})", <[f32; 8]>::from(*self))
+    }
 }
 
 #[inline]
 pub fn add(v: f32x8, w: f32x8) -> f32x8 {
-    unsafe { _mm256_add_ps(v, w) }
+    v + w
 }
 
 #[inline]
 pub fn min(v: f32x8, w: f32x8) -> f32x8 {
-    unsafe { _mm256_min_ps(v, w) }
+    v.min(w)
+}
+
+/// Mask of the elements where v is less than w, for blendv
+#[inline]
+pub fn cmp_lt(v: f32x8, w: f32x8) -> f32x8 {
+    v.lt_mask(w)
+}
+
+/// Mask of the elements where v equals w, for blendv
+#[inline]
+pub fn cmp_eq(v: f32x8, w: f32x8) -> f32x8 {
+    v.eq_mask(w)
+}
+
+/// Take the elements of w where mask, returned by cmp_lt or cmp_eq, is set, and the elements of v elsewhere
+#[inline]
+pub fn blendv(v: f32x8, w: f32x8, mask: f32x8) -> f32x8 {
+    v.blendv(w, mask)
 }
 
 /// Extract the lowest 32 bits of a 256-bit vector as a float
 #[inline]
 pub fn lowestf32(v: f32x8) -> f32 {
-    unsafe { _mm256_cvtss_f32(v) }
+    v.lowest()
 }
 
 /// Create a 256-bit vector from a f32 slice of length 8
 #[inline]
 pub fn from_slice(s: &[f32]) -> f32x8 {
     assert_eq!(s.len(), f32x8_LENGTH);
-    unsafe { _mm256_set_ps(s[0], s[1], s[2], s[3], s[4], s[5], s[6], s[7]) }
+    let mut a = [0.0; f32x8_LENGTH];
+    a.copy_from_slice(s);
+    f32x8::from(a)
+}
+
+/// Like from_slice, but for slices of at most 8 elements, missing elements are f32::INFINITY
+#[inline]
+pub fn from_slice_padded(s: &[f32]) -> f32x8 {
+    if s.len() == f32x8_LENGTH {
+        return from_slice(s);
+    }
+    let mut tmp = [f32::INFINITY; f32x8_LENGTH];
+    tmp[..s.len()].copy_from_slice(s);
+    from_slice(&tmp)
+}
+
+/// Load at most 8 elements in memory order, i.e. s[0] into the lowest 32 bits,
+/// missing elements are f32::INFINITY
+#[inline]
+fn load_padded(s: &[f32]) -> f32x8 {
+    if s.len() == f32x8_LENGTH {
+        return f32x8::loadu(s);
+    }
+    let mut tmp = [f32::INFINITY; f32x8_LENGTH];
+    tmp[..s.len()].copy_from_slice(s);
+    f32x8::loadu(&tmp)
 }
 
-/// Permute 1, 2, or 4 wide chunks with adjacent chunks
-/// E.g.
-/// swap([0, 1, 2, 3, 4, 5, 6, 7], 1) == [1, 0, 3, 2, 5, 4, 7, 6]
-/// swap([0, 1, 2, 3, 4, 5, 6, 7], 2) == [2, 3, 0, 1, 6, 7, 4, 5]
-/// swap([0, 1, 2, 3, 4, 5, 6, 7], 4) == [4, 5, 6, 7, 0, 1, 2, 3]
+/// Pack 8 rows of the n×n row-major matrix d, starting at `row`, vertically into vectors:
+/// out[c] gets the elements at column col + c of the 8 rows, in the same order as from_slice would.
+/// Elements at rows >= n or columns >= col_end are f32::INFINITY.
 ///
-/// To make sense of the 8-bit shuffle control, read it in binary from right to left
-/// e.g. for width = 1, control is 10_11_00_01.
-/// Reading from right to left in 2 bit chunks we get (1, 0, 3, 2) for the 1st 128-bit lane,
-/// and (5, 4, 7, 6) for the 2nd 128-bit lane.
-/// Width 4 swaps both 128-bit lanes.
+/// The rows are read 8×8 blocks at a time, which are transposed in registers, instead of
+/// gathering each vector from 8 different rows one element at a time.
+pub fn pack_columns(d: &[f32], n: usize, row: usize, col: usize, col_end: usize, out: &mut [f32x8]) {
+    pack_columns_strided(d, Layout::dense(n), n, row, col, col_end, out);
+}
+
+/// Like pack_columns, but for the n×n matrix d stored with the given layout.
+/// If d is column-major, the 8 elements of each vector are contiguous and are loaded directly.
+pub fn pack_columns_strided(d: &[f32], layout: Layout, n: usize, row: usize, col: usize, col_end: usize, out: &mut [f32x8]) {
+    debug_assert!(col_end <= n);
+    let ld = layout.ld;
+    if layout.col_major {
+        for (j, v) in (col..).zip(out.iter_mut()) {
+            *v = if j < col_end && row < n {
+                from_slice_padded(&d[ld * j + row..ld * j + n.min(row + f32x8_LENGTH)])
+            } else {
+                f32x8_infty()
+            };
+        }
+        return;
+    }
+    for (c0, out_block) in (col..).step_by(f32x8_LENGTH).zip(out.chunks_mut(f32x8_LENGTH)) {
+        // from_slice puts the first element into the highest 32 bits,
+        // so the block is loaded from the last row to the first
+        let block_rows: [f32x8; 8] = std::array::from_fn(|b| {
+            let i = row + f32x8_LENGTH - 1 - b;
+            if i < n && c0 < col_end {
+                load_padded(&d[ld * i + c0..ld * i + col_end.min(c0 + f32x8_LENGTH)])
+            } else {
+                f32x8_infty()
+            }
+        });
+        let block_cols = transpose_8x8(block_rows);
+        out_block.copy_from_slice(&block_cols[..out_block.len()]);
+    }
+}
+
+/// Pack columns col..col + 8 of the n×n row-major matrix d as rows of f32x8 vectors,
+/// i.e. row c of out gets vector jv with the elements at rows 8 * jv.. of column col + c,
+/// in the same order as from_slice would.
+/// out contains at most 8 rows of vecs_per_row vectors, elements at columns >= n are f32::INFINITY.
+pub fn pack_transposed(d: &[f32], n: usize, col: usize, out: &mut [f32x8], vecs_per_row: usize) {
+    pack_rows_strided(d, Layout::col_major(n), n, col, out, vecs_per_row);
+}
+
+/// Pack rows row..row + 8 of the n×n matrix d, stored with the given layout, as rows of f32x8 vectors,
+/// i.e. row b of out gets vector jv with the elements at columns 8 * jv.. of row row + b.
+/// out contains at most 8 rows of vecs_per_row vectors, elements at rows >= n are f32::INFINITY.
+/// If d is column-major, the rows are packed by transposing 8×8 blocks as in pack_columns.
+pub fn pack_rows_strided(d: &[f32], layout: Layout, n: usize, row: usize, out: &mut [f32x8], vecs_per_row: usize) {
+    if layout.col_major {
+        let mut block = [f32x8_infty(); f32x8_LENGTH];
+        for jv in 0..vecs_per_row {
+            pack_columns_strided(d, layout.transposed(), n, jv * f32x8_LENGTH, row, n, &mut block);
+            for (out_row, &v) in out.chunks_exact_mut(vecs_per_row).zip(block.iter()) {
+                out_row[jv] = v;
+            }
+        }
+        return;
+    }
+    for (i, out_row) in (row..).zip(out.chunks_exact_mut(vecs_per_row)) {
+        if i < n {
+            let d_row = &d[layout.ld * i..layout.ld * i + n];
+            for (vx, chunk) in out_row.iter_mut().zip(d_row.chunks(f32x8_LENGTH)) {
+                *vx = from_slice_padded(chunk);
+            }
+        } else {
+            out_row.fill(f32x8_infty());
+        }
+    }
+}
+
+/// Rearrange the 8 intermediate results of an 8x8 block in v5, v6 and v7 into 8 result rows.
 ///
+/// After swapping elements of the odd vectors, result (i, j) of the block is element j of tmp[i ^ j],
+/// counting elements in the order of from_slice.
+/// Row i is gathered from all 8 vectors in three steps, each resolving one bit of i ^ j:
+/// blend element j of tmp[k] and tmp[k ^ 1] if bit 0 of j is set, then tmp[k] and tmp[k ^ 2]
+/// if bit 1 is set and tmp[k] and tmp[k ^ 4] if bit 2 is set.
+/// Finally, the elements are reversed so that element j of row i is at column j in memory order.
+#[inline]
+pub fn unpack_results(tmp: [f32x8; 8]) -> [f32x8; 8] {
+    // Blend masks select elements j with the bit set, element j is controlled by bit 7 - j
+    const BIT0: i32 = 0b_01_01_01_01;
+    const BIT1: i32 = 0b_00_11_00_11;
+    const BIT2: i32 = 0b_00_00_11_11;
+    let t: [f32x8; 8] = std::array::from_fn(|k| if k % 2 == 1 { swap::<1>(tmp[k]) } else { tmp[k] });
+    let s1: [f32x8; 8] = std::array::from_fn(|k| t[k].blend::<BIT0>(t[k ^ 1]));
+    let s2: [f32x8; 8] = std::array::from_fn(|k| s1[k].blend::<BIT1>(s1[k ^ 2]));
+    std::array::from_fn(|k| reverse(s2[k].blend::<BIT2>(s2[k ^ 4])))
+}
+
+/// Store the elements of v in memory order into dst,
+/// if dst is shorter than 8, the remaining elements are not stored
 #[inline]
-pub fn swap(v: f32x8, width: i8) -> f32x8 {
-    match width {
-        1 => unsafe { _mm256_permute_ps(v, 0b_10_11_00_01) },
-        2 => unsafe { _mm256_permute_ps(v, 0b_01_00_11_10) },
-        4 => unsafe { _mm256_permute2f128_ps(v, v, 1) },
-        _ => panic!("Invalid shuffle control for 256-bit vector, must be 1, 2, or 4"),
+pub fn store_padded(dst: &mut [f32], v: f32x8) {
+    if dst.len() >= f32x8_LENGTH {
+        v.storeu(dst);
+    } else if !dst.is_empty() {
+        v.store_partial(dst);
     }
 }
 
-// ANCHOR: prefetch
+/// Like store_padded, but store the minimum of each element of v and the element already in dst,
+/// for merging results into an existing matrix
 #[inline]
-pub fn prefetch(p: *const f32x8, offset: isize) {
-    unsafe { _mm_prefetch(p.offset(offset) as *const i8, _MM_HINT_T0) }
+pub fn min_store_padded(dst: &mut [f32], v: f32x8) {
+    let len = dst.len().min(f32x8_LENGTH);
+    let old = load_padded(&dst[..len]);
+    store_padded(dst, min(old, v));
 }
-// ANCHOR_END: prefetch
 
-/// Use an index to extract a single f32 from a 256-bit vector of single precision floats
+/// Permute W wide chunks with adjacent chunks, see f32x8::swap
 #[inline]
-pub fn extract(v: f32x8, i: u8) -> f32 {
-    // Create a permutation of v such that the 32 lowest bits correspond to the ith 32-bit chunk of v
-    let permuted = match i {
-        7 => v,
-        6 => swap(v, 1),
-        5 => swap(v, 2),
-        4 => swap(swap(v, 1), 2),
-        3 => swap(v, 4),
-        2 => swap(swap(v, 1), 4),
-        1 => swap(swap(v, 2), 4),
-        0 => swap(swap(swap(v, 1), 2), 4),
-        _ => panic!("Invalid index for vector containing 8 elements"),
-    };
-    // Extract the lowest 32 bits
-    lowestf32(permuted)
+pub fn swap<const W: usize>(v: f32x8) -> f32x8 {
+    v.swap::<W>()
+}
+
+/// Extract element I from a 256-bit vector of single precision floats, see f32x8::extract
+#[inline]
+pub fn extract<const I: usize>(v: f32x8) -> f32 {
+    v.extract::<I>()
 }
 
 /// Return the smallest element from a 256-bit float vector
 /// v              = [0, 1, 2, 3, 4, 5, 6, 7]
-/// swap(v, 1)     = [1, 0, 3, 2, 5, 4, 7, 6]
-/// min_1          = [0, 0, 2, 2, 4, 4, 6, 6]
-/// swap(min_1, 2) = [2, 2, 0, 0, 6, 6, 4, 4]
-/// min_2          = [0, 0, 0, 0, 4, 4, 4, 4]
-/// swap(min_2, 4) = [4, 4, 4, 4, 0, 0, 0, 0]
-/// min_4          = [0, 0, 0, 0, 0, 0, 0, 0]
+/// swap::<1>(v)     = [1, 0, 3, 2, 5, 4, 7, 6]
+/// min_1            = [0, 0, 2, 2, 4, 4, 6, 6]
+/// swap::<2>(min_1) = [2, 2, 0, 0, 6, 6, 4, 4]
+/// min_2            = [0, 0, 0, 0, 4, 4, 4, 4]
+/// swap::<4>(min_2) = [4, 4, 4, 4, 0, 0, 0, 0]
+/// min_4            = [0, 0, 0, 0, 0, 0, 0, 0]
 ///
 #[inline]
 pub fn horizontal_min(v: f32x8) -> f32 {
-    let min_1 = min(swap(v, 1), v);
-    let min_2 = min(swap(min_1, 2), min_1);
-    let min_4 = min(swap(min_2, 4), min_2);
+    let min_1 = min(swap::<1>(v), v);
+    let min_2 = min(swap::<2>(min_1), min_1);
+    let min_4 = min(swap::<4>(min_2), min_2);
     // All elements of min_4 are the minimum of v, extract the lowest 32 bits
     lowestf32(min_4)
 }
@@ -103,8 +602,7 @@ pub fn horizontal_min(v: f32x8) -> f32 {
 /// Print the contents of a 256-bit vector
 #[inline]
 pub fn print_vec(v: f32x8, padding: usize, precision: usize) {
-    for i in 0..f32x8_LENGTH as u8 {
-        let x: f32 = extract(v, i);
+    for x in <[f32; 8]>::from(v) {
         print!("{:padding$.precision$} ", x, padding=padding, precision=precision);
     }
 }
@@ -115,3 +613,96 @@ pub fn is_aligned(v: &f32x8) -> bool {
     (v as *const f32x8).align_offset(std::mem::align_of::<f32x8>()) == 0
 }
 // ANCHOR_END: assert_aligned
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Bit patterns of all elements, for comparing vectors exactly
+    fn to_bits(v: f32x8) -> [u32; 8] {
+        <[f32; 8]>::from(v).map(f32::to_bits)
+    }
+
+    /// n×n matrix with distinct elements, stored with the given layout, with NaN outside of the matrix
+    fn matrix(n: usize, layout: Layout) -> Vec<f32> {
+        let mut d = vec![f32::NAN; layout.len(n)];
+        for i in 0..n {
+            for j in 0..n {
+                d[layout.index(i, j)] = (n * i + j) as f32;
+            }
+        }
+        d
+    }
+
+    /// Row-major and column-major, both dense and with padding after each row or column
+    fn layouts(n: usize) -> [Layout; 4] {
+        [Layout::dense(n), Layout::col_major(n), Layout { ld: n + 3, col_major: false }, Layout { ld: n + 5, col_major: true }]
+    }
+
+    /// Ragged sizes around multiples of 8
+    const SIZES: [usize; 8] = [1, 2, 7, 8, 9, 15, 16, 17];
+
+    /// Element b of each vector gathered one at a time, as v3 to v7 packed before the 8×8 transposes
+    fn gather(elements: impl Fn(usize) -> Option<f32>) -> f32x8 {
+        let a: [f32; 8] = std::array::from_fn(|b| elements(b).unwrap_or(f32::INFINITY));
+        from_slice(&a)
+    }
+
+    #[test]
+    fn pack_columns_strided_equals_gather() {
+        for n in SIZES {
+            for layout in layouts(n) {
+                let d = matrix(n, layout);
+                for row in (0..n + 8).step_by(8) {
+                    for (col, col_end) in [(0, n), (0, n / 2), (n / 3, n), (n, n)] {
+                        let mut out = vec![f32x8_infty(); n + 9 - col];
+                        pack_columns_strided(&d, layout, n, row, col, col_end, &mut out);
+                        for (c, &v) in out.iter().enumerate() {
+                            let expected = gather(|b| {
+                                let (i, j) = (row + b, col + c);
+                                (i < n && j < col_end).then(|| d[layout.index(i, j)])
+                            });
+                            assert_eq!(to_bits(v), to_bits(expected), "n = {}, {:?}, row {}, column {}", n, layout, row, col + c);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn pack_rows_strided_equals_gather() {
+        for n in SIZES {
+            let vecs_per_row = n.div_ceil(f32x8_LENGTH);
+            for layout in layouts(n) {
+                let d = matrix(n, layout);
+                for row in (0..n + 8).step_by(8) {
+                    let mut out = vec![f32x8_infty(); f32x8_LENGTH * vecs_per_row];
+                    pack_rows_strided(&d, layout, n, row, &mut out, vecs_per_row);
+                    for (b, out_row) in out.chunks_exact(vecs_per_row).enumerate() {
+                        for (jv, &v) in out_row.iter().enumerate() {
+                            let expected = gather(|e| {
+                                let (i, j) = (row + b, f32x8_LENGTH * jv + e);
+                                (i < n && j < n).then(|| d[layout.index(i, j)])
+                            });
+                            assert_eq!(to_bits(v), to_bits(expected), "n = {}, {:?}, row {}, vector {}", n, layout, row + b, jv);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn pack_transposed_packs_columns() {
+        for n in SIZES {
+            let vecs_per_row = n.div_ceil(f32x8_LENGTH);
+            let d = matrix(n, Layout::dense(n));
+            let mut transposed = vec![f32x8_infty(); f32x8_LENGTH * vecs_per_row];
+            let mut col_major = transposed.clone();
+            pack_transposed(&d, n, 0, &mut transposed, vecs_per_row);
+            pack_rows_strided(&d, Layout::dense(n).transposed(), n, 0, &mut col_major, vecs_per_row);
+            assert_eq!(transposed, col_major, "n = {}", n);
+        }
+    }
+}
@@ -1,7 +1,24 @@
 use std::time;
 use std::vec;
-extern crate core;
 
+/// Value of the CPU timestamp counter
+#[cfg(target_arch = "x86_64")]
+fn timestamp() -> u64 {
+    unsafe { core::arch::x86_64::_rdtsc() }
+}
+
+/// Without a portable timestamp counter, estimate cycles as nanoseconds, i.e. cycles of a 1 GHz clock,
+/// since the first call
+#[cfg(not(target_arch = "x86_64"))]
+fn timestamp() -> u64 {
+    static START: std::sync::OnceLock<time::Instant> = std::sync::OnceLock::new();
+    START.get_or_init(time::Instant::now).elapsed().as_nanos() as u64
+}
+
+/// Unit of the values returned by timestamp
+const TIMESTAMP_UNIT: &str = if cfg!(target_arch = "x86_64") { "cycles" } else { "cycles (estimated from ns)" };
+
+#[derive(Default)]
 pub struct Stopwatch {
     instants: vec::Vec<time::Instant>,
 }
@@ -27,6 +44,7 @@ impl Stopwatch {
 
 // TODO generics or associated types or something
 
+#[derive(Default)]
 pub struct CycleCounter {
     instants: vec::Vec<u64>,
 }
@@ -37,15 +55,14 @@ impl CycleCounter {
     }
 
     pub fn click(&mut self) {
-        let cpu_timestamp = unsafe { core::arch::x86_64::_rdtsc() };
-        self.instants.push(cpu_timestamp);
+        self.instants.push(timestamp());
     }
 
     pub fn report(&self) {
         self.instants.as_slice().windows(2).enumerate().for_each(|(i, w)| {
             let (earlier, later) = (w[0], w[1]);
             let cycles = later - earlier;
-            println!("{}-{} : {} cycles", i, i+1, cycles);
+            println!("{}-{} : {} {}", i, i+1, cycles, TIMESTAMP_UNIT);
         });
     }
 }
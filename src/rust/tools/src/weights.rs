@@ -0,0 +1,25 @@
+//! Vertex weights, added to every path through a vertex: r[i][j] = min_k d[i][k] + w[k] + d[k][j].
+//!
+//! E.g. transfer penalties of public transport graphs are costs of the vertices instead of the edges.
+//! The SIMD versions add w[k] to column k of d while packing vd, so the kernels are unchanged.
+//! All versions compute (d[i][k] + w[k]) + d[k][j] in this order, so that the results are identical.
+use crate::simd::{self, f32x8};
+
+/// Panic if w does not have a weight for each of the n vertices
+pub fn check(w: &[f32], n: usize) {
+    assert_eq!(w.len(), n, "w must contain a weight for each of the n = {} vertices", n);
+}
+
+/// Add w to a row of vd packed by simd::pack_rows_strided, i.e. elements 8 * jv.. of w to vector jv
+pub fn add_to_row(vd_row: &mut [f32x8], w: &[f32]) {
+    for (v, w_chunk) in vd_row.iter_mut().zip(w.chunks(simd::f32x8_LENGTH)) {
+        *v = simd::add(*v, simd::from_slice_padded(w_chunk));
+    }
+}
+
+/// Add w to a row of vd packed by simd::pack_columns, i.e. w[k] to all elements of vector k
+pub fn add_to_columns(vd_row: &mut [f32x8], w: &[f32]) {
+    for (v, &wk) in vd_row.iter_mut().zip(w) {
+        *v = simd::add(*v, f32x8::from([wk; simd::f32x8_LENGTH]));
+    }
+}
@@ -1,19 +1,108 @@
-use tools::create_extern_c_wrapper;
+use tools::{create_extern_c_in_place_wrapper, create_extern_c_weighted_wrapper, create_extern_c_wrapper, kfilter::KFilter};
+use tools::par::prelude::*; // Parallel chunks iterator, backend is selected with cargo features
 
-#[cfg(not(feature = "no-multi-thread"))]
-extern crate rayon; // Data-parallelism library with a work-stealing approach
-#[cfg(not(feature = "no-multi-thread"))]
-use rayon::prelude::*; // Parallel chunks iterator
 
+#[inline]
+pub fn step(r: &mut [f32], d: &[f32], n: usize) {
+    step_into(r, d, n, false);
+}
 
+/// Like step, but fold the new results into the existing contents of r,
+/// i.e. r[i][j] becomes the minimum of r[i][j] and min_k d[i][k] + d[k][j],
+/// e.g. for iterative algorithms repeating the step on the results
 #[inline]
-fn _step(r: &mut [f32], d: &[f32], n: usize) {
+pub fn step_accumulate(r: &mut [f32], d: &[f32], n: usize) {
+    step_into(r, d, n, true);
+}
+
+/// Like step, but d is read from rd, which is then overwritten with the results.
+/// Every result needs a whole row and column of d, so d is copied first.
+pub fn step_in_place(rd: &mut [f32], n: usize) {
+    let d = rd.to_vec();
+    step(rd, &d, n);
+}
+
+/// Like step, but minimize only over the intermediate nodes k allowed by filter, see tools::kfilter.
+/// All k are visited, skipping the ones not in the mask of the filter.
+pub fn step_filtered(r: &mut [f32], d: &[f32], n: usize, filter: &KFilter) {
+    filter.check(n);
+    if n == 0 {
+        return;
+    }
+    let mask = filter.mask(n);
+    let step_row = |(i, r_row): (usize, &mut [f32])| {
+        for (j, res) in r_row.iter_mut().enumerate() {
+            let mut v = f32::INFINITY;
+            for k in 0..n {
+                if filter.excludes(&mask, i, k) || filter.excludes(&mask, j, k) {
+                    continue;
+                }
+                v = v.min(d[n*i + k] + d[n*k + j]);
+            }
+            *res = v;
+        }
+    };
+    r.par_chunks_mut(n)
+        .enumerate()
+        .for_each(step_row);
+}
+
+/// Like step, but with a weight w[k] for each vertex k added to the paths through it,
+/// i.e. r[i][j] = min_k d[i][k] + w[k] + d[k][j], see tools::weights
+pub fn step_weighted(r: &mut [f32], d: &[f32], w: &[f32], n: usize) {
+    tools::weights::check(w, n);
+    if n == 0 {
+        return;
+    }
+    let step_row = |(i, r_row): (usize, &mut [f32])| {
+        for (j, res) in r_row.iter_mut().enumerate() {
+            let mut v = f32::INFINITY;
+            for k in 0..n {
+                v = v.min(d[n*i + k] + w[k] + d[n*k + j]);
+            }
+            *res = v;
+        }
+    };
+    r.par_chunks_mut(n)
+        .enumerate()
+        .for_each(step_row);
+}
+
+/// Like step, but each element is a pair (distance, cost), given as the separate matrices of distances and costs,
+/// and the pairs are minimized in lexicographic order, see tools::lexicographic.
+/// This is the scalar reference for v5_more_register_reuse::step_lexicographic.
+pub fn step_lexicographic(r_dist: &mut [f32], r_cost: &mut [f32], d_dist: &[f32], d_cost: &[f32], n: usize) {
+    tools::lexicographic::check(d_dist, d_cost, n);
+    if n == 0 {
+        return;
+    }
+    let step_row = |(i, (r_dist_row, r_cost_row)): (usize, (&mut [f32], &mut [f32]))| {
+        for (j, (res_dist, res_cost)) in r_dist_row.iter_mut().zip(r_cost_row.iter_mut()).enumerate() {
+            let mut v = (f32::INFINITY, f32::INFINITY);
+            for k in 0..n {
+                let z = (d_dist[n*i + k] + d_dist[n*k + j], d_cost[n*i + k] + d_cost[n*k + j]);
+                v = tools::lexicographic::min_pair(v, z);
+            }
+            (*res_dist, *res_cost) = v;
+        }
+    };
+    r_dist.par_chunks_mut(n)
+        .zip(r_cost.par_chunks_mut(n))
+        .enumerate()
+        .for_each(step_row);
+}
+
+/// Compute the results into r, starting from the existing contents of r if accumulate is true
+fn step_into(r: &mut [f32], d: &[f32], n: usize, accumulate: bool) {
+    if n == 0 {
+        return;
+    }
     // ANCHOR: step_row
     // Function: for some row i and every column j in d,
     // compute n results into r (r_row)
     let step_row = |(i, r_row): (usize, &mut [f32])| {
         for (j, res) in r_row.iter_mut().enumerate() {
-            let mut v = std::f32::INFINITY;
+            let mut v = if accumulate { *res } else { f32::INFINITY };
             for k in 0..n {
                 let x = d[n*i + k];
                 let y = d[n*k + j];
@@ -26,23 +115,18 @@ fn _step(r: &mut [f32], d: &[f32], n: usize) {
     // ANCHOR_END: step_row
     // Partition r into slices, each containing a single row and apply the function on the rows
     // ANCHOR: chunks
-    #[cfg(not(feature = "no-multi-thread"))] // Process each row as a separate task in parallel
+    // Process each row as a separate task, in parallel unless built with 'no-multi-thread'
     //// ANCHOR: par_chunks
     r.par_chunks_mut(n)
         .enumerate()
         .for_each(step_row);
     //// ANCHOR_END: par_chunks
-    #[cfg(feature = "no-multi-thread")] // Process all rows in the main thread
-    //// ANCHOR: seq_chunks
-    //// ANCHOR: seq_chunks_mut
-    r.chunks_mut(n)
-    //// ANCHOR_END: seq_chunks_mut
-        .enumerate()
-        .for_each(step_row);
-    //// ANCHOR_END: seq_chunks
     // ANCHOR_END: chunks
 }
 
 // ANCHOR: extern_macro_call
-create_extern_c_wrapper!(step, _step);
+create_extern_c_wrapper!(step, step);
+create_extern_c_wrapper!(step_accumulate, step_accumulate);
+create_extern_c_in_place_wrapper!(step_in_place, step_in_place);
+create_extern_c_weighted_wrapper!(step_weighted, step_weighted);
 // ANCHOR_END: extern_macro_call
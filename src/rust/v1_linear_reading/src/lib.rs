@@ -1,13 +1,91 @@
-use tools::{create_extern_c_wrapper, min};
+use tools::{create_extern_c_in_place_wrapper, create_extern_c_weighted_wrapper, create_extern_c_wrapper, kfilter::KFilter, min};
+use tools::par::prelude::*;
 
-#[cfg(not(feature = "no-multi-thread"))]
-extern crate rayon;
-#[cfg(not(feature = "no-multi-thread"))]
-use rayon::prelude::*;
 
+#[inline]
+pub fn step(r: &mut [f32], d: &[f32], n: usize) {
+    step_into(r, d, n, false);
+}
 
+/// Like step, but r becomes min(r, d ⊗ d), by starting each fold from the existing result
 #[inline]
-fn _step(r: &mut [f32], d: &[f32], n: usize) {
+pub fn step_accumulate(r: &mut [f32], d: &[f32], n: usize) {
+    step_into(r, d, n, true);
+}
+
+/// Like step, but d is read from rd, which is then overwritten with the results.
+/// The rows of d are read while computing, so d is copied first.
+pub fn step_in_place(rd: &mut [f32], n: usize) {
+    let d = rd.to_vec();
+    step(rd, &d, n);
+}
+
+/// Like step, but minimize only over the intermediate nodes k allowed by filter, see tools::kfilter.
+/// The rows are read from a copy of d and the columns from its transpose, with the elements
+/// not in the mask of the filter replaced by f32::INFINITY.
+pub fn step_filtered(r: &mut [f32], d: &[f32], n: usize, filter: &KFilter) {
+    filter.check(n);
+    if n == 0 {
+        return;
+    }
+    let mask = filter.mask(n);
+    let mut vd = std::vec![0.0; n * n];
+    let mut t = std::vec![0.0; n * n];
+    let mask_row_and_column = |(i, (vd_row, t_row)): (usize, (&mut [f32], &mut [f32]))| {
+        for (k, (x, y)) in vd_row.iter_mut().zip(t_row.iter_mut()).enumerate() {
+            let excluded = filter.excludes(&mask, i, k);
+            *x = if excluded { f32::INFINITY } else { d[n*i + k] };
+            *y = if excluded { f32::INFINITY } else { d[n*k + i] };
+        }
+    };
+    vd.par_chunks_mut(n)
+        .zip(t.par_chunks_mut(n))
+        .enumerate()
+        .for_each(mask_row_and_column);
+    let step_row = |(r_row, vd_row): (&mut [f32], &[f32])| {
+        for (res, t_row) in r_row.iter_mut().zip(t.chunks_exact(n)) {
+            *res = vd_row.iter()
+                         .zip(t_row)
+                         .fold(f32::INFINITY, |v, (&x, &y)| min(v, x + y));
+        }
+    };
+    r.par_chunks_mut(n)
+        .zip(vd.par_chunks(n))
+        .for_each(step_row);
+}
+
+/// Like step, but with a weight w[k] for each vertex k added to the paths through it, see tools::weights.
+/// The weights are read along with each row of d.
+pub fn step_weighted(r: &mut [f32], d: &[f32], w: &[f32], n: usize) {
+    tools::weights::check(w, n);
+    if n == 0 {
+        return;
+    }
+    let mut t = std::vec![0.0; n * n];
+    t.par_chunks_mut(n)
+        .enumerate()
+        .for_each(|(j, t_row)| {
+            for (i, x) in t_row.iter_mut().enumerate() {
+                *x = d[n*i + j];
+            }
+        });
+    let step_row = |(r_row, d_row): (&mut [f32], &[f32])| {
+        for (res, t_row) in r_row.iter_mut().zip(t.chunks_exact(n)) {
+            *res = d_row.iter()
+                        .zip(w)
+                        .zip(t_row)
+                        .fold(f32::INFINITY, |v, ((&x, &wk), &y)| min(v, x + wk + y));
+        }
+    };
+    r.par_chunks_mut(n)
+        .zip(d.par_chunks(n))
+        .for_each(step_row);
+}
+
+fn step_into(r: &mut [f32], d: &[f32], n: usize, accumulate: bool) {
+    if n == 0 {
+        return;
+    }
     // ANCHOR: transpose
     // Transpose of d
     let mut t = std::vec![0.0; n * n];
@@ -20,16 +98,11 @@ fn _step(r: &mut [f32], d: &[f32], n: usize) {
     };
     // Copy all columns of d into rows of t in parallel
     // ANCHOR_END: transpose
-    #[cfg(not(feature = "no-multi-thread"))]
     // ANCHOR: transpose_apply
     t.par_chunks_mut(n)
         .enumerate()
         .for_each(transpose_column);
     // ANCHOR_END: transpose_apply
-    #[cfg(feature = "no-multi-thread")]
-    t.chunks_mut(n)
-        .enumerate()
-        .for_each(transpose_column);
     // ANCHOR: step_row
     // Function: for some row i in d (d_row) and all rows t (t_rows),
     // compute n results into a row in r (r_row)
@@ -37,26 +110,25 @@ fn _step(r: &mut [f32], d: &[f32], n: usize) {
         let t_rows = t.chunks_exact(n);
         // ANCHOR: step_row_inner
         for (res, t_row) in r_row.iter_mut().zip(t_rows) {
+            let init = if accumulate { *res } else { f32::INFINITY };
             *res = d_row.iter()
                         .zip(t_row)
-                        .fold(std::f32::INFINITY, |v, (&x, &y)| min(v, x + y));
+                        .fold(init, |v, (&x, &y)| min(v, x + y));
         }
         // ANCHOR_END: step_row_inner
     };
     // Partition r and d into slices, each containing a single row of r and d,
     // and apply the function on the row pairs
     // ANCHOR_END: step_row
-    #[cfg(not(feature = "no-multi-thread"))]
     // ANCHOR: step_row_apply
     r.par_chunks_mut(n)
         .zip(d.par_chunks(n))
         .for_each(step_row);
     // ANCHOR_END: step_row_apply
-    #[cfg(feature = "no-multi-thread")]
-    r.chunks_mut(n)
-        .zip(d.chunks(n))
-        .for_each(step_row);
 }
 
 
-create_extern_c_wrapper!(step, _step);
+create_extern_c_wrapper!(step, step);
+create_extern_c_wrapper!(step_accumulate, step_accumulate);
+create_extern_c_in_place_wrapper!(step_in_place, step_in_place);
+create_extern_c_weighted_wrapper!(step_weighted, step_weighted);
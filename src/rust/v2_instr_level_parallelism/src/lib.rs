@@ -1,21 +1,98 @@
-use tools::{create_extern_c_wrapper, min};
+use tools::{create_extern_c_in_place_wrapper, create_extern_c_weighted_wrapper, create_extern_c_wrapper, kfilter::KFilter, min, params::Params};
+use tools::par::prelude::*;
 
-#[cfg(not(feature = "no-multi-thread"))]
-extern crate rayon;
-#[cfg(not(feature = "no-multi-thread"))]
-use rayon::prelude::*;
 
+#[inline]
+pub fn step(r: &mut [f32], d: &[f32], n: usize) {
+    step_with_params(r, d, n, tools::params::get());
+}
+
+/// Like step, but with params.block_size independent accumulators instead of the tuned amount
+pub fn step_with_params(r: &mut [f32], d: &[f32], n: usize, params: &Params) {
+    step_into(r, d, n, params, false);
+}
+
+/// Like step, but r becomes min(r, d ⊗ d), by folding the accumulators into the existing result
+#[inline]
+pub fn step_accumulate(r: &mut [f32], d: &[f32], n: usize) {
+    step_accumulate_with_params(r, d, n, tools::params::get());
+}
+
+pub fn step_accumulate_with_params(r: &mut [f32], d: &[f32], n: usize, params: &Params) {
+    step_into(r, d, n, params, true);
+}
 
+/// Like step, but d is read from rd, which is then overwritten with the results.
+/// Only v3 to v7 compute without a copy of d, here d is copied first as in v0 and v1.
 #[inline]
-fn _step(r: &mut [f32], d: &[f32], n: usize) {
+pub fn step_in_place(rd: &mut [f32], n: usize) {
+    step_in_place_with_params(rd, n, tools::params::get());
+}
+
+pub fn step_in_place_with_params(rd: &mut [f32], n: usize, params: &Params) {
+    let d = rd.to_vec();
+    step_with_params(rd, &d, n, params);
+}
+
+/// Like step, but minimize only over the intermediate nodes k allowed by filter, see tools::kfilter
+#[inline]
+pub fn step_filtered(r: &mut [f32], d: &[f32], n: usize, filter: &KFilter) {
+    step_filtered_with_params(r, d, n, filter, tools::params::get());
+}
+
+/// Like step_with_params, but with the elements not in the mask of the filter replaced by f32::INFINITY in vd and vt
+pub fn step_filtered_with_params(r: &mut [f32], d: &[f32], n: usize, filter: &KFilter, params: &Params) {
+    filter.check(n);
+    if n == 0 {
+        return;
+    }
+    step_into_with(r, d, n, params, filter, None, false);
+}
+
+/// Like step, but with a weight w[k] for each vertex k added to the paths through it, see tools::weights
+#[inline]
+pub fn step_weighted(r: &mut [f32], d: &[f32], w: &[f32], n: usize) {
+    step_weighted_with_params(r, d, w, n, tools::params::get());
+}
+
+/// Like step_with_params, but with w[k] added to column k of vd
+pub fn step_weighted_with_params(r: &mut [f32], d: &[f32], w: &[f32], n: usize, params: &Params) {
+    tools::weights::check(w, n);
+    if n == 0 {
+        return;
+    }
+    step_into_with(r, d, n, params, &KFilter::ALL, Some(w), false);
+}
+
+fn step_into(r: &mut [f32], d: &[f32], n: usize, params: &Params, accumulate: bool) {
+    step_into_with(r, d, n, params, &KFilter::ALL, None, accumulate);
+}
+
+/// Compute the results over the intermediate nodes of filter, with the vertex weights w if given
+fn step_into_with(r: &mut [f32], d: &[f32], n: usize, params: &Params, filter: &KFilter, w: Option<&[f32]>, accumulate: bool) {
+    if n == 0 {
+        return;
+    }
+    // The block size determines the size of an array, so each supported value has its own instance
+    match params.block_size {
+        1 => step_blocks::<1>(r, d, n, filter, w, accumulate),
+        2 => step_blocks::<2>(r, d, n, filter, w, accumulate),
+        4 => step_blocks::<4>(r, d, n, filter, w, accumulate),
+        8 => step_blocks::<8>(r, d, n, filter, w, accumulate),
+        16 => step_blocks::<16>(r, d, n, filter, w, accumulate),
+        b => panic!("unsupported block size {}, must be one of {:?}", b, tools::params::BLOCK_SIZES),
+    }
+}
+
+#[inline]
+fn step_blocks<const BLOCK_SIZE: usize>(r: &mut [f32], d: &[f32], n: usize, filter: &KFilter, w: Option<&[f32]>, accumulate: bool) {
     // ANCHOR: preprocess
-    const BLOCK_SIZE: usize = 4;
-    let blocks_per_row = (n + BLOCK_SIZE - 1) / BLOCK_SIZE;
+    let blocks_per_row = n.div_ceil(BLOCK_SIZE);
     let n_padded = blocks_per_row * BLOCK_SIZE;
     // d and transpose of d with extra room at the end of each row,
     // both initially filled with f32::INFINITY
-    let mut vd = std::vec![std::f32::INFINITY; n_padded * n];
-    let mut vt = std::vec![std::f32::INFINITY; n_padded * n];
+    let mut vd = std::vec![f32::INFINITY; n_padded * n];
+    let mut vt = std::vec![f32::INFINITY; n_padded * n];
     // Function: for one row of vd and vt,
     // copy a row at 'i' of d into vd and column at 'i' of d into vt
     let preprocess_row = |(i, (vd_row, vt_row)): (usize, (&mut [f32], &mut [f32]))| {
@@ -28,18 +105,36 @@ fn _step(r: &mut [f32], d: &[f32], n: usize) {
     };
     // Partition vd and vt into rows, apply preprocessing in parallel for each row pair
     // ANCHOR_END: preprocess
-    #[cfg(not(feature = "no-multi-thread"))]
     // ANCHOR: preprocess_apply
     vd.par_chunks_mut(n_padded)
         .zip(vt.par_chunks_mut(n_padded))
         .enumerate()
         .for_each(preprocess_row);
     // ANCHOR_END: preprocess_apply
-    #[cfg(feature = "no-multi-thread")]
-    vd.chunks_mut(n_padded)
-        .zip(vt.chunks_mut(n_padded))
-        .enumerate()
-        .for_each(preprocess_row);
+    // Elements on no path through an intermediate node allowed by the filter are never the minimum as f32::INFINITY
+    if !filter.is_all() {
+        let mask = filter.mask(n);
+        vd.par_chunks_mut(n_padded)
+            .zip(vt.par_chunks_mut(n_padded))
+            .enumerate()
+            .for_each(|(i, (vd_row, vt_row))| {
+                for (k, (x, y)) in vd_row.iter_mut().zip(vt_row.iter_mut()).enumerate().take(n) {
+                    if filter.excludes(&mask, i, k) {
+                        *x = f32::INFINITY;
+                        *y = f32::INFINITY;
+                    }
+                }
+            });
+    }
+    // Vertex weights are added to the columns of vd, i.e. each path through k gets w[k] after d[i][k]
+    if let Some(w) = w {
+        vd.par_chunks_mut(n_padded)
+            .for_each(|vd_row| {
+                for (x, &wk) in vd_row.iter_mut().zip(w) {
+                    *x += wk;
+                }
+            });
+    }
     // ANCHOR: step_row
     // Function: for some row in vd (vd_row) and all rows in vt (vt_rows),
     // compute all results for a row in r (r_row), corresponding to the row index of vd_row.
@@ -47,35 +142,35 @@ fn _step(r: &mut [f32], d: &[f32], n: usize) {
         let vt_rows = vt.chunks_exact(n_padded);
         // Length of a zipped iterator is the length of the shorter iterator in the zip pair so this never exceeds n
         for (res, vt_row) in r_row.iter_mut().zip(vt_rows) {
-            // Partition both rows into chunks of size 4
+            // Partition both rows into chunks of size BLOCK_SIZE, e.g. for 4
             // (x0, x1, x2, x3), (x4, x5, x6, x7), ...
             let vd_blocks = vd_row.chunks_exact(BLOCK_SIZE);
             // (y0, y1, y2, y3), (y4, y5, y6, y7), ...
             let vt_blocks = vt_row.chunks_exact(BLOCK_SIZE);
             // Using an array here is bit more convenient than 4 different variables, e.g. v0, v1, v2, v3
-            let mut block = [std::f32::INFINITY; BLOCK_SIZE];
-            // Accumulate all results as in v1, but 4 elements at a time
+            let mut block = [f32::INFINITY; BLOCK_SIZE];
+            // Accumulate all results as in v1, but BLOCK_SIZE elements at a time
             for (vd_block, vt_block) in vd_blocks.zip(vt_blocks) {
                 for (b, (&x, &y)) in block.iter_mut().zip(vd_block.iter().zip(vt_block)) {
                     *b = min(*b, x + y);
                 }
             }
-            // Fold 4 intermediate values into a single minimum and assign to final result
-            *res = block.iter().fold(std::f32::INFINITY, |acc, &x| min(acc, x));
+            // Fold BLOCK_SIZE intermediate values into a single minimum and assign to final result,
+            // or into the existing result when accumulating
+            let init = if accumulate { *res } else { f32::INFINITY };
+            *res = block.iter().fold(init, |acc, &x| min(acc, x));
         }
     };
     // ANCHOR_END: step_row
-    #[cfg(not(feature = "no-multi-thread"))]
     // ANCHOR: step_row_apply
     r.par_chunks_mut(n)
         .zip(vd.par_chunks(n_padded))
         .for_each(step_row);
     // ANCHOR_END: step_row_apply
-    #[cfg(feature = "no-multi-thread")]
-    r.chunks_mut(n)
-        .zip(vd.chunks(n_padded))
-        .for_each(step_row);
 }
 
 
-create_extern_c_wrapper!(step, _step);
+create_extern_c_wrapper!(step, step);
+create_extern_c_wrapper!(step_accumulate, step_accumulate);
+create_extern_c_in_place_wrapper!(step_in_place, step_in_place);
+create_extern_c_weighted_wrapper!(step_weighted, step_weighted);
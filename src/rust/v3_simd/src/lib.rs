@@ -1,16 +1,157 @@
-use tools::{create_extern_c_wrapper, simd, simd::f32x8};
+use tools::{create_extern_c_in_place_wrapper, create_extern_c_strided_wrapper, create_extern_c_weighted_wrapper, create_extern_c_wrapper, kfilter::{self, KFilter}, layout::Layout, simd, simd::f32x8};
+use tools::par::prelude::*;
 
-#[cfg(not(feature = "no-multi-thread"))]
-extern crate rayon;
-#[cfg(not(feature = "no-multi-thread"))]
-use rayon::prelude::*;
 
+#[inline]
+pub fn step(r: &mut [f32], d: &[f32], n: usize) {
+    step_strided(r, Layout::dense(n), d, Layout::dense(n), n);
+}
+
+/// Like step, but for r and d stored with the given layouts, see tools::layout
+#[inline]
+pub fn step_strided(r: &mut [f32], r_layout: Layout, d: &[f32], d_layout: Layout, n: usize) {
+    step_into(r, r_layout, d, d_layout, n, false);
+}
 
+/// Like step, but r becomes min(r, d ⊗ d), merging each result into r when it is written
 #[inline]
-fn _step(r: &mut [f32], d: &[f32], n: usize) {
+pub fn step_accumulate(r: &mut [f32], d: &[f32], n: usize) {
+    step_into(r, Layout::dense(n), d, Layout::dense(n), n, true);
+}
+
+fn step_into(r: &mut [f32], r_layout: Layout, d: &[f32], d_layout: Layout, n: usize, accumulate: bool) {
+    r_layout.check(n, r.len(), "r");
+    d_layout.check(n, d.len(), "d");
+    if n == 0 {
+        return;
+    }
+    // A column-major r is computed as the row-major result of d transposed
+    let d_layout = if r_layout.col_major { d_layout.transposed() } else { d_layout };
+    let (vd, vt) = pack_simd(d, d_layout, n, &KFilter::ALL);
+    step_rows(&mut r[..r_layout.len(n)], r_layout.ld, &vd, &vt, n.div_ceil(simd::f32x8_LENGTH), accumulate);
+}
+
+/// Like step, but minimize only over the intermediate nodes k allowed by filter, see tools::kfilter
+pub fn step_filtered(r: &mut [f32], d: &[f32], n: usize, filter: &KFilter) {
+    assert_eq!(r.len(), n * n, "r must have room for the n×n results");
+    assert_eq!(d.len(), n * n, "d must contain the n×n input");
+    filter.check(n);
+    if filter.len(n) == 0 {
+        // No intermediate nodes, so there are no paths
+        return r.fill(f32::INFINITY);
+    }
+    let (vd, vt) = pack_simd(d, Layout::dense(n), n, filter);
+    step_rows(r, n, &vd, &vt, filter.len(n).div_ceil(simd::f32x8_LENGTH), false);
+}
+
+/// Like step, but d is read from rd, which is then overwritten with the results
+pub fn step_in_place(rd: &mut [f32], n: usize) {
+    assert_eq!(rd.len(), n * n, "rd must contain the n×n matrix d");
+    if n == 0 {
+        return;
+    }
+    let (vd, vt) = pack_simd(rd, Layout::dense(n), n, &KFilter::ALL);
+    step_rows(rd, n, &vd, &vt, n.div_ceil(simd::f32x8_LENGTH), false);
+}
+
+/// Like step, but compute only the rows of r at the given indexes, row rows[k] into row k of r_rows.
+/// All columns of d are packed into vt as usual, but only the requested rows into vd.
+pub fn step_row_subset(r_rows: &mut [f32], d: &[f32], n: usize, rows: &[usize]) {
+    assert_eq!(d.len(), n * n, "d must contain the n×n input");
+    assert_eq!(r_rows.len(), rows.len() * n, "r_rows must contain one row of r for each row index");
+    assert!(rows.iter().all(|&i| i < n), "row indexes must be less than n");
+    if r_rows.is_empty() {
+        return;
+    }
+    let vecs_per_row = n.div_ceil(simd::f32x8_LENGTH);
+    let vd = pack_selected(d, Layout::dense(n), n, rows);
+    // All columns are packed 8 at a time as in pack_simd
+    let mut vt = std::vec![simd::f32x8_infty(); n * vecs_per_row];
+    vt.par_chunks_mut(simd::f32x8_LENGTH * vecs_per_row)
+        .enumerate()
+        .for_each(|(i, vt_rows)| {
+            simd::pack_rows_strided(d, Layout::col_major(n), n, i * simd::f32x8_LENGTH, vt_rows, vecs_per_row)
+        });
+    step_rows(r_rows, n, &vd, &vt, vecs_per_row, false);
+}
+
+/// Compute only the results r[i][j] of the given (i, j) pairs into results, in the same order.
+/// Each row i and column j of d used by some pair is packed once, into vd and vt respectively.
+pub fn step_pairs(results: &mut [f32], d: &[f32], n: usize, pairs: &[(usize, usize)]) {
+    assert_eq!(d.len(), n * n, "d must contain the n×n input");
+    assert_eq!(results.len(), pairs.len(), "results must have one element for each pair");
+    assert!(pairs.iter().all(|&(i, j)| i < n && j < n), "row and column indexes must be less than n");
+    // Also if n is 0, since there can be no pairs then
+    if pairs.is_empty() {
+        return;
+    }
+    let distinct = |index: fn(&(usize, usize)) -> usize| {
+        let mut indexes: Vec<usize> = pairs.iter().map(index).collect();
+        indexes.sort_unstable();
+        indexes.dedup();
+        indexes
+    };
+    let (rows, cols) = (distinct(|p| p.0), distinct(|p| p.1));
+    let vd = pack_selected(d, Layout::dense(n), n, &rows);
+    let vt = pack_selected(d, Layout::col_major(n), n, &cols);
+    let vecs_per_row = n.div_ceil(simd::f32x8_LENGTH);
+    let step_pair = |(res, &(i, j)): (&mut f32, &(usize, usize))| {
+        // Both are found, since rows and cols contain the indexes of all pairs
+        let vd_row = rows.binary_search(&i).unwrap() * vecs_per_row;
+        let vt_row = cols.binary_search(&j).unwrap() * vecs_per_row;
+        *res = min_plus_rows(&vd[vd_row..vd_row + vecs_per_row], &vt[vt_row..vt_row + vecs_per_row]);
+    };
+    results.par_iter_mut()
+        .zip(pairs.par_iter())
+        .for_each(step_pair);
+}
+
+/// Pack the rows of d at the given indexes into rows of f32x8 vectors, as pack_simd packs all rows,
+/// or columns if d_layout is transposed
+fn pack_selected(d: &[f32], d_layout: Layout, n: usize, rows: &[usize]) -> Vec<f32x8> {
+    let vecs_per_row = n.div_ceil(simd::f32x8_LENGTH);
+    let mut v = std::vec![simd::f32x8_infty(); rows.len() * vecs_per_row];
+    let pack_row = |(v_row, &i): (&mut [f32x8], &usize)| {
+        if !d_layout.col_major {
+            return simd::pack_rows_strided(d, d_layout, n, i, v_row, vecs_per_row);
+        }
+        // pack_rows_strided would transpose 8 rows at a time to keep only one of them,
+        // so the elements of a single row are gathered directly
+        for (jv, vx) in v_row.iter_mut().enumerate() {
+            let elements: [f32; simd::f32x8_LENGTH] = std::array::from_fn(|b| {
+                let k = jv * simd::f32x8_LENGTH + b;
+                if k < n { d[d_layout.index(i, k)] } else { f32::INFINITY }
+            });
+            *vx = simd::from_slice(&elements);
+        }
+    };
+    v.par_chunks_mut(vecs_per_row)
+        .zip(rows.par_iter())
+        .for_each(pack_row);
+    v
+}
+
+/// Like step, but with a weight w[k] for each vertex k added to the paths through it, see tools::weights
+pub fn step_weighted(r: &mut [f32], d: &[f32], w: &[f32], n: usize) {
+    assert_eq!(r.len(), n * n, "r must have room for the n×n results");
+    assert_eq!(d.len(), n * n, "d must contain the n×n input");
+    tools::weights::check(w, n);
+    if n == 0 {
+        return;
+    }
+    let vecs_per_row = n.div_ceil(simd::f32x8_LENGTH);
+    let (mut vd, vt) = pack_simd(d, Layout::dense(n), n, &KFilter::ALL);
+    vd.par_chunks_mut(vecs_per_row)
+        .for_each(|vd_row| tools::weights::add_to_row(vd_row, w));
+    step_rows(r, n, &vd, &vt, vecs_per_row, false);
+}
+
+/// Pack the rows of d into vd and the columns of d into vt, both with n rows of f32x8 vectors,
+/// containing only the elements at the intermediate nodes of filter
+fn pack_simd(d: &[f32], d_layout: Layout, n: usize, filter: &KFilter) -> (Vec<f32x8>, Vec<f32x8>) {
     // ANCHOR: init
     // How many f32x8 vectors we need for all elements from a row or column of d
-    let vecs_per_row = (n + simd::f32x8_LENGTH - 1) / simd::f32x8_LENGTH;
+    let vecs_per_row = filter.len(n).div_ceil(simd::f32x8_LENGTH);
     // All rows and columns d packed into f32x8 vectors,
     // each initially filled with 8 f32::INFINITYs
     let mut vd = std::vec![simd::f32x8_infty(); n * vecs_per_row];
@@ -20,78 +161,71 @@ fn _step(r: &mut [f32], d: &[f32], n: usize) {
     debug_assert!(vt.iter().all(simd::is_aligned));
     // ANCHOR_END: init
     // ANCHOR: preprocess
-    // Function: for one row of f32x8 vectors in vd and one row of f32x8 vectors in vt,
-    // - copy all elements from row 'i' in d,
-    // - pack them into f32x8 vectors,
-    // - insert all into row 'i' of vd (vd_row)
-    // and
-    // - copy all elements from column 'i' in d,
-    // - pack them into f32x8 vectors,
-    // - insert all into row 'i' of vt (vt_row)
-    let pack_simd_row = |(i, (vd_row, vt_row)): (usize, (&mut [f32x8], &mut [f32x8]))| {
-        // For every SIMD vector at row 'i', column 'jv' in vt and vd
-        for (jv, (vx, vy)) in vd_row.iter_mut().zip(vt_row.iter_mut()).enumerate() {
-            // Temporary buffers for f32 elements of two f32x8s
-            let mut vx_tmp = [std::f32::INFINITY; simd::f32x8_LENGTH];
-            let mut vy_tmp = [std::f32::INFINITY; simd::f32x8_LENGTH];
-            // Iterate over 8 elements to fill the buffers
-            for (b, (x, y)) in vx_tmp.iter_mut().zip(vy_tmp.iter_mut()).enumerate() {
-                // Offset by 8 elements to get correct index mapping of j to d
-                let j = jv * simd::f32x8_LENGTH + b;
-                if i < n && j < n {
-                    *x = d[n * i + j];
-                    *y = d[n * j + i];
-                }
-            }
-            // Initialize f32x8 vectors from buffer contents
-            // and assign them into the std::vec::Vec containers
-            *vx = simd::from_slice(&vx_tmp);
-            *vy = simd::from_slice(&vy_tmp);
-        }
+    // Function: for 8 rows of f32x8 vectors in vd, starting at row 'i' * 8,
+    // - copy all elements from rows 'i' * 8 .. 'i' * 8 + 8 in d,
+    // - pack them 8 at a time into f32x8 vectors, padding the last one with f32::INFINITY,
+    // - insert all into the 8 rows of vd (vd_rows)
+    let pack_simd_rows = |(i, vd_rows): (usize, &mut [f32x8])| {
+        kfilter::pack_rows(d, d_layout, n, filter, i * simd::f32x8_LENGTH, vd_rows, vecs_per_row);
     };
-    // Fill rows of vd and vt in parallel one pair of rows at a time
+    // Function: for 8 rows of f32x8 vectors in vt, starting at row 'i' * 8,
+    // - copy all elements from columns 'i' * 8 .. 'i' * 8 + 8 in d,
+    // - pack them into f32x8 vectors by transposing 8x8 blocks of d,
+    // - insert all into the 8 rows of vt (vt_rows)
+    let pack_simd_cols = |(i, vt_rows): (usize, &mut [f32x8])| {
+        kfilter::pack_rows(d, d_layout.transposed(), n, filter, i * simd::f32x8_LENGTH, vt_rows, vecs_per_row);
+    };
+    // Fill rows of vd and vt in parallel 8 rows at a time
     // ANCHOR_END: preprocess
-    #[cfg(not(feature = "no-multi-thread"))]
     // ANCHOR: preprocess_apply
-    vd.par_chunks_mut(vecs_per_row)
-        .zip(vt.par_chunks_mut(vecs_per_row))
+    vd.par_chunks_mut(simd::f32x8_LENGTH * vecs_per_row)
         .enumerate()
-        .for_each(pack_simd_row);
-    // ANCHOR_END: preprocess_apply
-    #[cfg(feature = "no-multi-thread")]
-    vd.chunks_mut(vecs_per_row)
-        .zip(vt.chunks_mut(vecs_per_row))
+        .for_each(pack_simd_rows);
+    vt.par_chunks_mut(simd::f32x8_LENGTH * vecs_per_row)
         .enumerate()
-        .for_each(pack_simd_row);
+        .for_each(pack_simd_cols);
+    // ANCHOR_END: preprocess_apply
+    (vd, vt)
+}
+
+/// Result for a row of vd and a row of vt
+#[inline]
+fn min_plus_rows(vd_row: &[f32x8], vt_row: &[f32x8]) -> f32 {
+    // Fold vd_row and vt_row into a single f32x8 result
+    let tmp = vd_row.iter()
+                    .zip(vt_row)
+                    .fold(simd::f32x8_infty(),
+                          |v, (&x, &y)| simd::min(v, simd::add(x, y)));
+    // Reduce 8 different f32 results in tmp into the final result
+    simd::horizontal_min(tmp)
+}
+
+/// Compute the results of all row pairs of vd and vt, which have vecs_per_row vectors per row,
+/// into the rows of r, which are ldr elements apart
+fn step_rows(r: &mut [f32], ldr: usize, vd: &[f32x8], vt: &[f32x8], vecs_per_row: usize, accumulate: bool) {
     // ANCHOR: step_row
     // Function: for a row of f32x8 elements from vd,
-    // compute a n f32 results into r
+    // compute a n f32 results into the first n elements of a row of r
     let step_row = |(r_row, vd_row): (&mut [f32], &[f32x8])| {
         let vt_rows = vt.chunks_exact(vecs_per_row);
         // ANCHOR: step_row_inner
         for (res, vt_row) in r_row.iter_mut().zip(vt_rows) {
-            // Fold vd_row and vt_row into a single f32x8 result
-            let tmp = vd_row.iter()
-                            .zip(vt_row)
-                            .fold(simd::f32x8_infty(),
-                                  |v, (&x, &y)| simd::min(v, simd::add(x, y)));
-            // Reduce 8 different f32 results in tmp into the final result
-            *res = simd::horizontal_min(tmp);
+            let v = min_plus_rows(vd_row, vt_row);
+            *res = if accumulate { tools::min(*res, v) } else { v };
         }
         // ANCHOR_END: step_row_inner
     };
     // ANCHOR_END: step_row
-    #[cfg(not(feature = "no-multi-thread"))]
     // ANCHOR: step_row_apply
-    r.par_chunks_mut(n)
+    r.par_chunks_mut(ldr)
         .zip(vd.par_chunks(vecs_per_row))
         .for_each(step_row);
     // ANCHOR_END: step_row_apply
-    #[cfg(feature = "no-multi-thread")]
-    r.chunks_mut(n)
-        .zip(vd.chunks(vecs_per_row))
-        .for_each(step_row);
 }
 
 
-create_extern_c_wrapper!(step, _step);
+create_extern_c_wrapper!(step, step);
+create_extern_c_wrapper!(step_accumulate, step_accumulate);
+create_extern_c_in_place_wrapper!(step_in_place, step_in_place);
+create_extern_c_weighted_wrapper!(step_weighted, step_weighted);
+create_extern_c_strided_wrapper!(step_strided, step_strided);
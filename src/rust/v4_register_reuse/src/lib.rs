@@ -1,111 +1,208 @@
-use tools::{create_extern_c_wrapper, simd, simd::f32x8};
-use itertools::Itertools;
-// izip for zipping multiple iterators
-#[macro_use]
-extern crate itertools;
+use tools::{create_extern_c_in_place_wrapper, create_extern_c_strided_wrapper, create_extern_c_weighted_wrapper, create_extern_c_wrapper, kernel, kfilter::{self, KFilter}, layout::Layout, params::Params, simd, simd::f32x8};
+use tools::par::prelude::*;
 
-#[cfg(not(feature = "no-multi-thread"))]
-extern crate rayon;
-#[cfg(not(feature = "no-multi-thread"))]
-use rayon::prelude::*;
 
+#[inline]
+pub fn step(r: &mut [f32], d: &[f32], n: usize) {
+    step_with_params(r, d, n, tools::params::get());
+}
+
+/// Like step, but with a register tile of params.block_height × params.block_width vectors instead of the tuned shape
+pub fn step_with_params(r: &mut [f32], d: &[f32], n: usize, params: &Params) {
+    step_strided_with_params(r, Layout::dense(n), d, Layout::dense(n), n, params);
+}
+
+/// Like step, but for r and d stored with the given layouts, see tools::layout
+#[inline]
+pub fn step_strided(r: &mut [f32], r_layout: Layout, d: &[f32], d_layout: Layout, n: usize) {
+    step_strided_with_params(r, r_layout, d, d_layout, n, tools::params::get());
+}
+
+/// Like step_with_params, but for layouts as in step_strided
+pub fn step_strided_with_params(r: &mut [f32], r_layout: Layout, d: &[f32], d_layout: Layout, n: usize, params: &Params) {
+    step_into(r, r_layout, d, d_layout, n, params, false);
+}
+
+/// Like step, but r becomes min(r, d ⊗ d), merging each result of a tile into r when it is written
+#[inline]
+pub fn step_accumulate(r: &mut [f32], d: &[f32], n: usize) {
+    step_accumulate_with_params(r, d, n, tools::params::get());
+}
+
+pub fn step_accumulate_with_params(r: &mut [f32], d: &[f32], n: usize, params: &Params) {
+    step_into(r, Layout::dense(n), d, Layout::dense(n), n, params, true);
+}
+
+fn step_into(r: &mut [f32], r_layout: Layout, d: &[f32], d_layout: Layout, n: usize, params: &Params, accumulate: bool) {
+    r_layout.check(n, r.len(), "r");
+    d_layout.check(n, d.len(), "d");
+    if n == 0 {
+        return;
+    }
+    // A column-major r is computed as the row-major result of d transposed
+    let d_layout = if r_layout.col_major { d_layout.transposed() } else { d_layout };
+    let (vd, vt) = pack_simd(d, d_layout, n, &KFilter::ALL, params.block_height, params.block_width);
+    step_packed(&mut r[..r_layout.len(n)], r_layout.ld, &vd, &vt, n, n.div_ceil(simd::f32x8_LENGTH), params, accumulate);
+}
+
+/// Like step, but d is read from rd, which is then overwritten with the results
+#[inline]
+pub fn step_in_place(rd: &mut [f32], n: usize) {
+    step_in_place_with_params(rd, n, tools::params::get());
+}
+
+pub fn step_in_place_with_params(rd: &mut [f32], n: usize, params: &Params) {
+    assert_eq!(rd.len(), n * n, "rd must contain the n×n matrix d");
+    if n == 0 {
+        return;
+    }
+    let (vd, vt) = pack_simd(rd, Layout::dense(n), n, &KFilter::ALL, params.block_height, params.block_width);
+    step_packed(rd, n, &vd, &vt, n, n.div_ceil(simd::f32x8_LENGTH), params, false);
+}
+
+/// Like step, but minimize only over the intermediate nodes k allowed by filter, see tools::kfilter
+#[inline]
+pub fn step_filtered(r: &mut [f32], d: &[f32], n: usize, filter: &KFilter) {
+    step_filtered_with_params(r, d, n, filter, tools::params::get());
+}
 
+/// Like step_with_params, but with a filter as in step_filtered
+pub fn step_filtered_with_params(r: &mut [f32], d: &[f32], n: usize, filter: &KFilter, params: &Params) {
+    assert_eq!(r.len(), n * n, "r must have room for the n×n results");
+    assert_eq!(d.len(), n * n, "d must contain the n×n input");
+    filter.check(n);
+    if filter.len(n) == 0 {
+        // No intermediate nodes, so there are no paths
+        return r.fill(f32::INFINITY);
+    }
+    let (vd, vt) = pack_simd(d, Layout::dense(n), n, filter, params.block_height, params.block_width);
+    step_packed(r, n, &vd, &vt, n, filter.len(n).div_ceil(simd::f32x8_LENGTH), params, false);
+}
+
+/// Like step, but with a weight w[k] for each vertex k added to the paths through it, see tools::weights
+#[inline]
+pub fn step_weighted(r: &mut [f32], d: &[f32], w: &[f32], n: usize) {
+    step_weighted_with_params(r, d, w, n, tools::params::get());
+}
+
+/// Like step_with_params, but with weights as in step_weighted
+pub fn step_weighted_with_params(r: &mut [f32], d: &[f32], w: &[f32], n: usize, params: &Params) {
+    assert_eq!(r.len(), n * n, "r must have room for the n×n results");
+    assert_eq!(d.len(), n * n, "d must contain the n×n input");
+    tools::weights::check(w, n);
+    if n == 0 {
+        return;
+    }
+    let vecs_per_row = n.div_ceil(simd::f32x8_LENGTH);
+    let (mut vd, vt) = pack_simd(d, Layout::dense(n), n, &KFilter::ALL, params.block_height, params.block_width);
+    vd.par_chunks_mut(vecs_per_row)
+        .for_each(|vd_row| tools::weights::add_to_row(vd_row, w));
+    step_packed(r, n, &vd, &vt, n, vecs_per_row, params, false);
+}
+
+/// Compute r from vd and vt, packed for the block shape in params with vecs_per_row vectors per row
+#[allow(clippy::too_many_arguments)]
+fn step_packed(r: &mut [f32], ldr: usize, vd: &[f32x8], vt: &[f32x8], n: usize, vecs_per_row: usize, params: &Params, accumulate: bool) {
+    // Each supported shape has its own instance with fully unrolled inner loops
+    match (params.block_height, params.block_width) {
+        (1, 1) => step_row_blocks::<1, 1>(r, ldr, vd, vt, n, vecs_per_row, accumulate),
+        (2, 2) => step_row_blocks::<2, 2>(r, ldr, vd, vt, n, vecs_per_row, accumulate),
+        (2, 4) => step_row_blocks::<2, 4>(r, ldr, vd, vt, n, vecs_per_row, accumulate),
+        (3, 2) => step_row_blocks::<3, 2>(r, ldr, vd, vt, n, vecs_per_row, accumulate),
+        (3, 3) => step_row_blocks::<3, 3>(r, ldr, vd, vt, n, vecs_per_row, accumulate),
+        (3, 4) => step_row_blocks::<3, 4>(r, ldr, vd, vt, n, vecs_per_row, accumulate),
+        (4, 2) => step_row_blocks::<4, 2>(r, ldr, vd, vt, n, vecs_per_row, accumulate),
+        (4, 3) => step_row_blocks::<4, 3>(r, ldr, vd, vt, n, vecs_per_row, accumulate),
+        (4, 4) => step_row_blocks::<4, 4>(r, ldr, vd, vt, n, vecs_per_row, accumulate),
+        (6, 2) => step_row_blocks::<6, 2>(r, ldr, vd, vt, n, vecs_per_row, accumulate),
+        (h, w) => panic!("unsupported block shape ({}, {}), must be one of {:?}", h, w, tools::params::BLOCK_SHAPES),
+    }
+}
+
+/// Compute r using a register tile of BLOCK_HEIGHT rows of vd and BLOCK_WIDTH rows of vt,
+/// any shape can be instantiated for benchmarking, not only the ones in BLOCK_SHAPES
 #[inline]
-fn _step(r: &mut [f32], d: &[f32], n: usize) {
+pub fn step_blocks<const BLOCK_HEIGHT: usize, const BLOCK_WIDTH: usize>(r: &mut [f32], d: &[f32], n: usize) {
+    step_blocks_strided::<BLOCK_HEIGHT, BLOCK_WIDTH>(r, Layout::dense(n), d, Layout::dense(n), n, false);
+}
+
+/// Like step_blocks, but for r and d stored with the given layouts.
+/// Only the packing of d and the row stride of r depend on the layouts.
+/// If accumulate is true, the results are merged into the existing contents of r.
+pub fn step_blocks_strided<const BLOCK_HEIGHT: usize, const BLOCK_WIDTH: usize>(
+    r: &mut [f32], r_layout: Layout, d: &[f32], d_layout: Layout, n: usize, accumulate: bool,
+) {
+    r_layout.check(n, r.len(), "r");
+    d_layout.check(n, d.len(), "d");
+    if n == 0 {
+        return;
+    }
+    let d_layout = if r_layout.col_major { d_layout.transposed() } else { d_layout };
+    let (vd, vt) = pack_simd(d, d_layout, n, &KFilter::ALL, BLOCK_HEIGHT, BLOCK_WIDTH);
+    step_row_blocks::<BLOCK_HEIGHT, BLOCK_WIDTH>(&mut r[..r_layout.len(n)], r_layout.ld, &vd, &vt, n, n.div_ceil(simd::f32x8_LENGTH), accumulate);
+}
+
+/// Pack the rows of d into vd and the columns of d into vt, with the amount of rows padded
+/// to a multiple of block_height in vd and of block_width in vt, and only the elements at the intermediate nodes of filter
+fn pack_simd(d: &[f32], d_layout: Layout, n: usize, filter: &KFilter, block_height: usize, block_width: usize) -> (Vec<f32x8>, Vec<f32x8>) {
     // ANCHOR: init
-    const BLOCK_HEIGHT: usize = 3;
-    let blocks_per_col = (n + BLOCK_HEIGHT - 1) / BLOCK_HEIGHT;
-    let vecs_per_row = (n + simd::f32x8_LENGTH - 1) / simd::f32x8_LENGTH;
-    let padded_height = BLOCK_HEIGHT * blocks_per_col;
-    // Preprocess exactly as in v3_simd,
-    // but make sure the amount of rows is divisible by BLOCK_HEIGHT
-    let mut vd = std::vec![simd::f32x8_infty(); padded_height * vecs_per_row];
-    let mut vt = std::vec![simd::f32x8_infty(); padded_height * vecs_per_row];
+    let vecs_per_row = filter.len(n).div_ceil(simd::f32x8_LENGTH);
+    // Preprocess exactly as in v3_simd, but make sure the amount of rows
+    // is divisible by the block height in vd and by the block width in vt
+    let vd_height = block_height * n.div_ceil(block_height);
+    let vt_height = block_width * n.div_ceil(block_width);
+    let mut vd = std::vec![simd::f32x8_infty(); vd_height * vecs_per_row];
+    let mut vt = std::vec![simd::f32x8_infty(); vt_height * vecs_per_row];
     // ANCHOR_END: init
     debug_assert!(vd.iter().all(simd::is_aligned));
     debug_assert!(vt.iter().all(simd::is_aligned));
-    let pack_simd_row = |(i, (vd_row, vt_row)): (usize, (&mut [f32x8], &mut [f32x8]))| {
-        for (jv, (vx, vy)) in vd_row.iter_mut().zip(vt_row.iter_mut()).enumerate() {
-            let mut vx_tmp = [std::f32::INFINITY; simd::f32x8_LENGTH];
-            let mut vy_tmp = [std::f32::INFINITY; simd::f32x8_LENGTH];
-            for (b, (x, y)) in vx_tmp.iter_mut().zip(vy_tmp.iter_mut()).enumerate() {
-                let j = jv * simd::f32x8_LENGTH + b;
-                if i < n && j < n {
-                    *x = d[n * i + j];
-                    *y = d[n * j + i];
-                }
-            }
-            *vx = simd::from_slice(&vx_tmp);
-            *vy = simd::from_slice(&vy_tmp);
-        }
+    // Rows of vd and vt past n are padding and are filled with f32::INFINITY
+    let pack_simd_rows = |(i, vd_rows): (usize, &mut [f32x8])| {
+        kfilter::pack_rows(d, d_layout, n, filter, i * simd::f32x8_LENGTH, vd_rows, vecs_per_row);
     };
-    #[cfg(not(feature = "no-multi-thread"))]
-    vd.par_chunks_mut(vecs_per_row)
-        .zip(vt.par_chunks_mut(vecs_per_row))
+    let pack_simd_cols = |(i, vt_rows): (usize, &mut [f32x8])| {
+        kfilter::pack_rows(d, d_layout.transposed(), n, filter, i * simd::f32x8_LENGTH, vt_rows, vecs_per_row);
+    };
+    vd.par_chunks_mut(simd::f32x8_LENGTH * vecs_per_row)
         .enumerate()
-        .for_each(pack_simd_row);
-    #[cfg(feature = "no-multi-thread")]
-    vd.chunks_mut(vecs_per_row)
-        .zip(vt.chunks_mut(vecs_per_row))
+        .for_each(pack_simd_rows);
+    vt.par_chunks_mut(simd::f32x8_LENGTH * vecs_per_row)
         .enumerate()
-        .for_each(pack_simd_row);
+        .for_each(pack_simd_cols);
+    (vd, vt)
+}
 
+/// Compute all results from vd and vt, which have vecs_per_row vectors per row, into the rows of r, which are ldr elements apart
+fn step_row_blocks<const BLOCK_HEIGHT: usize, const BLOCK_WIDTH: usize>(
+    r: &mut [f32], ldr: usize, vd: &[f32x8], vt: &[f32x8], n: usize, vecs_per_row: usize, accumulate: bool,
+) {
     // ANCHOR: step_row_block
     //// ANCHOR: step_row_block_head
-    // Function: For a row block vd_row_block containing 3 rows of f32x8 vectors,
+    // Function: For a row block vd_row_block containing BLOCK_HEIGHT rows of f32x8 vectors,
     // compute results for all row combinations of vd_row_block and row blocks of vt
     let step_row_block = |(i, (r_row_block, vd_row_block)): (usize, (&mut [f32], &[f32x8]))| {
-        // Chunk up vt into blocks exactly as vd
-        let vt_row_blocks = vt.chunks_exact(BLOCK_HEIGHT * vecs_per_row);
+        let vd_rows: [&[f32x8]; BLOCK_HEIGHT] = kernel::rows(vd_row_block, vecs_per_row);
+        // Chunk up vt into blocks of BLOCK_WIDTH rows
+        let vt_row_blocks = vt.chunks_exact(BLOCK_WIDTH * vecs_per_row);
         // Compute results for all combinations of row blocks from vd and vt
         for (j, vt_row_block) in vt_row_blocks.enumerate() {
             //// ANCHOR_END: step_row_block_head
-            //// ANCHOR: step_row_block_init
-            // Partial results for 9 f32x8 row pairs
-            // All as separate variables to encourage the compiler
-            // to keep these values in 9 registers for the duration of the loop
-            let mut tmp0 = simd::f32x8_infty();
-            let mut tmp1 = simd::f32x8_infty();
-            let mut tmp2 = simd::f32x8_infty();
-            let mut tmp3 = simd::f32x8_infty();
-            let mut tmp4 = simd::f32x8_infty();
-            let mut tmp5 = simd::f32x8_infty();
-            let mut tmp6 = simd::f32x8_infty();
-            let mut tmp7 = simd::f32x8_infty();
-            let mut tmp8 = simd::f32x8_infty();
-            // Extract all rows from the row blocks
-            let mut vd_rows = vd_row_block.chunks_exact(vecs_per_row);
-            let mut vt_rows = vt_row_block.chunks_exact(vecs_per_row);
-            let (vd_row_0, vd_row_1, vd_row_2) = vd_rows.next_tuple().unwrap();
-            let (vt_row_0, vt_row_1, vt_row_2) = vt_rows.next_tuple().unwrap();
-            //// ANCHOR_END: step_row_block_init
             //// ANCHOR: step_row_block_inner_loop
-            // Move horizontally, computing 3 x 3 results for each column
-            // At each iteration, load two 'vertical stripes' of 3 f32x8 vectors
-            let rows = izip!(vd_row_0, vd_row_1, vd_row_2, vt_row_0, vt_row_1, vt_row_2);
-            for (&d0, &d1, &d2, &t0, &t1, &t2) in rows {
-                // Combine all 9 pairs of f32x8 vectors from 6 rows at every column
-                tmp0 = simd::min(tmp0, simd::add(d0, t0));
-                tmp1 = simd::min(tmp1, simd::add(d0, t1));
-                tmp2 = simd::min(tmp2, simd::add(d0, t2));
-                tmp3 = simd::min(tmp3, simd::add(d1, t0));
-                tmp4 = simd::min(tmp4, simd::add(d1, t1));
-                tmp5 = simd::min(tmp5, simd::add(d1, t2));
-                tmp6 = simd::min(tmp6, simd::add(d2, t0));
-                tmp7 = simd::min(tmp7, simd::add(d2, t1));
-                tmp8 = simd::min(tmp8, simd::add(d2, t2));
-            }
+            // Partial results for all BLOCK_HEIGHT * BLOCK_WIDTH f32x8 row pairs,
+            // kept in registers for the duration of the loop over all columns
+            let vt_rows: [&[f32x8]; BLOCK_WIDTH] = kernel::rows(vt_row_block, vecs_per_row);
+            let tile = kernel::min_plus_tile(&vd_rows, &vt_rows);
             //// ANCHOR_END: step_row_block_inner_loop
             //// ANCHOR: step_row_block_results
-            let tmp = [tmp0, tmp1, tmp2, tmp3, tmp4, tmp5, tmp6, tmp7, tmp8];
-            // Set 9 final results for all combinations of 3 rows starting at i and 3 rows starting at j
-            for (block_i, (r_row, tmp_row)) in r_row_block.chunks_exact_mut(n).zip(tmp.chunks_exact(BLOCK_HEIGHT)).enumerate() {
-                for (block_j, &tmp_res) in tmp_row.iter().enumerate() {
+            // Set final results for all combinations of BLOCK_HEIGHT rows starting at i and BLOCK_WIDTH rows starting at j
+            for (block_i, (r_row, tile_row)) in r_row_block.chunks_mut(ldr).zip(tile.iter()).enumerate() {
+                for (block_j, &tile_res) in tile_row.iter().enumerate() {
                     let res_i = i * BLOCK_HEIGHT + block_i;
-                    let res_j = j * BLOCK_HEIGHT + block_j;
+                    let res_j = j * BLOCK_WIDTH + block_j;
                     if res_i < n && res_j < n {
                         // Reduce one f32x8 to the final result for one pair of rows
-                        r_row[res_j] = simd::horizontal_min(tmp_res);
+                        let v = simd::horizontal_min(tile_res);
+                        r_row[res_j] = if accumulate { tools::min(r_row[res_j], v) } else { v };
                     }
                 }
             }
@@ -114,19 +211,16 @@ fn _step(r: &mut [f32], d: &[f32], n: usize) {
     };
     // ANCHOR_END: step_row_block
     // Chunk up r and vd into row blocks and compute results of all row combinations between vd and vt
-    #[cfg(not(feature = "no-multi-thread"))]
     // ANCHOR: step_row_apply
-    r.par_chunks_mut(BLOCK_HEIGHT * n)
+    r.par_chunks_mut(BLOCK_HEIGHT * ldr)
         .zip(vd.par_chunks(BLOCK_HEIGHT * vecs_per_row))
         .enumerate()
         .for_each(step_row_block);
     // ANCHOR_END: step_row_apply
-    #[cfg(feature = "no-multi-thread")]
-    r.chunks_mut(BLOCK_HEIGHT * n)
-        .zip(vd.chunks(BLOCK_HEIGHT * vecs_per_row))
-        .enumerate()
-        .for_each(step_row_block);
 }
 
-
-create_extern_c_wrapper!(step, _step);
+create_extern_c_wrapper!(step, step);
+create_extern_c_wrapper!(step_accumulate, step_accumulate);
+create_extern_c_in_place_wrapper!(step_in_place, step_in_place);
+create_extern_c_weighted_wrapper!(step_weighted, step_weighted);
+create_extern_c_strided_wrapper!(step_strided, step_strided);
@@ -1,124 +1,403 @@
-use tools::{create_extern_c_wrapper, simd, simd::f32x8};
+use std::cell::RefCell;
+use tools::{create_extern_c_in_place_wrapper, create_extern_c_strided_wrapper, create_extern_c_weighted_wrapper, create_extern_c_wrapper, kfilter::{self, KFilter}, layout::Layout, simd, simd::f32x8};
+use tools::par::{self, prelude::*};
 
-#[cfg(not(feature = "no-multi-thread"))]
-extern crate rayon;
-#[cfg(not(feature = "no-multi-thread"))]
-use rayon::prelude::*;
 
+#[inline]
+pub fn step(r: &mut [f32], d: &[f32], n: usize) {
+    step_strided(r, Layout::dense(n), d, Layout::dense(n), n);
+}
+
+/// Like step, but for r and d stored with the given layouts, see tools::layout
+#[inline]
+pub fn step_strided(r: &mut [f32], r_layout: Layout, d: &[f32], d_layout: Layout, n: usize) {
+    step_into(r, r_layout, d, d_layout, n, false);
+}
 
+/// Like step, but r becomes min(r, d ⊗ d), merging each 8×8 block of results into r when it is stored
 #[inline]
-fn _step(r: &mut [f32], d: &[f32], n: usize) {
+pub fn step_accumulate(r: &mut [f32], d: &[f32], n: usize) {
+    step_into(r, Layout::dense(n), d, Layout::dense(n), n, true);
+}
+
+fn step_into(r: &mut [f32], r_layout: Layout, d: &[f32], d_layout: Layout, n: usize, accumulate: bool) {
+    r_layout.check(n, r.len(), "r");
+    d_layout.check(n, d.len(), "d");
+    if n == 0 {
+        return;
+    }
+    // A column-major r is computed as the row-major result of d transposed
+    let d_layout = if r_layout.col_major { d_layout.transposed() } else { d_layout };
     // ANCHOR: init
-    let vecs_per_col = (n + simd::f32x8_LENGTH - 1) / simd::f32x8_LENGTH;
+    let vecs_per_col = n.div_ceil(simd::f32x8_LENGTH);
     // Like v4, but this time pack all elements of d into f32x8s vertically
     let mut vd = std::vec![simd::f32x8_infty(); n * vecs_per_col];
     let mut vt = std::vec![simd::f32x8_infty(); n * vecs_per_col];
     // ANCHOR_END: init
     debug_assert!(vd.iter().all(simd::is_aligned));
     debug_assert!(vt.iter().all(simd::is_aligned));
+    pack_simd(d, d_layout, n, &KFilter::ALL, &mut vd, &mut vt);
+    step_row_blocks(&mut r[..r_layout.len(n)], r_layout.ld, &vd, &vt, n, n, false, accumulate);
+}
+
+/// Like step, but d is read from rd, which is then overwritten with the results
+pub fn step_in_place(rd: &mut [f32], n: usize) {
+    assert_eq!(rd.len(), n * n, "rd must contain the n×n matrix d");
+    if n == 0 {
+        return;
+    }
+    let vecs_per_col = n.div_ceil(simd::f32x8_LENGTH);
+    let mut vd = std::vec![simd::f32x8_infty(); n * vecs_per_col];
+    let mut vt = std::vec![simd::f32x8_infty(); n * vecs_per_col];
+    pack_simd(rd, Layout::dense(n), n, &KFilter::ALL, &mut vd, &mut vt);
+    step_row_blocks(rd, n, &vd, &vt, n, n, false, false);
+}
+
+/// Pack 8 rows of d into each row of vd and 8 columns of d into each row of vt,
+/// which both have a vector for each intermediate node of filter, i.e. n vectors per row unless filtered
+fn pack_simd(d: &[f32], d_layout: Layout, n: usize, filter: &KFilter, vd: &mut [f32x8], vt: &mut [f32x8]) {
+    let row_len = filter.len(n);
     // ANCHOR: pack_simd
     // Function: for row i of vd and row i of vt,
     // copy 8 rows of d into vd and 8 columns of d into vt
     let pack_simd_row_block = |(i, (vd_row, vt_row)): (usize, (&mut [f32x8], &mut [f32x8]))| {
-        for (jv, (vx, vy)) in vd_row.iter_mut().zip(vt_row.iter_mut()).enumerate() {
-            let mut vx_tmp = [std::f32::INFINITY; simd::f32x8_LENGTH];
-            let mut vy_tmp = [std::f32::INFINITY; simd::f32x8_LENGTH];
-            for (b, (x, y)) in vx_tmp.iter_mut().zip(vy_tmp.iter_mut()).enumerate() {
-                let j = i * simd::f32x8_LENGTH + b;
-                if i < n && j < n {
-                    *x = d[n * j + jv];
-                    *y = d[n * jv + j];
-                }
-            }
-            *vx = simd::from_slice(&vx_tmp);
-            *vy = simd::from_slice(&vy_tmp);
-        }
+        let row_begin = i * simd::f32x8_LENGTH;
+        // Rows of a row-major d are transposed 8x8 blocks at a time into vd,
+        // columns are contiguous 8 element chunks of each row, and the other way around if d is column-major
+        kfilter::pack_columns(d, d_layout, n, filter, row_begin, 0, row_len, vd_row);
+        kfilter::pack_columns(d, d_layout.transposed(), n, filter, row_begin, 0, row_len, vt_row);
     };
     // ANCHOR_END: pack_simd
-    #[cfg(not(feature = "no-multi-thread"))]
     // ANCHOR: pack_simd_apply
-    vd.par_chunks_mut(n)
-        .zip(vt.par_chunks_mut(n))
+    vd.par_chunks_mut(row_len)
+        .zip(vt.par_chunks_mut(row_len))
         .enumerate()
         .for_each(pack_simd_row_block);
     // ANCHOR_END: pack_simd_apply
-    #[cfg(feature = "no-multi-thread")]
-    vd.chunks_mut(n)
-        .zip(vt.chunks_mut(n))
+}
+
+/// Like step, but compute only the rows of r at the given indexes, row rows[k] into row k of r_rows.
+/// All columns of d are packed into vt as usual, but vd contains only the requested rows,
+/// 8 at a time in the given order, so that only their row blocks are computed.
+pub fn step_row_subset(r_rows: &mut [f32], d: &[f32], n: usize, rows: &[usize]) {
+    assert_eq!(d.len(), n * n, "d must contain the n×n input");
+    assert_eq!(r_rows.len(), rows.len() * n, "r_rows must contain one row of r for each row index");
+    assert!(rows.iter().all(|&i| i < n), "row indexes must be less than n");
+    if r_rows.is_empty() {
+        return;
+    }
+    let vecs_per_col = n.div_ceil(simd::f32x8_LENGTH);
+    let mut vd = std::vec![simd::f32x8_infty(); rows.len().div_ceil(simd::f32x8_LENGTH) * n];
+    vd.par_chunks_mut(n)
+        .zip(rows.par_chunks(simd::f32x8_LENGTH))
+        .for_each(|(vd_row, block_rows)| pack_selected_rows(d, n, block_rows, vd_row));
+    let mut vt = std::vec![simd::f32x8_infty(); n * vecs_per_col];
+    vt.par_chunks_mut(n)
         .enumerate()
-        .for_each(pack_simd_row_block);
+        .for_each(|(j, vt_row)| simd::pack_columns_strided(d, Layout::col_major(n), n, j * simd::f32x8_LENGTH, 0, n, vt_row));
+    step_row_blocks(r_rows, n, &vd, &vt, n, n, false, false);
+}
+
+/// Compute only the results r[i][j] of the given (i, j) pairs into results, in the same order.
+/// Only the 8×8 blocks of r containing some pair are computed, each one once,
+/// and only their row blocks of d are packed into vd and their column blocks into vt.
+pub fn step_pairs(results: &mut [f32], d: &[f32], n: usize, pairs: &[(usize, usize)]) {
+    assert_eq!(d.len(), n * n, "d must contain the n×n input");
+    assert_eq!(results.len(), pairs.len(), "results must have one element for each pair");
+    assert!(pairs.iter().all(|&(i, j)| i < n && j < n), "row and column indexes must be less than n");
+    // Also if n is 0, since there can be no pairs then
+    if pairs.is_empty() {
+        return;
+    }
+    const BLOCK_LEN: usize = simd::f32x8_LENGTH * simd::f32x8_LENGTH;
+    fn sorted_distinct<T: Ord>(mut v: Vec<T>) -> Vec<T> {
+        v.sort_unstable();
+        v.dedup();
+        v
+    }
+    // (row block, column block) of all pairs, and the row and column blocks used by some of them
+    let blocks: Vec<(usize, usize)> = sorted_distinct(pairs.iter().map(|&(i, j)| (i / simd::f32x8_LENGTH, j / simd::f32x8_LENGTH)).collect());
+    let row_blocks: Vec<usize> = sorted_distinct(blocks.iter().map(|b| b.0).collect());
+    let col_blocks: Vec<usize> = sorted_distinct(blocks.iter().map(|b| b.1).collect());
+    let pack_blocks = |d_layout: Layout, indexes: &[usize]| {
+        let mut v = std::vec![simd::f32x8_infty(); indexes.len() * n];
+        v.par_chunks_mut(n)
+            .zip(indexes.par_iter())
+            .for_each(|(v_row, &b)| simd::pack_columns_strided(d, d_layout, n, b * simd::f32x8_LENGTH, 0, n, v_row));
+        v
+    };
+    let vd = pack_blocks(Layout::dense(n), &row_blocks);
+    let vt = pack_blocks(Layout::col_major(n), &col_blocks);
+    // Function: compute the 64 results of an 8×8 block of r as in step_row_blocks, 8 rows of 8 results each
+    let step_selected_block = |(block_results, &(bi, bj)): (&mut [f32], &(usize, usize))| {
+        // Both are found, since row_blocks and col_blocks contain the blocks of all pairs
+        let vd_row = row_blocks.binary_search(&bi).unwrap() * n;
+        let vt_row = col_blocks.binary_search(&bj).unwrap() * n;
+        let rows = simd::unpack_results(step_block(&vd[vd_row..vd_row + n], &vt[vt_row..vt_row + n]));
+        for (dst, &row) in block_results.chunks_exact_mut(simd::f32x8_LENGTH).zip(rows.iter()) {
+            simd::store_padded(dst, row);
+        }
+    };
+    let mut block_results = std::vec![0.0; blocks.len() * BLOCK_LEN];
+    block_results.par_chunks_mut(BLOCK_LEN)
+        .zip(blocks.par_iter())
+        .for_each(step_selected_block);
+    for (res, &(i, j)) in results.iter_mut().zip(pairs) {
+        let (bi, bj) = (i / simd::f32x8_LENGTH, j / simd::f32x8_LENGTH);
+        let block = blocks.binary_search(&(bi, bj)).unwrap();
+        *res = block_results[block * BLOCK_LEN + simd::f32x8_LENGTH * (i % simd::f32x8_LENGTH) + j % simd::f32x8_LENGTH];
+    }
+}
+
+/// Pack the rows of d at the given indexes, at most 8, vertically into vd_row,
+/// in the same order as pack_columns packs 8 adjacent rows, with f32::INFINITY for missing rows
+fn pack_selected_rows(d: &[f32], n: usize, rows: &[usize], vd_row: &mut [f32x8]) {
+    for (col, v) in vd_row.iter_mut().enumerate() {
+        let elements: [f32; simd::f32x8_LENGTH] = std::array::from_fn(|b| rows.get(b).map_or(f32::INFINITY, |&i| d[n * i + col]));
+        *v = simd::from_slice(&elements);
+    }
+}
+
+/// Like step, but minimize only over the intermediate nodes k allowed by filter, see tools::kfilter
+pub fn step_filtered(r: &mut [f32], d: &[f32], n: usize, filter: &KFilter) {
+    assert_eq!(r.len(), n * n, "r must have room for the n×n results");
+    assert_eq!(d.len(), n * n, "d must contain the n×n input");
+    filter.check(n);
+    let row_len = filter.len(n);
+    if row_len == 0 {
+        // No intermediate nodes, so there are no paths
+        return r.fill(f32::INFINITY);
+    }
+    let vecs_per_col = n.div_ceil(simd::f32x8_LENGTH);
+    let mut vd = std::vec![simd::f32x8_infty(); row_len * vecs_per_col];
+    let mut vt = std::vec![simd::f32x8_infty(); row_len * vecs_per_col];
+    pack_simd(d, Layout::dense(n), n, filter, &mut vd, &mut vt);
+    step_row_blocks(r, n, &vd, &vt, n, row_len, false, false);
+}
+
+/// Like step, but with a weight w[k] for each vertex k added to the paths through it, see tools::weights
+pub fn step_weighted(r: &mut [f32], d: &[f32], w: &[f32], n: usize) {
+    assert_eq!(r.len(), n * n, "r must have room for the n×n results");
+    assert_eq!(d.len(), n * n, "d must contain the n×n input");
+    tools::weights::check(w, n);
+    if n == 0 {
+        return;
+    }
+    let vecs_per_col = n.div_ceil(simd::f32x8_LENGTH);
+    let mut vd = std::vec![simd::f32x8_infty(); n * vecs_per_col];
+    let mut vt = std::vec![simd::f32x8_infty(); n * vecs_per_col];
+    pack_simd(d, Layout::dense(n), n, &KFilter::ALL, &mut vd, &mut vt);
+    vd.par_chunks_mut(n)
+        .for_each(|vd_row| tools::weights::add_to_columns(vd_row, w));
+    step_row_blocks(r, n, &vd, &vt, n, n, false, false);
+}
+
+/// Like step, but each element is a pair (distance, cost), given as the separate matrices of distances and costs,
+/// and the pairs are minimized in lexicographic order, see tools::lexicographic.
+/// Distances and costs are packed into their own vd and vt, whose vectors are paired lane by lane,
+/// and the 8×8 blocks are computed by step_block_lexicographic.
+pub fn step_lexicographic(r_dist: &mut [f32], r_cost: &mut [f32], d_dist: &[f32], d_cost: &[f32], n: usize) {
+    assert_eq!(r_dist.len(), n * n, "r_dist must have room for the n×n distances");
+    assert_eq!(r_cost.len(), n * n, "r_cost must have room for the n×n costs");
+    tools::lexicographic::check(d_dist, d_cost, n);
+    if n == 0 {
+        return;
+    }
+    let vecs_per_col = n.div_ceil(simd::f32x8_LENGTH);
+    let mut vd_dist = std::vec![simd::f32x8_infty(); n * vecs_per_col];
+    let mut vt_dist = std::vec![simd::f32x8_infty(); n * vecs_per_col];
+    let mut vd_cost = std::vec![simd::f32x8_infty(); n * vecs_per_col];
+    let mut vt_cost = std::vec![simd::f32x8_infty(); n * vecs_per_col];
+    pack_simd(d_dist, Layout::dense(n), n, &KFilter::ALL, &mut vd_dist, &mut vt_dist);
+    pack_simd(d_cost, Layout::dense(n), n, &KFilter::ALL, &mut vd_cost, &mut vt_cost);
+    // For 8 rows of d, compute the distances and costs of 8 rows of r
+    r_dist.par_chunks_mut(simd::f32x8_LENGTH * n)
+        .zip(r_cost.par_chunks_mut(simd::f32x8_LENGTH * n))
+        .zip(vd_dist.par_chunks(n).zip(vd_cost.par_chunks(n)))
+        .for_each(|((r_dist_block, r_cost_block), (vd_dist_row, vd_cost_row))| {
+            for (j, (vt_dist_row, vt_cost_row)) in vt_dist.chunks_exact(n).zip(vt_cost.chunks_exact(n)).enumerate() {
+                let (tmp_dist, tmp_cost) = step_block_lexicographic(vd_dist_row, vd_cost_row, vt_dist_row, vt_cost_row);
+                // The lanes of the distances and costs are paired, so both are unpacked the same way
+                let res_j = j * simd::f32x8_LENGTH;
+                let res_end = n.min(res_j + simd::f32x8_LENGTH);
+                let (rows_dist, rows_cost) = (simd::unpack_results(tmp_dist), simd::unpack_results(tmp_cost));
+                for ((r_dist_row, r_cost_row), (dist, cost)) in r_dist_block.chunks_mut(n).zip(r_cost_block.chunks_mut(n)).zip(rows_dist.iter().zip(&rows_cost)) {
+                    simd::store_padded(&mut r_dist_row[res_j..res_end], *dist);
+                    simd::store_padded(&mut r_cost_row[res_j..res_end], *cost);
+                }
+            }
+        });
+}
+
+/// Compute the results of many small matrices, e.g. for n from 16 to 128, where one matrix
+/// has too few row blocks to be worth computing in parallel.
+/// d contains the n×n input matrices one after the other, and r the results in the same order.
+/// The matrices are computed in parallel, but each one sequentially in one thread,
+/// reusing vd and vt of the previous matrix computed in the same thread.
+pub fn step_batch(r: &mut [f32], d: &[f32], n: usize) {
+    assert_eq!(r.len(), d.len(), "r and d must contain the same amount of matrices");
+    assert_eq!(d.len() % (n * n).max(1), 0, "length of d must be a multiple of n * n");
+    if n == 0 {
+        return;
+    }
+    thread_local! {
+        // vd and vt for the matrices computed in this thread, kept for later calls
+        static BUFFERS: RefCell<(Vec<f32x8>, Vec<f32x8>)> = const { RefCell::new((Vec::new(), Vec::new())) };
+    }
+    let vecs_per_col = n.div_ceil(simd::f32x8_LENGTH);
+    let step_matrix = |(r_matrix, d_matrix): (&mut [f32], &[f32])| {
+        BUFFERS.with(|buffers| {
+            let (vd, vt) = &mut *buffers.borrow_mut();
+            // All elements are overwritten when packing, including the padding
+            vd.resize(n * vecs_per_col, simd::f32x8_infty());
+            vt.resize(n * vecs_per_col, simd::f32x8_infty());
+            par::run_sequentially(|| {
+                pack_simd(d_matrix, Layout::dense(n), n, &KFilter::ALL, vd, vt);
+                step_row_blocks(r_matrix, n, vd, vt, n, n, false, false);
+            });
+        });
+    };
+    r.par_chunks_mut(n * n)
+        .zip(d.par_chunks(n * n))
+        .for_each(step_matrix);
+}
 
+/// Like step, but for a symmetric d, i.e. d[i][j] equals d[j][i] for all i and j, which is checked only
+/// with debug assertions, see step_symmetric_checked. Then vt would be equal to vd and r is symmetric,
+/// so only vd is packed and only the blocks on and above the diagonal are computed,
+/// which are then mirrored to the blocks below it.
+pub fn step_symmetric(r: &mut [f32], d: &[f32], n: usize) {
+    assert_eq!(r.len(), n * n, "r must have room for the n×n results");
+    assert_eq!(d.len(), n * n, "d must contain the n×n input");
+    debug_assert!(tools::is_symmetric(d, n), "d is not symmetric");
+    if n == 0 {
+        return;
+    }
+    let vecs_per_col = n.div_ceil(simd::f32x8_LENGTH);
+    let mut vd = std::vec![simd::f32x8_infty(); n * vecs_per_col];
+    vd.par_chunks_mut(n)
+        .enumerate()
+        .for_each(|(i, vd_row)| simd::pack_columns(d, n, i * simd::f32x8_LENGTH, 0, n, vd_row));
+    step_row_blocks(r, n, &vd, &vd, n, n, true, false);
+    tools::mirror_upper_triangle(r, n);
+}
+
+/// Like step_symmetric, but check that d is symmetric, also without debug assertions.
+/// If it is not, all results are computed as in step and false is returned.
+pub fn step_symmetric_checked(r: &mut [f32], d: &[f32], n: usize) -> bool {
+    assert_eq!(d.len(), n * n, "d must contain the n×n input");
+    if !tools::is_symmetric(d, n) {
+        step(r, d, n);
+        return false;
+    }
+    step_symmetric(r, d, n);
+    true
+}
+
+/// Intermediate results of the 8×8 block of r for a row of vd and a row of vt, see simd::unpack_results
+#[inline]
+fn step_block(vd_row: &[f32x8], vt_row: &[f32x8]) -> [f32x8; simd::f32x8_LENGTH] {
+    let mut tmp = [simd::f32x8_infty(); simd::f32x8_LENGTH];
+    //// ANCHOR: step_row_block_inner
+    // Iterate horizontally over both rows,
+    // permute elements of each `f32x8` to create 8 unique combinations,
+    // and compute 8 minimums from all combinations
+    for (&d0, &t0) in vd_row.iter().zip(vt_row) {
+        // Compute permutations of f32x8 elements
+        // 2 3 0 1 6 7 4 5
+        let d2 = simd::swap::<2>(d0);
+        // 4 5 6 7 0 1 2 3
+        let d4 = simd::swap::<4>(d0);
+        // 6 7 4 5 2 3 0 1
+        let d6 = simd::swap::<2>(d4);
+        // 1 0 3 2 5 4 7 6
+        let t1 = simd::swap::<1>(t0);
+        // Compute 8 independent, intermediate results for 8 rows
+        tmp[0] = simd::min(tmp[0], simd::add(d0, t0));
+        tmp[1] = simd::min(tmp[1], simd::add(d0, t1));
+        tmp[2] = simd::min(tmp[2], simd::add(d2, t0));
+        tmp[3] = simd::min(tmp[3], simd::add(d2, t1));
+        tmp[4] = simd::min(tmp[4], simd::add(d4, t0));
+        tmp[5] = simd::min(tmp[5], simd::add(d4, t1));
+        tmp[6] = simd::min(tmp[6], simd::add(d6, t0));
+        tmp[7] = simd::min(tmp[7], simd::add(d6, t1));
+    }
+    //// ANCHOR_END: step_row_block_inner
+    tmp
+}
+
+/// Like step_block, but for the paired rows of distances and costs, minimizing the pairs in lexicographic order
+/// with tools::lexicographic::min_pairs. The costs are permuted and added exactly as the distances.
+#[inline]
+fn step_block_lexicographic(vd_dist_row: &[f32x8], vd_cost_row: &[f32x8], vt_dist_row: &[f32x8], vt_cost_row: &[f32x8]) -> ([f32x8; simd::f32x8_LENGTH], [f32x8; simd::f32x8_LENGTH]) {
+    let mut tmp_dist = [simd::f32x8_infty(); simd::f32x8_LENGTH];
+    let mut tmp_cost = [simd::f32x8_infty(); simd::f32x8_LENGTH];
+    let vd_rows = vd_dist_row.iter().zip(vd_cost_row);
+    let vt_rows = vt_dist_row.iter().zip(vt_cost_row);
+    for ((&d0, &c0), (&t0, &u0)) in vd_rows.zip(vt_rows) {
+        // Same permutations as in step_block, for both criteria
+        let d = [d0, simd::swap::<2>(d0), simd::swap::<4>(d0), simd::swap::<2>(simd::swap::<4>(d0))];
+        let c = [c0, simd::swap::<2>(c0), simd::swap::<4>(c0), simd::swap::<2>(simd::swap::<4>(c0))];
+        let t = [t0, simd::swap::<1>(t0)];
+        let u = [u0, simd::swap::<1>(u0)];
+        for (b, (dist, cost)) in tmp_dist.iter_mut().zip(tmp_cost.iter_mut()).enumerate() {
+            let new = (simd::add(d[b / 2], t[b % 2]), simd::add(c[b / 2], u[b % 2]));
+            (*dist, *cost) = tools::lexicographic::min_pairs((*dist, *cost), new);
+        }
+    }
+    (tmp_dist, tmp_cost)
+}
+
+/// Compute all 8×8 blocks of r, with rows ldr elements apart, from the packed rows of vd and vt,
+/// which have row_len vectors each, or only the blocks on and above the diagonal if upper_only is true.
+/// If accumulate is true, each block is merged into the existing results in r.
+#[allow(clippy::too_many_arguments)]
+fn step_row_blocks(r: &mut [f32], ldr: usize, vd: &[f32x8], vt: &[f32x8], n: usize, row_len: usize, upper_only: bool, accumulate: bool) {
     // ANCHOR: step_row_block
     //// ANCHOR: step_row_block_init
     ////// ANCHOR: step_row_block_header
     // Function: for 8 rows in d, compute all results for 8 rows into r
-    let step_row_block = |(r_row_block, vd_row): (&mut [f32], &[f32x8])| {
+    let step_row_block = |(i, (r_row_block, vd_row)): (usize, (&mut [f32], &[f32x8]))| {
         ////// ANCHOR_END: step_row_block_header
         // Chunk up vt into rows, each containing n f32x8 vectors,
-        // exactly as vd_row
-        for (j, vt_row) in vt.chunks_exact(n).enumerate() {
+        // exactly as vd_row, skipping the blocks below the diagonal if only the upper ones are needed
+        let first_j = if upper_only { i } else { 0 };
+        for (j, vt_row) in vt.chunks_exact(row_len).enumerate().skip(first_j) {
             // Intermediate results for 8 rows
-            let mut tmp = [simd::f32x8_infty(); simd::f32x8_LENGTH];
             //// ANCHOR_END: step_row_block_init
-            //// ANCHOR: step_row_block_inner
-            // Iterate horizontally over both rows,
-            // permute elements of each `f32x8` to create 8 unique combinations,
-            // and compute 8 minimums from all combinations
-            for (&d0, &t0) in vd_row.iter().zip(vt_row) {
-                // Compute permutations of f32x8 elements
-                // 2 3 0 1 6 7 4 5
-                let d2 = simd::swap(d0, 2);
-                // 4 5 6 7 0 1 2 3
-                let d4 = simd::swap(d0, 4);
-                // 6 7 4 5 2 3 0 1
-                let d6 = simd::swap(d4, 2);
-                // 1 0 3 2 5 4 7 6
-                let t1 = simd::swap(t0, 1);
-                // Compute 8 independent, intermediate results for 8 rows
-                tmp[0] = simd::min(tmp[0], simd::add(d0, t0));
-                tmp[1] = simd::min(tmp[1], simd::add(d0, t1));
-                tmp[2] = simd::min(tmp[2], simd::add(d2, t0));
-                tmp[3] = simd::min(tmp[3], simd::add(d2, t1));
-                tmp[4] = simd::min(tmp[4], simd::add(d4, t0));
-                tmp[5] = simd::min(tmp[5], simd::add(d4, t1));
-                tmp[6] = simd::min(tmp[6], simd::add(d6, t0));
-                tmp[7] = simd::min(tmp[7], simd::add(d6, t1));
-            }
-            //// ANCHOR_END: step_row_block_inner
+            let tmp = step_block(vd_row, vt_row);
             //// ANCHOR: step_row_block_results
-            // Swap elements of f32x8s at odd indexes to enable a linear iteration
-            // pattern for index tmp_j when extracting elements
-            for i in (1..simd::f32x8_LENGTH).step_by(2) {
-                tmp[i] = simd::swap(tmp[i], 1);
-            }
-            // Set 8 final results (i.e. 64 f32 results in total)
-            for (tmp_i, r_row) in r_row_block.chunks_exact_mut(n).enumerate() {
-                for tmp_j in 0..simd::f32x8_LENGTH {
-                    let res_j = j * simd::f32x8_LENGTH + tmp_j;
-                    if res_j < n {
-                        let v = tmp[tmp_i ^ tmp_j];
-                        let vi = tmp_j as u8;
-                        r_row[res_j] = simd::extract(v, vi);
-                    }
+            // Swap elements of f32x8s at odd indexes and blend the results of each row
+            // of r into one f32x8, see simd::unpack_results
+            let rows = simd::unpack_results(tmp);
+            // Set 8 final results (i.e. 64 f32 results in total),
+            // storing only the first n % 8 of each row in the last column block
+            let res_j = j * simd::f32x8_LENGTH;
+            for (r_row, &row) in r_row_block.chunks_mut(ldr).zip(rows.iter()) {
+                let dst = &mut r_row[res_j..n.min(res_j + simd::f32x8_LENGTH)];
+                if accumulate {
+                    simd::min_store_padded(dst, row);
+                } else {
+                    simd::store_padded(dst, row);
                 }
             }
             //// ANCHOR_END: step_row_block_results
         }
     };
     // ANCHOR_END: step_row_block
-    #[cfg(not(feature = "no-multi-thread"))]
     // ANCHOR: step_row_block_apply
     // Chunk up r into row blocks containing 8 rows, each containing n f32s,
     // and chunk up vd into rows, each containing n f32x8s
-    r.par_chunks_mut(simd::f32x8_LENGTH * n)
-        .zip(vd.par_chunks(n))
+    r.par_chunks_mut(simd::f32x8_LENGTH * ldr)
+        .zip(vd.par_chunks(row_len))
+        .enumerate()
         .for_each(step_row_block);
     // ANCHOR_END: step_row_block_apply
-    #[cfg(feature = "no-multi-thread")]
-    r.chunks_mut(simd::f32x8_LENGTH * n)
-        .zip(vd.chunks(n))
-        .for_each(step_row_block);
 }
 
 
-create_extern_c_wrapper!(step, _step);
+create_extern_c_wrapper!(step, step);
+create_extern_c_wrapper!(step_accumulate, step_accumulate);
+create_extern_c_in_place_wrapper!(step_in_place, step_in_place);
+create_extern_c_weighted_wrapper!(step_weighted, step_weighted);
+create_extern_c_strided_wrapper!(step_strided, step_strided);
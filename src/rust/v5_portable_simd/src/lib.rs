@@ -0,0 +1,147 @@
+//! The permutation micro-kernel of v5_more_register_reuse, written with the portable
+//! `core::simd` vectors instead of AVX intrinsics.
+//! The amount of lanes is a const generic parameter, so the same source compiles to
+//! SSE (4 lanes), AVX (8 lanes) or AVX-512 (16 lanes) code, or to whatever the target supports.
+#![feature(portable_simd)]
+use std::simd::cmp::SimdPartialOrd;
+use std::simd::{Select, Simd, Swizzle};
+use tools::create_extern_c_wrapper;
+use tools::par::prelude::*;
+
+/// Amount of lanes used by `step`, the widest vectors supported by the target
+pub const LANES: usize = if cfg!(target_feature = "avx512f") {
+    16
+} else if cfg!(target_feature = "avx") {
+    8
+} else {
+    4
+};
+
+/// Swizzle moving element `i ^ X` to index `i`, i.e. swapping adjacent groups of X elements.
+/// With X = 1, 2 and 4 on 8 lanes, these are the permutations of simd::swap in v5.
+/// `simd_swizzle!` cannot be used here, since its index array cannot depend on the lane count.
+/// Groups as wide as the whole vector leave it unchanged, so that `swap` compiles for any lane count.
+struct Xor<const X: usize>;
+
+impl<const N: usize, const X: usize> Swizzle<N> for Xor<X> {
+    const INDEX: [usize; N] = {
+        let mut index = [0; N];
+        let mut i = 0;
+        while i < N {
+            index[i] = i ^ (X % N);
+            i += 1;
+        }
+        index
+    };
+}
+
+/// Swap adjacent groups of W elements, like tools::simd::swap for any amount of lanes
+#[inline(always)]
+fn swap<const W: usize, const N: usize>(v: Simd<f32, N>) -> Simd<f32, N> {
+    Xor::<W>::swizzle(v)
+}
+
+/// Fill ds[x + W] with ds[x] swapped in groups of W elements for all even x < W, if W is less than the lane count.
+/// ds[x] has been filled for all even x < W by the narrower swaps, so all of ds is filled after W = N / 2.
+#[inline(always)]
+fn swap_into<const W: usize, const N: usize>(ds: &mut [Simd<f32, N>; N]) {
+    if W < N {
+        for x in (0..W).step_by(2) {
+            ds[x + W] = swap::<W, N>(ds[x]);
+        }
+    }
+}
+
+/// Same as tools::min, element-wise
+#[inline(always)]
+fn min<const N: usize>(x: Simd<f32, N>, y: Simd<f32, N>) -> Simd<f32, N> {
+    x.simd_lt(y).select(x, y)
+}
+
+#[inline]
+pub fn step(r: &mut [f32], d: &[f32], n: usize) {
+    step_lanes::<LANES>(r, d, n);
+}
+
+/// Like step, but with vectors of the given amount of lanes, which must be 4, 8 or 16
+pub fn step_with_lanes(r: &mut [f32], d: &[f32], n: usize, lanes: usize) {
+    match lanes {
+        4 => step_lanes::<4>(r, d, n),
+        8 => step_lanes::<8>(r, d, n),
+        16 => step_lanes::<16>(r, d, n),
+        _ => panic!("unsupported lane count {}, must be 4, 8 or 16", lanes),
+    }
+}
+
+/// Compute r with vectors of LANES f32 elements, LANES can be any power of two from 2 to 64
+#[inline]
+pub fn step_lanes<const LANES: usize>(r: &mut [f32], d: &[f32], n: usize) {
+    const { assert!(LANES.is_power_of_two() && LANES >= 2 && LANES <= 64, "LANES must be a power of two from 2 to 64") };
+    assert_eq!(r.len(), n * n, "r must have room for the n×n results");
+    assert_eq!(d.len(), n * n, "d must contain the n×n input");
+    if n == 0 {
+        return;
+    }
+    let infty = Simd::<f32, LANES>::splat(f32::INFINITY);
+    let vecs_per_col = n.div_ceil(LANES);
+    // Pack d into vd and vt exactly as in v5, but with LANES rows or columns in each vector
+    let mut vd = std::vec![infty; n * vecs_per_col];
+    let mut vt = std::vec![infty; n * vecs_per_col];
+    let pack_simd_row_block = |(i, (vd_row, vt_row)): (usize, (&mut [_], &mut [_]))| {
+        let row_begin = i * LANES;
+        let row_end = n.min(row_begin + LANES);
+        // Element b of column k in vd comes from row row_begin + b of d
+        for (k, vx) in vd_row.iter_mut().enumerate() {
+            *vx = Simd::from_array(std::array::from_fn(|b| {
+                if row_begin + b < n { d[n * (row_begin + b) + k] } else { f32::INFINITY }
+            }));
+        }
+        // Columns of d are contiguous chunks of each row
+        for (jv, vy) in vt_row.iter_mut().enumerate() {
+            *vy = Simd::load_or(&d[n * jv + row_begin..n * jv + row_end], infty);
+        }
+    };
+    vd.par_chunks_mut(n)
+        .zip(vt.par_chunks_mut(n))
+        .enumerate()
+        .for_each(pack_simd_row_block);
+
+    // Function: for LANES rows in d, compute all results for LANES rows into r
+    let step_row_block = |(r_row_block, vd_row): (&mut [f32], &[Simd<f32, LANES>])| {
+        for (j, vt_row) in vt.chunks_exact(n).enumerate() {
+            // Accumulator m combines element b of vd with element b ^ m of vt
+            let mut tmp = [infty; LANES];
+            for (&d0, &t0) in vd_row.iter().zip(vt_row) {
+                // ds[x] = d0 with elements b ^ x for all even x, built from earlier permutations
+                // by swapping ever wider groups, e.g. d6 = swap::<4>(d2) on 8 lanes
+                let mut ds = [d0; LANES];
+                swap_into::<2, LANES>(&mut ds);
+                swap_into::<4, LANES>(&mut ds);
+                swap_into::<8, LANES>(&mut ds);
+                swap_into::<16, LANES>(&mut ds);
+                swap_into::<32, LANES>(&mut ds);
+                let ts = [t0, swap::<1, LANES>(t0)];
+                // Compute LANES independent, intermediate results for LANES rows
+                for (m, acc) in tmp.iter_mut().enumerate() {
+                    *acc = min(*acc, ds[m & !1] + ts[m & 1]);
+                }
+            }
+            // Accumulator m has the result of row b ^ (m & !1) and column b ^ (m & 1) in element b.
+            // Unpacking with scalar stores costs LANES² operations per LANES²·n operations of the loop above.
+            let tmp = tmp.map(Simd::to_array);
+            let col_begin = j * LANES;
+            for (a, r_row) in r_row_block.chunks_exact_mut(n).enumerate() {
+                for (c, res) in r_row[col_begin..n.min(col_begin + LANES)].iter_mut().enumerate() {
+                    let m = a ^ c;
+                    *res = tmp[m][a ^ (m & !1)];
+                }
+            }
+        }
+    };
+    r.par_chunks_mut(LANES * n)
+        .zip(vd.par_chunks(n))
+        .for_each(step_row_block);
+}
+
+
+create_extern_c_wrapper!(step, step);
@@ -1,44 +1,129 @@
-use tools::{create_extern_c_wrapper, simd, simd::f32x8};
+use tools::{create_extern_c_in_place_wrapper, create_extern_c_strided_wrapper, create_extern_c_weighted_wrapper, create_extern_c_wrapper, kfilter::{self, KFilter}, layout::Layout, params::Params, simd, simd::f32x8};
+use tools::par::prelude::*;
 
-#[cfg(not(feature = "no-multi-thread"))]
-extern crate rayon;
-#[cfg(not(feature = "no-multi-thread"))]
-use rayon::prelude::*;
 
+#[inline]
+pub fn step(r: &mut [f32], d: &[f32], n: usize) {
+    step_with_params(r, d, n, tools::params::get());
+}
+
+/// Like step, but prefetching params.prefetch_length vectors ahead instead of the tuned length
+pub fn step_with_params(r: &mut [f32], d: &[f32], n: usize, params: &Params) {
+    step_strided_with_params(r, Layout::dense(n), d, Layout::dense(n), n, params);
+}
+
+/// Like step, but for r and d stored with the given layouts, see tools::layout
+#[inline]
+pub fn step_strided(r: &mut [f32], r_layout: Layout, d: &[f32], d_layout: Layout, n: usize) {
+    step_strided_with_params(r, r_layout, d, d_layout, n, tools::params::get());
+}
+
+/// Like step_with_params, but for layouts as in step_strided
+pub fn step_strided_with_params(r: &mut [f32], r_layout: Layout, d: &[f32], d_layout: Layout, n: usize, params: &Params) {
+    step_into(r, r_layout, d, d_layout, n, params, false);
+}
+
+/// Like step, but r becomes min(r, d ⊗ d), as in v5
+#[inline]
+pub fn step_accumulate(r: &mut [f32], d: &[f32], n: usize) {
+    step_accumulate_with_params(r, d, n, tools::params::get());
+}
+
+pub fn step_accumulate_with_params(r: &mut [f32], d: &[f32], n: usize, params: &Params) {
+    step_into(r, Layout::dense(n), d, Layout::dense(n), n, params, true);
+}
+
+fn step_into(r: &mut [f32], r_layout: Layout, d: &[f32], d_layout: Layout, n: usize, params: &Params, accumulate: bool) {
+    r_layout.check(n, r.len(), "r");
+    d_layout.check(n, d.len(), "d");
+    if n == 0 {
+        return;
+    }
+    // A column-major r is computed as the row-major result of d transposed
+    let d_layout = if r_layout.col_major { d_layout.transposed() } else { d_layout };
+    let (vd, vt) = pack_simd(d, d_layout, n, &KFilter::ALL);
+    step_row_blocks(&mut r[..r_layout.len(n)], r_layout.ld, &vd, &vt, n, n, params, accumulate);
+}
 
+/// Like step, but d is read from rd, which is then overwritten with the results
 #[inline]
-fn _step(r: &mut [f32], d: &[f32], n: usize) {
-    let vecs_per_col = (n + simd::f32x8_LENGTH - 1) / simd::f32x8_LENGTH;
-    let mut vd = std::vec![simd::f32x8_infty(); n * vecs_per_col];
-    let mut vt = std::vec![simd::f32x8_infty(); n * vecs_per_col];
+pub fn step_in_place(rd: &mut [f32], n: usize) {
+    step_in_place_with_params(rd, n, tools::params::get());
+}
+
+pub fn step_in_place_with_params(rd: &mut [f32], n: usize, params: &Params) {
+    assert_eq!(rd.len(), n * n, "rd must contain the n×n matrix d");
+    if n == 0 {
+        return;
+    }
+    let (vd, vt) = pack_simd(rd, Layout::dense(n), n, &KFilter::ALL);
+    step_row_blocks(rd, n, &vd, &vt, n, n, params, false);
+}
+
+/// Like step, but minimize only over the intermediate nodes k allowed by filter, see tools::kfilter
+#[inline]
+pub fn step_filtered(r: &mut [f32], d: &[f32], n: usize, filter: &KFilter) {
+    step_filtered_with_params(r, d, n, filter, tools::params::get());
+}
+
+/// Like step_with_params, but with a filter as in step_filtered
+pub fn step_filtered_with_params(r: &mut [f32], d: &[f32], n: usize, filter: &KFilter, params: &Params) {
+    assert_eq!(r.len(), n * n, "r must have room for the n×n results");
+    assert_eq!(d.len(), n * n, "d must contain the n×n input");
+    filter.check(n);
+    if filter.len(n) == 0 {
+        // No intermediate nodes, so there are no paths
+        return r.fill(f32::INFINITY);
+    }
+    let (vd, vt) = pack_simd(d, Layout::dense(n), n, filter);
+    step_row_blocks(r, n, &vd, &vt, n, filter.len(n), params, false);
+}
+
+/// Like step, but with a weight w[k] for each vertex k added to the paths through it, see tools::weights
+#[inline]
+pub fn step_weighted(r: &mut [f32], d: &[f32], w: &[f32], n: usize) {
+    step_weighted_with_params(r, d, w, n, tools::params::get());
+}
+
+/// Like step_with_params, but with weights as in step_weighted
+pub fn step_weighted_with_params(r: &mut [f32], d: &[f32], w: &[f32], n: usize, params: &Params) {
+    assert_eq!(r.len(), n * n, "r must have room for the n×n results");
+    assert_eq!(d.len(), n * n, "d must contain the n×n input");
+    tools::weights::check(w, n);
+    if n == 0 {
+        return;
+    }
+    let (mut vd, vt) = pack_simd(d, Layout::dense(n), n, &KFilter::ALL);
+    vd.par_chunks_mut(n)
+        .for_each(|vd_row| tools::weights::add_to_columns(vd_row, w));
+    step_row_blocks(r, n, &vd, &vt, n, n, params, false);
+}
+
+/// Pack 8 rows of d into each row of vd and 8 columns of d into each row of vt, as in v5,
+/// with a vector for each intermediate node of filter
+fn pack_simd(d: &[f32], d_layout: Layout, n: usize, filter: &KFilter) -> (Vec<f32x8>, Vec<f32x8>) {
+    let row_len = filter.len(n);
+    let vecs_per_col = n.div_ceil(simd::f32x8_LENGTH);
+    let mut vd = std::vec![simd::f32x8_infty(); row_len * vecs_per_col];
+    let mut vt = std::vec![simd::f32x8_infty(); row_len * vecs_per_col];
     debug_assert!(vd.iter().all(simd::is_aligned));
     debug_assert!(vt.iter().all(simd::is_aligned));
     let pack_simd_row = |(i, (vd_row, vt_row)): (usize, (&mut [f32x8], &mut [f32x8]))| {
-        for (jv, (vx, vy)) in vd_row.iter_mut().zip(vt_row.iter_mut()).enumerate() {
-            let mut vx_tmp = [std::f32::INFINITY; simd::f32x8_LENGTH];
-            let mut vy_tmp = [std::f32::INFINITY; simd::f32x8_LENGTH];
-            for (b, (x, y)) in vx_tmp.iter_mut().zip(vy_tmp.iter_mut()).enumerate() {
-                let j = i * simd::f32x8_LENGTH + b;
-                if i < n && j < n {
-                    *x = d[n * j + jv];
-                    *y = d[n * jv + j];
-                }
-            }
-            *vx = simd::from_slice(&vx_tmp);
-            *vy = simd::from_slice(&vy_tmp);
-        }
+        let row_begin = i * simd::f32x8_LENGTH;
+        // As in v5, rows of d are packed into vd and columns into vt
+        kfilter::pack_columns(d, d_layout, n, filter, row_begin, 0, row_len, vd_row);
+        kfilter::pack_columns(d, d_layout.transposed(), n, filter, row_begin, 0, row_len, vt_row);
     };
-    #[cfg(not(feature = "no-multi-thread"))]
-    vd.par_chunks_mut(n)
-        .zip(vt.par_chunks_mut(n))
-        .enumerate()
-        .for_each(pack_simd_row);
-    #[cfg(feature = "no-multi-thread")]
-    vd.chunks_mut(n)
-        .zip(vt.chunks_mut(n))
+    vd.par_chunks_mut(row_len)
+        .zip(vt.par_chunks_mut(row_len))
         .enumerate()
         .for_each(pack_simd_row);
+    (vd, vt)
+}
 
+/// Compute all results from vd and vt, which have row_len vectors per row, into the rows of r, which are ldr elements apart
+#[allow(clippy::too_many_arguments)]
+fn step_row_blocks(r: &mut [f32], ldr: usize, vd: &[f32x8], vt: &[f32x8], n: usize, row_len: usize, params: &Params, accumulate: bool) {
     // ANCHOR: step_row_block
     // Everything is mostly as in v5,
     // but we add some prefetch instructions in the innermost loop,
@@ -46,8 +131,8 @@ fn _step(r: &mut [f32], d: &[f32], n: usize) {
     let step_row_block = |(r_row_block, vd_row): (&mut [f32], &[f32x8])| {
         // Create const raw pointers for specifying addresses to prefetch
         let vd_row_ptr = vd_row.as_ptr();
-        const PREFETCH_LENGTH: usize = 20;
-        for (j, vt_row) in vt.chunks_exact(n).enumerate() {
+        let prefetch_length = params.prefetch_length;
+        for (j, vt_row) in vt.chunks_exact(row_len).enumerate() {
             let vt_row_ptr = vt_row.as_ptr();
             let mut tmp0 = simd::f32x8_infty();
             let mut tmp1 = simd::f32x8_infty();
@@ -59,13 +144,13 @@ fn _step(r: &mut [f32], d: &[f32], n: usize) {
             let mut tmp7 = simd::f32x8_infty();
             for (col, (&d0, &t0)) in vd_row.iter().zip(vt_row).enumerate() {
                 // Insert prefetch hints for fetching the cache line containing
-                // the memory address 20 addresses ahead of the current column
-                simd::prefetch(vd_row_ptr, (col + PREFETCH_LENGTH) as isize);
-                simd::prefetch(vt_row_ptr, (col + PREFETCH_LENGTH) as isize);
-                let d2 = simd::swap(d0, 2);
-                let d4 = simd::swap(d0, 4);
-                let d6 = simd::swap(d4, 2);
-                let t1 = simd::swap(t0, 1);
+                // the memory address prefetch_length (e.g. 20) addresses ahead of the current column
+                simd::prefetch(vd_row_ptr, (col + prefetch_length) as isize);
+                simd::prefetch(vt_row_ptr, (col + prefetch_length) as isize);
+                let d2 = simd::swap::<2>(d0);
+                let d4 = simd::swap::<4>(d0);
+                let d6 = simd::swap::<2>(d4);
+                let t1 = simd::swap::<1>(t0);
                 tmp0 = simd::min(tmp0, simd::add(d0, t0));
                 tmp1 = simd::min(tmp1, simd::add(d0, t1));
                 tmp2 = simd::min(tmp2, simd::add(d2, t0));
@@ -75,36 +160,29 @@ fn _step(r: &mut [f32], d: &[f32], n: usize) {
                 tmp6 = simd::min(tmp6, simd::add(d6, t0));
                 tmp7 = simd::min(tmp7, simd::add(d6, t1));
             }
-            let tmp = [
-                tmp0, simd::swap(tmp1, 1),
-                tmp2, simd::swap(tmp3, 1),
-                tmp4, simd::swap(tmp5, 1),
-                tmp6, simd::swap(tmp7, 1),
-            ];
-            for (tmp_i, r_row) in r_row_block.chunks_exact_mut(n).enumerate() {
-                for tmp_j in 0..simd::f32x8_LENGTH {
-                    let res_j = j * simd::f32x8_LENGTH + tmp_j;
-                    if res_j < n {
-                        let v = tmp[tmp_i ^ tmp_j];
-                        let vi = tmp_j as u8;
-                        r_row[res_j] = simd::extract(v, vi);
-                    }
+            let rows = simd::unpack_results([tmp0, tmp1, tmp2, tmp3, tmp4, tmp5, tmp6, tmp7]);
+            let res_j = j * simd::f32x8_LENGTH;
+            for (r_row, &row) in r_row_block.chunks_mut(ldr).zip(rows.iter()) {
+                let dst = &mut r_row[res_j..n.min(res_j + simd::f32x8_LENGTH)];
+                if accumulate {
+                    simd::min_store_padded(dst, row);
+                } else {
+                    simd::store_padded(dst, row);
                 }
             }
         }
     };
     // ANCHOR_END: step_row_block
-    #[cfg(not(feature = "no-multi-thread"))]
     // ANCHOR: step_row_block_apply
-    r.par_chunks_mut(simd::f32x8_LENGTH * n)
-        .zip(vd.par_chunks(n))
+    r.par_chunks_mut(simd::f32x8_LENGTH * ldr)
+        .zip(vd.par_chunks(row_len))
         .for_each(step_row_block);
     // ANCHOR_END: step_row_block_apply
-    #[cfg(feature = "no-multi-thread")]
-    r.chunks_mut(simd::f32x8_LENGTH * n)
-        .zip(vd.chunks(n))
-        .for_each(step_row_block);
 }
 
 
-create_extern_c_wrapper!(step, _step);
+create_extern_c_wrapper!(step, step);
+create_extern_c_wrapper!(step_accumulate, step_accumulate);
+create_extern_c_in_place_wrapper!(step_in_place, step_in_place);
+create_extern_c_weighted_wrapper!(step_weighted, step_weighted);
+create_extern_c_strided_wrapper!(step_strided, step_strided);
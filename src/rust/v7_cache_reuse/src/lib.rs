@@ -1,25 +1,196 @@
-use tools::{create_extern_c_wrapper, simd, simd::f32x8, z_encode};
+use tools::{create_extern_c_in_place_wrapper, create_extern_c_strided_wrapper, create_extern_c_weighted_wrapper, create_extern_c_wrapper, kfilter::{self, KFilter}, layout::Layout, params::Params, simd, simd::f32x8, z_encode};
+use tools::par::prelude::*;
 
-#[cfg(not(feature = "no-multi-thread"))]
-extern crate rayon;
-#[cfg(not(feature = "no-multi-thread"))]
-use rayon::prelude::*;
 
+#[inline]
+pub fn step(r: &mut [f32], d: &[f32], n: usize) {
+    step_with_params(r, d, n, tools::params::get());
+}
+
+/// Like step, but with vertical stripes of params.cols_per_stripe columns
+/// and params.z_block_size row pairs per task instead of the tuned values
+pub fn step_with_params(r: &mut [f32], d: &[f32], n: usize, params: &Params) {
+    if n == 0 {
+        return;
+    }
+    step_stripes(r, n, d, Layout::dense(n), n, params, 0, false, false);
+}
+
+/// Like step, but for r and d stored with the given layouts, see tools::layout
+#[inline]
+pub fn step_strided(r: &mut [f32], r_layout: Layout, d: &[f32], d_layout: Layout, n: usize) {
+    step_strided_with_params(r, r_layout, d, d_layout, n, tools::params::get());
+}
+
+/// Like step_with_params, but for layouts as in step_strided
+pub fn step_strided_with_params(r: &mut [f32], r_layout: Layout, d: &[f32], d_layout: Layout, n: usize, params: &Params) {
+    r_layout.check(n, r.len(), "r");
+    d_layout.check(n, d.len(), "d");
+    if n == 0 {
+        return;
+    }
+    // A column-major r is computed as the row-major result of d transposed
+    let d_layout = if r_layout.col_major { d_layout.transposed() } else { d_layout };
+    step_stripes(&mut r[..r_layout.len(n)], r_layout.ld, d, d_layout, n, params, 0, false, false);
+}
+
+/// Like step, but r becomes min(r, d ⊗ d), merging the final results of each row pair into r
+#[inline]
+pub fn step_accumulate(r: &mut [f32], d: &[f32], n: usize) {
+    step_accumulate_with_params(r, d, n, tools::params::get());
+}
+
+pub fn step_accumulate_with_params(r: &mut [f32], d: &[f32], n: usize, params: &Params) {
+    if n == 0 {
+        return;
+    }
+    step_stripes(r, n, d, Layout::dense(n), n, params, 0, false, true);
+}
+
+/// Like step_with_params, but compute only rows first_row.. of r into r_rows, which contains whole rows.
+/// first_row must be a multiple of 8. The partial results take as much memory as r_rows,
+/// so that r can be computed one panel of rows at a time, if the partial results of all rows do not fit into memory.
+pub fn step_rows_with_params(r_rows: &mut [f32], d: &[f32], n: usize, first_row: usize, params: &Params) {
+    assert_eq!(first_row % simd::f32x8_LENGTH, 0, "first row must be a multiple of {}", simd::f32x8_LENGTH);
+    assert!(r_rows.len().is_multiple_of(n.max(1)) && first_row + r_rows.len() / n.max(1) <= n, "r_rows must contain whole rows of r");
+    if n == 0 {
+        return;
+    }
+    step_stripes(r_rows, n, d, Layout::dense(n), n, params, first_row / simd::f32x8_LENGTH, false, false);
+}
+
+/// Like step, but d is read from rd, which is then overwritten with the results.
+/// The partial results of all stripes are complete before the first result is written.
+#[inline]
+pub fn step_in_place(rd: &mut [f32], n: usize) {
+    step_in_place_with_params(rd, n, tools::params::get());
+}
+
+pub fn step_in_place_with_params(rd: &mut [f32], n: usize, params: &Params) {
+    assert_eq!(rd.len(), n * n, "rd must contain the n×n matrix d");
+    if n == 0 {
+        return;
+    }
+    let (row_pairs, partial_results) = step_partial_results(rd, Layout::dense(n), n, params, &KFilter::ALL, None, 0, n, false);
+    store_results(rd, n, n, &row_pairs, &partial_results, false, false);
+}
+
+/// Like step, but minimize only over the intermediate nodes k allowed by filter, see tools::kfilter
+#[inline]
+pub fn step_filtered(r: &mut [f32], d: &[f32], n: usize, filter: &KFilter) {
+    step_filtered_with_params(r, d, n, filter, tools::params::get());
+}
+
+/// Like step_with_params, but the stripes are taken from the packed rows containing only the hubs
+pub fn step_filtered_with_params(r: &mut [f32], d: &[f32], n: usize, filter: &KFilter, params: &Params) {
+    assert_eq!(r.len(), n * n, "r must have room for the n×n results");
+    assert_eq!(d.len(), n * n, "d must contain the n×n input");
+    filter.check(n);
+    if n == 0 {
+        return;
+    }
+    let (row_pairs, partial_results) = step_partial_results(d, Layout::dense(n), n, params, filter, None, 0, n, false);
+    store_results(r, n, n, &row_pairs, &partial_results, false, false);
+}
+
+/// Like step, but with a weight w[k] for each vertex k added to the paths through it, see tools::weights
+#[inline]
+pub fn step_weighted(r: &mut [f32], d: &[f32], w: &[f32], n: usize) {
+    step_weighted_with_params(r, d, w, n, tools::params::get());
+}
 
+/// Like step_with_params, but with the weights added to each stripe of vd when it is packed
+pub fn step_weighted_with_params(r: &mut [f32], d: &[f32], w: &[f32], n: usize, params: &Params) {
+    assert_eq!(r.len(), n * n, "r must have room for the n×n results");
+    assert_eq!(d.len(), n * n, "d must contain the n×n input");
+    tools::weights::check(w, n);
+    if n == 0 {
+        return;
+    }
+    let (row_pairs, partial_results) = step_partial_results(d, Layout::dense(n), n, params, &KFilter::ALL, Some(w), 0, n, false);
+    store_results(r, n, n, &row_pairs, &partial_results, false, false);
+}
+
+/// Like step, but for a symmetric d, i.e. d[i][j] equals d[j][i] for all i and j, which is checked only
+/// with debug assertions, see step_symmetric_checked. Then vt would be equal to vd and r is symmetric,
+/// so only vd is packed and only the row pairs (i, j) with i <= j are computed,
+/// which are then mirrored to the pairs with i > j.
 #[inline]
-fn _step(r: &mut [f32], d: &[f32], n: usize) {
+pub fn step_symmetric(r: &mut [f32], d: &[f32], n: usize) {
+    step_symmetric_with_params(r, d, n, tools::params::get());
+}
+
+pub fn step_symmetric_with_params(r: &mut [f32], d: &[f32], n: usize, params: &Params) {
+    assert_eq!(r.len(), n * n, "r must have room for the n×n results");
+    assert_eq!(d.len(), n * n, "d must contain the n×n input");
+    debug_assert!(tools::is_symmetric(d, n), "d is not symmetric");
+    if n == 0 {
+        return;
+    }
+    step_stripes(r, n, d, Layout::dense(n), n, params, 0, true, false);
+    tools::mirror_upper_triangle(r, n);
+}
+
+/// Like step_symmetric, but check that d is symmetric, also without debug assertions.
+/// If it is not, all results are computed as in step and false is returned.
+#[inline]
+pub fn step_symmetric_checked(r: &mut [f32], d: &[f32], n: usize) -> bool {
+    step_symmetric_checked_with_params(r, d, n, tools::params::get())
+}
+
+pub fn step_symmetric_checked_with_params(r: &mut [f32], d: &[f32], n: usize, params: &Params) -> bool {
+    assert_eq!(d.len(), n * n, "d must contain the n×n input");
+    if !tools::is_symmetric(d, n) {
+        step_with_params(r, d, n, params);
+        return false;
+    }
+    step_symmetric_with_params(r, d, n, params);
+    true
+}
+
+/// Compute r one vertical stripe at a time, only the row pairs (i, j) with i <= j if symmetric is true.
+/// r contains the rows from 8 * first_row_block on, ldr elements apart, the last one possibly ending after n elements.
+/// Row pair indexes i are relative to the first row block.
+/// If accumulate is true, the final results are merged into the existing contents of r.
+#[allow(clippy::too_many_arguments)]
+fn step_stripes(r: &mut [f32], ldr: usize, d: &[f32], d_layout: Layout, n: usize, params: &Params,
+                first_row_block: usize, symmetric: bool, accumulate: bool) {
+    // Amount of rows of r to compute, all of them unless r contains only some rows
+    let num_rows = if r.is_empty() { 0 } else { (r.len() - n) / ldr + 1 };
+    let (row_pairs, partial_results) = step_partial_results(d, d_layout, n, params, &KFilter::ALL, None, first_row_block, num_rows, symmetric);
+    store_results(r, ldr, n, &row_pairs, &partial_results, symmetric, accumulate);
+}
+
+/// Compute the results of num_rows rows from 8 * first_row_block on, one vertical stripe at a time,
+/// over the intermediate nodes of filter, whose packed rows are split into the stripes,
+/// with the vertex weights w added to the stripes of vd if given.
+/// Returns the row pairs (index of the pair in partial results, i, j), sorted by (i, j),
+/// and the partial results, 8 vectors for each row pair.
+#[allow(clippy::too_many_arguments)]
+fn step_partial_results(d: &[f32], d_layout: Layout, n: usize, params: &Params, filter: &KFilter, w: Option<&[f32]>, first_row_block: usize,
+                        num_rows: usize, symmetric: bool) -> (Vec<(usize, usize, usize)>, Vec<f32x8>) {
     // ANCHOR: init
     // How many adjacent columns to process during one pass
     // Smaller numbers improve cache locality but add overhead
     // from having to merge partial results
-    const COLS_PER_STRIPE: usize = 500;
-    let vecs_per_col = (n + simd::f32x8_LENGTH - 1) / simd::f32x8_LENGTH;
+    // Unless tuned, the width is chosen such that one row of vd and vt fit into L1
+    let cols_per_stripe = params.cols_per_stripe;
+    let vecs_per_col = n.div_ceil(simd::f32x8_LENGTH);
+    let row_vecs = num_rows.div_ceil(simd::f32x8_LENGTH);
+    // vt is shared with vd when symmetric, so all rows are needed
+    debug_assert!(!symmetric || row_vecs == vecs_per_col);
+    // How many row pairs, adjacent on the Z-order curve, one task processes per stripe
+    // Unless tuned, the rows of vd and vt read by one task fit into L2 or its share of L3,
+    // but leave at least a few tasks for each thread
+    let pairs_per_task = params.z_block_size
+        .min(row_vecs * vecs_per_col / (4 * tools::par::num_threads()))
+        .max(1);
     // ANCHOR_END: init
 
     // ANCHOR: interleave
     // Build a Z-order curve iteration pattern of pairs (i, j)
     // by using interleaved bits of i and j as a sort key
-    let mut row_pairs = std::vec![(0, 0, 0); vecs_per_col * vecs_per_col];
+    let mut row_pairs = std::vec![(0, 0, 0); row_vecs * vecs_per_col];
     // Define a function that interleaves one row of indexes
     let interleave_row = |(i, row): (usize, &mut [(usize, usize, usize)])| {
         for (j, x) in row.iter_mut().enumerate() {
@@ -28,70 +199,55 @@ fn _step(r: &mut [f32], d: &[f32], n: usize) {
         }
     };
     // ANCHOR_END: interleave
-    #[cfg(not(feature = "no-multi-thread"))]
-    {
     // ANCHOR: interleave_apply
     // Apply the function independently on all rows and sort by ija
     row_pairs
         .par_chunks_mut(vecs_per_col)
         .enumerate()
         .for_each(interleave_row);
+    if symmetric {
+        row_pairs.retain(|&(_, i, j)| i <= j);
+    }
     // We don't need stable sort since there are no duplicate keys
     row_pairs.par_sort_unstable();
     // ANCHOR_END: interleave_apply
-    }
-    #[cfg(feature = "no-multi-thread")]
-    {
-        row_pairs
-            .chunks_mut(vecs_per_col)
-            .enumerate()
-            .for_each(interleave_row);
-        row_pairs.sort_unstable();
-    }
 
     // ANCHOR: init_stripe_data
     // We'll be processing the input one stripe at a time
-    let mut vd = std::vec![simd::f32x8_infty(); COLS_PER_STRIPE * vecs_per_col];
-    let mut vt = std::vec![simd::f32x8_infty(); COLS_PER_STRIPE * vecs_per_col];
+    // If d is symmetric, vt would be equal to vd and is not needed
+    let mut vd = std::vec![simd::f32x8_infty(); cols_per_stripe * row_vecs];
+    let mut vt = std::vec![simd::f32x8_infty(); if symmetric { 0 } else { cols_per_stripe * vecs_per_col }];
     // Non-overlapping working memory for threads to update their results
     // When enumerated in 8 element chunks, indexes the Z-order curve keys
-    let mut partial_results = std::vec![simd::f32x8_infty(); vecs_per_col * vecs_per_col * simd::f32x8_LENGTH];
+    let mut partial_results = std::vec![simd::f32x8_infty(); row_pairs.len() * simd::f32x8_LENGTH];
     // ANCHOR_END: init_stripe_data
 
     // ANCHOR: stripe_loop_head
     // Process vd and vt in Z-order one vertical stripe at a time, writing partial results in parallel
-    let num_vertical_stripes = (n + COLS_PER_STRIPE - 1) / COLS_PER_STRIPE;
+    let row_len = filter.len(n);
+    let num_vertical_stripes = row_len.div_ceil(cols_per_stripe);
     for stripe in 0..num_vertical_stripes {
-        let col_begin = stripe * COLS_PER_STRIPE;
-        let col_end = n.min((stripe + 1) * COLS_PER_STRIPE);
+        let col_begin = stripe * cols_per_stripe;
+        let col_end = row_len.min((stripe + 1) * cols_per_stripe);
         // ANCHOR_END: stripe_loop_head
         // Preprocessing as in v5, but one vertical stripe at a time
-        let pack_simd_row = |(i, (vd_stripe, vt_stripe)): (usize, (&mut [f32x8], &mut [f32x8]))| {
-            for (jv, (vx, vy)) in vd_stripe.iter_mut().zip(vt_stripe.iter_mut()).enumerate() {
-                let mut vx_tmp = [std::f32::INFINITY; simd::f32x8_LENGTH];
-                let mut vy_tmp = [std::f32::INFINITY; simd::f32x8_LENGTH];
-                for (b, (x, y)) in vx_tmp.iter_mut().zip(vy_tmp.iter_mut()).enumerate() {
-                    let d_row = i * simd::f32x8_LENGTH + b;
-                    let d_col = col_begin + jv;
-                    if d_row < n && d_col < col_end {
-                        *x = d[n * d_row + d_col];
-                        *y = d[n * d_col + d_row];
-                    }
-                }
-                *vx = simd::from_slice(&vx_tmp);
-                *vy = simd::from_slice(&vy_tmp);
+        let pack_simd_row = |(i, vd_stripe): (usize, &mut [f32x8])| {
+            kfilter::pack_columns(d, d_layout, n, filter, (first_row_block + i) * simd::f32x8_LENGTH, col_begin, col_end, vd_stripe);
+            if let Some(w) = w {
+                tools::weights::add_to_columns(vd_stripe, &w[col_begin..col_end]);
             }
         };
-        #[cfg(not(feature = "no-multi-thread"))]
-        vd.par_chunks_mut(COLS_PER_STRIPE)
-            .zip(vt.par_chunks_mut(COLS_PER_STRIPE))
+        // vt is reused for all stripes, so columns past the last stripe are reset to f32::INFINITY
+        let pack_simd_col = |(i, vt_stripe): (usize, &mut [f32x8])| {
+            kfilter::pack_columns(d, d_layout.transposed(), n, filter, i * simd::f32x8_LENGTH, col_begin, col_end, vt_stripe);
+        };
+        vd.par_chunks_mut(cols_per_stripe)
             .enumerate()
             .for_each(pack_simd_row);
-        #[cfg(feature = "no-multi-thread")]
-        vd.chunks_mut(COLS_PER_STRIPE)
-            .zip(vt.chunks_mut(COLS_PER_STRIPE))
+        vt.par_chunks_mut(cols_per_stripe)
             .enumerate()
-            .for_each(pack_simd_row);
+            .for_each(pack_simd_col);
+        let vt = if symmetric { &vd } else { &vt };
         // ANCHOR_END: stripe_loop_head
         // ANCHOR: stripe_loop_step_partial_block
         // Function: for a f32x8 block of partial results and indexes row i col j,
@@ -101,15 +257,15 @@ fn _step(r: &mut [f32], d: &[f32], n: usize) {
         let step_partial_block = |(prev_tmp, &(_, i, j)): (&mut [f32x8], &(usize, usize, usize))| {
             // Copy results from previous pass over previous stripe
             let mut tmp = [simd::f32x8_infty(); simd::f32x8_LENGTH];
-            tmp.copy_from_slice(&prev_tmp);
+            tmp.copy_from_slice(prev_tmp);
             // Get slices over current stripes of row i and column j
-            let vd_row = &vd[(COLS_PER_STRIPE * i)..(COLS_PER_STRIPE * (i + 1))];
-            let vt_row = &vt[(COLS_PER_STRIPE * j)..(COLS_PER_STRIPE * (j + 1))];
+            let vd_row = &vd[(cols_per_stripe * i)..(cols_per_stripe * (i + 1))];
+            let vt_row = &vt[(cols_per_stripe * j)..(cols_per_stripe * (j + 1))];
             for (&d0, &t0) in vd_row.iter().zip(vt_row) {
-                let d2 = simd::swap(d0, 2);
-                let d4 = simd::swap(d0, 4);
-                let d6 = simd::swap(d4, 2);
-                let t1 = simd::swap(t0, 1);
+                let d2 = simd::swap::<2>(d0);
+                let d4 = simd::swap::<4>(d0);
+                let d6 = simd::swap::<2>(d4);
+                let t1 = simd::swap::<1>(t0);
                 tmp[0] = simd::min(tmp[0], simd::add(d0, t0));
                 tmp[1] = simd::min(tmp[1], simd::add(d0, t1));
                 tmp[2] = simd::min(tmp[2], simd::add(d2, t0));
@@ -124,19 +280,18 @@ fn _step(r: &mut [f32], d: &[f32], n: usize) {
             prev_tmp.copy_from_slice(&tmp);
         };
         // ANCHOR_END: stripe_loop_step_partial_block
-        #[cfg(not(feature = "no-multi-thread"))]
         // ANCHOR: stripe_loop_step_partial_block_apply
-        // Process current stripe in parallel, each thread filling one `tmp` block
+        // Process current stripe in parallel, each task filling the `tmp` blocks of one Z-order block
         partial_results
-            .par_chunks_mut(simd::f32x8_LENGTH)
-            .zip(row_pairs.par_iter())
-            .for_each(step_partial_block);
+            .par_chunks_mut(simd::f32x8_LENGTH * pairs_per_task)
+            .zip(row_pairs.par_chunks(pairs_per_task))
+            .for_each(|(prev_tmps, pairs)| {
+                prev_tmps
+                    .chunks_mut(simd::f32x8_LENGTH)
+                    .zip(pairs)
+                    .for_each(step_partial_block)
+            });
         // ANCHOR_END: stripe_loop_step_partial_block_apply
-        #[cfg(feature = "no-multi-thread")]
-        partial_results
-            .chunks_mut(simd::f32x8_LENGTH)
-            .zip(row_pairs.iter())
-            .for_each(step_partial_block);
     }
 
     // ANCHOR: replace_sort_key
@@ -150,8 +305,6 @@ fn _step(r: &mut [f32], d: &[f32], n: usize) {
     };
     let key_ij = |&idx: &(usize, usize, usize)| { (idx.1, idx.2) };
     // ANCHOR_END: replace_sort_key
-    #[cfg(not(feature = "no-multi-thread"))]
-    {
     // ANCHOR: replace_sort_key_apply
     row_pairs
         .par_chunks_mut(vecs_per_col)
@@ -159,55 +312,58 @@ fn _step(r: &mut [f32], d: &[f32], n: usize) {
         .for_each(replace_z_index_row);
     row_pairs.par_sort_unstable_by_key(key_ij);
     // ANCHOR_END: replace_sort_key_apply
-    }
-    #[cfg(feature = "no-multi-thread")]
-    {
-        row_pairs
-            .chunks_mut(vecs_per_col)
-            .enumerate()
-            .for_each(replace_z_index_row);
-        row_pairs.sort_unstable_by_key(key_ij);
-    }
+    (row_pairs, partial_results)
+}
+
+/// Write the final partial results of each row pair into r, whose rows are ldr elements apart,
+/// or merge them into r if accumulate is true
+fn store_results(r: &mut [f32], ldr: usize, n: usize, row_pairs: &[(usize, usize, usize)], partial_results: &[f32x8],
+                 symmetric: bool, accumulate: bool) {
+    let vecs_per_col = n.div_ceil(simd::f32x8_LENGTH);
 
     // ANCHOR: set_z_order_result_block
     // Function: for 8 rows in r starting at row i*8,
     // read partial results at z-index corresponding to each row i and column j
     // and write them to r
     let set_z_order_result_block = |(i, r_row_block): (usize, &mut [f32])| {
-        for j in 0..vecs_per_col {
+        // If symmetric, each row i has only the pairs with columns j >= i
+        let (first_j, pairs_before_row) = if symmetric {
+            (i, i * vecs_per_col - (i * i - i) / 2)
+        } else {
+            (0, i * vecs_per_col)
+        };
+        for j in first_j..vecs_per_col {
             // Get z-order index for row i and column j
-            let z = row_pairs[i * vecs_per_col + j].0 * simd::f32x8_LENGTH;
+            let (z, pair_i, pair_j) = row_pairs[pairs_before_row + j - first_j];
+            debug_assert_eq!((pair_i, pair_j), (i, j));
+            let z = z * simd::f32x8_LENGTH;
             // Load tmp from z-order partial results for this i, j pair
             let mut tmp = [simd::f32x8_infty(); simd::f32x8_LENGTH];
             tmp.copy_from_slice(&partial_results[z..z + simd::f32x8_LENGTH]);
             // Continue exactly as in v5
-            for k in (1..simd::f32x8_LENGTH).step_by(2) {
-                tmp[k] = simd::swap(tmp[k], 1);
-            }
-            for (tmp_i, r_row) in r_row_block.chunks_exact_mut(n).enumerate() {
-                for tmp_j in 0..simd::f32x8_LENGTH {
-                    let res_j = j * simd::f32x8_LENGTH + tmp_j;
-                    if res_j < n {
-                        let v = tmp[tmp_i ^ tmp_j];
-                        let vi = tmp_j as u8;
-                        r_row[res_j] = simd::extract(v, vi);
-                    }
+            let rows = simd::unpack_results(tmp);
+            let res_j = j * simd::f32x8_LENGTH;
+            for (r_row, &row) in r_row_block.chunks_mut(ldr).zip(rows.iter()) {
+                let dst = &mut r_row[res_j..n.min(res_j + simd::f32x8_LENGTH)];
+                if accumulate {
+                    simd::min_store_padded(dst, row);
+                } else {
+                    simd::store_padded(dst, row);
                 }
             }
         }
     };
     // ANCHOR_END: set_z_order_result_block
-    #[cfg(not(feature = "no-multi-thread"))]
     // ANCHOR: set_z_order_result_block_apply
-    r.par_chunks_mut(simd::f32x8_LENGTH * n)
+    r.par_chunks_mut(simd::f32x8_LENGTH * ldr)
         .enumerate()
         .for_each(set_z_order_result_block);
     // ANCHOR_END: set_z_order_result_block_apply
-    #[cfg(feature = "no-multi-thread")]
-    r.chunks_mut(simd::f32x8_LENGTH * n)
-        .enumerate()
-        .for_each(set_z_order_result_block);
 }
 
 
-create_extern_c_wrapper!(step, _step);
+create_extern_c_wrapper!(step, step);
+create_extern_c_wrapper!(step_accumulate, step_accumulate);
+create_extern_c_in_place_wrapper!(step_in_place, step_in_place);
+create_extern_c_weighted_wrapper!(step_weighted, step_weighted);
+create_extern_c_strided_wrapper!(step_strided, step_strided);
@@ -4,7 +4,7 @@ import os
 import subprocess
 import sys
 
-from build import print_header, STEP_IMPLEMENTATIONS
+from build import print_header, STEP_IMPLEMENTATIONS, RUST_ONLY_STEP_IMPLEMENTATIONS
 from bench import CPU_BIND_CMD
 
 
@@ -73,7 +73,8 @@ if __name__ == "__main__":
         langs.append("rust")
 
     for lang in langs:
-        for step_impl in STEP_IMPLEMENTATIONS:
+        step_impls = STEP_IMPLEMENTATIONS + (RUST_ONLY_STEP_IMPLEMENTATIONS if lang == "rust" else [])
+        for step_impl in step_impls:
             if impl_filter and not step_impl.startswith(impl_filter):
                 continue
             print_header(lang + ' ' + step_impl + ' ...', end=' ')